@@ -0,0 +1,70 @@
+use crate::cid::Cid;
+use crate::quota::Quota;
+use serde::{Deserialize, Serialize};
+
+// Types for the JSON API of `mag serve`, shared by the server and clients.
+
+/// Path of the listing endpoint, relative to the server's base URL
+pub const BLOBS_PATH: &str = "api/v1/blobs";
+
+/// Default number of blobs in a page of the listing
+pub const DEFAULT_PAGE_LIMIT: usize = 1000;
+
+/// Largest number of blobs a client may ask for in one page
+pub const MAX_PAGE_LIMIT: usize = 10_000;
+
+/// Path of the bulk existence check endpoint
+pub const HAS_PATH: &str = "api/v1/has";
+
+/// Largest number of CIDs a client may check in one request
+pub const MAX_HAS_CIDS: usize = 10_000;
+
+/// Path of the usage endpoint
+pub const USAGE_PATH: &str = "api/v1/usage";
+
+/// A blob held by a server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobInfo {
+    pub cid: Cid,
+    /// Size in bytes
+    pub size: u64,
+    /// Unix timestamp (seconds) of when the server stored the blob
+    pub added: u64,
+}
+
+/// A page of the listing returned by `GET /api/v1/blobs`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobList {
+    pub blobs: Vec<BlobInfo>,
+    /// Pass this as `cursor` to get the next page.
+    /// None if this is the last page.
+    pub cursor: Option<String>,
+    /// Unix timestamp (seconds) of when the server made this page.
+    /// A replica that has read every page can pass the `listed_at` of the
+    /// first page as `since` next time, to only list blobs added since.
+    pub listed_at: u64,
+}
+
+/// Response to `POST /api/v1/has`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HasResponse {
+    /// The blobs the server has
+    pub present: Vec<BlobInfo>,
+    /// The CIDs the server doesn't have
+    pub missing: Vec<Cid>,
+}
+
+/// Response to `GET /api/v1/usage`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    /// Number of blobs in the store
+    pub blobs: u64,
+    /// Total size of the blobs in the store, in bytes
+    pub size: u64,
+    /// How the server identifies the client that asked
+    pub client: String,
+    /// Total size of the blobs uploaded by the client that asked, in bytes
+    pub client_size: u64,
+    /// The limits the server enforces
+    pub quota: Quota,
+}
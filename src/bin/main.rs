@@ -1,34 +1,342 @@
-use magnetize::cid::Cid;
-use magnetize::cli::{Cli, Commands, Parser};
+use magnetize::cache::{ContentCache, DEFAULT_MAX_SIZE};
+use magnetize::car;
+use magnetize::chunk::{self, ChunkedFile, Chunker};
+use magnetize::cid::{Cid, Codec};
+use magnetize::cli::{
+    CacheCommands, CarCommands, Cli, CommandFactory, Commands, ConfigCommands, FromArgMatches,
+    OutputArgs, PinCommands, ServeArgs,
+};
+use magnetize::config::Config;
+use magnetize::drisl;
+use magnetize::file::{Existing, check_existing, temp_path, write_atomic};
+use magnetize::fsck::{fsck, repair};
+use magnetize::gc::{GcPolicy, collect_garbage};
+use magnetize::health::HealthCache;
 use magnetize::magnet::MagnetLink;
-use magnetize::request::get_and_check_cid;
+use magnetize::mirror::{MirrorConfig, MirrorOutcome, mirror_all, mirror_cids};
+use magnetize::pins::PinSet;
+use magnetize::progress;
+use magnetize::quota::{Quota, Usage};
+use magnetize::ratelimit::{RateLimit, RateLimits};
+use magnetize::request::{
+    FetchConfig, Progress, PushOutcome, RequestError, RetryPolicy, Throttle, Timeouts,
+    TransferEvent, build_client, has_cids, push_cid_to_all, race_get_and_check_cid,
+};
 use magnetize::server::{ServerConfig, serve};
-use magnetize::url::Url;
+use magnetize::store::{Store, StoreError};
+use magnetize::tls::TlsConfig;
+use magnetize::torrent::TorrentInfo;
+use magnetize::url::{Url, into_base_url};
 use std::collections::HashSet;
 use std::fs;
 use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::runtime;
 
 fn main() {
-    let args = Cli::parse();
+    let config = Config::load(Config::explicit_path(std::env::args_os()).as_deref())
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        });
+    let cli = config.apply(Cli::command()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(2);
+    });
+    let args = Cli::from_arg_matches(&cli.clone().get_matches()).unwrap_or_else(|err| err.exit());
     match args.command {
-        Commands::Get { url } => cmd_get(&url),
-        Commands::Add { file } => {
-            cmd_add(file);
+        Commands::Get {
+            url,
+            output,
+            mirror,
+            gateway,
+            race,
+            parallel,
+            stagger,
+            connect_timeout,
+            read_timeout,
+            timeout,
+            retries,
+            no_cache,
+            cache_size,
+            limit_rate,
+            json_events,
+        } => cmd_get(
+            magnet_with_fallbacks(&url, &mirror, &gateway),
+            &output,
+            FetchConfig {
+                concurrency: race,
+                stagger: Duration::from_millis(stagger),
+                retry: RetryPolicy {
+                    max_attempts: retries + 1,
+                    ..RetryPolicy::default()
+                },
+                progress: if json_events {
+                    progress::json_events()
+                } else {
+                    progress::progress_bars()
+                },
+                throttle: throttle(limit_rate),
+            },
+            parallel,
+            Timeouts {
+                connect: Some(Duration::from_secs(connect_timeout)),
+                read: Some(Duration::from_secs(read_timeout)),
+                total: timeout.map(Duration::from_secs),
+            },
+            (!no_cache).then(|| open_cache(cache_size)).flatten(),
+        ),
+        Commands::Add {
+            file,
+            max_blob_size,
+            max_store_size,
+            chunked,
+            chunk_size,
+            fixed_size,
+        } => {
+            let chunk_size = u32::try_from(chunk_size).unwrap_or(u32::MAX);
+            cmd_add(
+                file,
+                &Quota {
+                    max_blob_size,
+                    max_store_size,
+                    max_client_size: None,
+                },
+                chunked.then_some(if fixed_size {
+                    Chunker::Fixed(chunk_size)
+                } else {
+                    Chunker::ContentDefined(chunk_size)
+                }),
+            );
         }
-        Commands::Link { url } => {
-            cmd_link(url);
+        Commands::Link {
+            url,
+            from_torrent,
+            from_magnet,
+            connect_timeout,
+            read_timeout,
+        } => {
+            let timeouts = Timeouts {
+                connect: Some(Duration::from_secs(connect_timeout)),
+                read: Some(Duration::from_secs(read_timeout)),
+                total: None,
+            };
+            match (from_torrent, from_magnet) {
+                (Some(file), _) => cmd_link_torrent(file, timeouts),
+                (None, Some(magnet)) => cmd_link_bittorrent_magnet(&magnet, timeouts),
+                (None, None) => cmd_link(url, timeouts),
+            }
         }
-        Commands::Serve { dir, addr } => {
-            serve(ServerConfig { addr, dir });
+        Commands::Push {
+            file,
+            to,
+            token,
+            connect_timeout,
+            timeout,
+            limit_rate,
+            json_events,
+        } => cmd_push(
+            file,
+            to,
+            token.as_deref(),
+            Timeouts {
+                connect: Some(Duration::from_secs(connect_timeout)),
+                read: None,
+                total: timeout.map(Duration::from_secs),
+            },
+            if json_events {
+                progress::json_events()
+            } else {
+                progress::upload_bars()
+            },
+            throttle(limit_rate),
+        ),
+        Commands::Mirror {
+            from,
+            to,
+            cids,
+            all,
+            state,
+            token,
+            concurrency,
+            retries,
+            connect_timeout,
+            timeout,
+            limit_rate,
+            json_events,
+        } => cmd_mirror(
+            &from,
+            &to,
+            (!all).then_some(cids),
+            state.as_deref(),
+            MirrorConfig {
+                concurrency,
+                retry: RetryPolicy {
+                    max_attempts: retries + 1,
+                    ..RetryPolicy::default()
+                },
+                token,
+                progress: if json_events {
+                    progress::json_events()
+                } else {
+                    Progress::default()
+                },
+                throttle: throttle(limit_rate),
+                ..MirrorConfig::default()
+            },
+            Timeouts {
+                connect: Some(Duration::from_secs(connect_timeout)),
+                read: None,
+                total: timeout.map(Duration::from_secs),
+            },
+        ),
+        Commands::Has {
+            server,
+            cids,
+            missing,
+            connect_timeout,
+            read_timeout,
+        } => cmd_has(
+            &server,
+            cids,
+            missing,
+            Timeouts {
+                connect: Some(Duration::from_secs(connect_timeout)),
+                read: Some(Duration::from_secs(read_timeout)),
+                total: None,
+            },
+        ),
+        Commands::Gc {
+            dir,
+            older_than,
+            max_size,
+            dry_run,
+        } => cmd_gc(
+            &Store::new(dir),
+            &GcPolicy {
+                older_than,
+                max_size,
+            },
+            dry_run,
+        ),
+        Commands::Fsck {
+            dir,
+            repair_from,
+            connect_timeout,
+            read_timeout,
+            timeout,
+            dry_run,
+        } => cmd_fsck(
+            &Store::new(dir),
+            &parse_servers(&repair_from),
+            Timeouts {
+                connect: Some(Duration::from_secs(connect_timeout)),
+                read: Some(Duration::from_secs(read_timeout)),
+                total: timeout.map(Duration::from_secs),
+            },
+            dry_run,
+        ),
+        Commands::Config {
+            command: ConfigCommands::Show { command },
+        } => print!("{}", config.show(&cli, command.as_deref())),
+        Commands::Pin { dir, command } => cmd_pin(&Store::new(dir), command),
+        Commands::Car { dir, command } => cmd_car(&Store::new(dir), command),
+        Commands::Cache { command } => cmd_cache(command),
+        Commands::Serve(args) => {
+            let ServeArgs {
+                dir,
+                addr,
+                auth_token,
+                enable_listing,
+                verify_on_read,
+                enable_metrics,
+                drain_timeout,
+                tls_cert,
+                tls_key,
+                http_redirect_addr,
+                max_blob_size,
+                max_store_size,
+                max_client_size,
+                scrub_interval,
+                repair_from,
+                cors_origin,
+                read_requests_per_sec,
+                read_bytes_per_sec,
+                write_requests_per_sec,
+                write_bytes_per_sec,
+                max_concurrent_requests,
+            } = *args;
+            serve(ServerConfig {
+                addr,
+                dir,
+                auth_token,
+                enable_listing,
+                quota: Quota {
+                    max_blob_size,
+                    max_store_size,
+                    max_client_size,
+                },
+                verify_on_read,
+                enable_metrics,
+                scrub_interval,
+                repair_peers: parse_servers(&repair_from),
+                drain_timeout,
+                tls: tls_cert.zip(tls_key).map(|(cert, key)| TlsConfig {
+                    cert,
+                    key,
+                    redirect_addr: http_redirect_addr,
+                }),
+                cors_origins: cors_origin,
+                rate_limits: RateLimits {
+                    read: RateLimit {
+                        requests_per_sec: read_requests_per_sec,
+                        bytes_per_sec: read_bytes_per_sec,
+                    },
+                    write: RateLimit {
+                        requests_per_sec: write_requests_per_sec,
+                        bytes_per_sec: write_bytes_per_sec,
+                    },
+                    max_concurrent_requests,
+                },
+            });
         }
     }
 }
 
-fn cmd_get(url: &str) {
-    let mag = MagnetLink::parse(url).expect("Unable to parse magnet link");
-    let client = reqwest::Client::new();
+/// Parse the magnet link of `mag get`, adding its fallback mirrors and
+/// gateways
+fn magnet_with_fallbacks(url: &str, mirrors: &[String], gateways: &[String]) -> MagnetLink {
+    let mut mag = MagnetLink::parse(url).expect("Unable to parse magnet link");
+    mag.add_fallbacks(&parse_servers(mirrors), &parse_servers(gateways));
+    mag
+}
+
+fn cmd_get(
+    mag: MagnetLink,
+    output: &OutputArgs,
+    config: FetchConfig,
+    parallel: usize,
+    timeouts: Timeouts,
+    cache: Option<ContentCache>,
+) {
+    let output_path = output_path(output, &mag);
+
+    // Don't fetch anything if the output file already has the content.
+    // A chunked file doesn't hash to the CID of its record, so records are
+    // checked once we know what they hold.
+    if mag.cid.codec() == Codec::Raw
+        && let Some(path) = &output_path
+        && !may_write(
+            path,
+            check_existing(path, &mag.cid).expect("Unable to read output file"),
+            output.force,
+        )
+    {
+        return;
+    }
+
+    let client = build_client(timeouts).expect("Unable to build HTTP client");
 
     // Create a single-threaded tokio runtime
     let runtime = runtime::Builder::new_current_thread()
@@ -37,88 +345,768 @@ fn cmd_get(url: &str) {
         .build()
         .expect("Unable to create tokio runtime");
 
-    for url in mag.urls() {
-        match runtime.block_on(get_and_check_cid(&client, &url, &mag.cid)) {
-            Ok(body) => {
-                io::stdout()
-                    .write_all(&body)
-                    .expect("Unable to write to stdout");
-                return;
+    // Try historically fast and honest mirrors first
+    let health_path = HealthCache::default_path();
+    let mut health = health_path
+        .as_deref()
+        .map(HealthCache::load)
+        .unwrap_or_default();
+
+    let body = fetch_content(
+        &runtime,
+        &client,
+        &mag,
+        &config,
+        &mut health,
+        cache.as_ref(),
+    );
+
+    if let Some(body) = body {
+        let chunked = (mag.cid.codec() == Codec::Drisl)
+            .then(|| drisl::from_slice::<ChunkedFile>(&body).ok())
+            .flatten();
+        match chunked {
+            Some(file) => runtime.block_on(get_chunked(
+                &client,
+                &mag,
+                file,
+                output,
+                &config,
+                parallel,
+                &mut health,
+            )),
+            None => {
+                // Check again, in case the file changed while we were
+                // fetching
+                if output_path.as_deref().is_none_or(|path| {
+                    let existing =
+                        check_existing(path, &mag.cid).expect("Unable to read output file");
+                    may_write(path, existing, output.force)
+                }) {
+                    write_output(output_path.as_deref(), &body);
+                }
             }
-            Err(e) => {
-                eprintln!("Error getting URL {}\n\tError: {}", &url, e);
+        }
+    }
+
+    if let Some(path) = &health_path
+        && let Err(e) = health.save(path)
+    {
+        eprintln!("Unable to save mirror health cache: {}", e);
+    }
+}
+
+/// Whether `get` may write to a path, given what is there already.
+/// Reports why not.
+fn may_write(path: &Path, existing: Existing, force: bool) -> bool {
+    if path.is_dir() {
+        eprintln!(
+            "{} is a directory. Use --dir to write into it.",
+            path.display()
+        );
+        return false;
+    }
+    match existing {
+        Existing::Missing => true,
+        Existing::Matches => {
+            eprintln!("{} already exists with the same content", path.display());
+            false
+        }
+        Existing::Differs if force => true,
+        Existing::Differs => {
+            eprintln!(
+                "{} already exists with different content. Use --force to overwrite it.",
+                path.display()
+            );
+            false
+        }
+    }
+}
+
+/// The content of a magnet link's CID, from the cache or its sources
+fn fetch_content(
+    runtime: &runtime::Runtime,
+    client: &reqwest::Client,
+    mag: &MagnetLink,
+    config: &FetchConfig,
+    health: &mut HealthCache,
+    cache: Option<&ContentCache>,
+) -> Option<Vec<u8>> {
+    // Cache hits are verified against the CID, so they are as good as a
+    // fresh download. They are reported as a transfer from the cache file.
+    if let Some(cache) = cache {
+        match cache.get(&mag.cid) {
+            Ok(Some(body)) => {
+                if let Ok(url) = Url::from_file_path(cache.path(&mag.cid)) {
+                    let bytes = body.len() as u64;
+                    config.progress.emit(TransferEvent::Started {
+                        url: url.clone(),
+                        total: Some(bytes),
+                    });
+                    config.progress.emit(TransferEvent::Finished { url, bytes });
+                }
+                return Some(body);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Unable to read from cache: {}", e),
+        }
+    }
+
+    let urls = health.rank(mag.urls());
+    if urls.is_empty() {
+        eprintln!("Magnet link has no sources");
+        return None;
+    }
+
+    match runtime.block_on(race_get_and_check_cid(
+        client, &urls, &mag.cid, config, health,
+    )) {
+        Ok((_, body)) => {
+            if let Some(cache) = cache
+                && let Err(e) = cache.put(&mag.cid, &body)
+            {
+                eprintln!("Unable to write to cache: {}", e);
             }
+            Some(body)
+        }
+        Err(e) => {
+            eprintln!("Resource not found\n{}", e);
+            None
+        }
+    }
+}
+
+/// Fetch the chunks of a chunked file in parallel, and reassemble them.
+/// Files are written to a temp file and renamed into place once every chunk
+/// has arrived.
+async fn get_chunked(
+    client: &reqwest::Client,
+    mag: &MagnetLink,
+    file: ChunkedFile,
+    output: &OutputArgs,
+    config: &FetchConfig,
+    parallel: usize,
+    health: &mut HealthCache,
+) {
+    if mag.urls_for(&mag.cid).is_empty() {
+        eprintln!(
+            "Magnet link has no sources for the chunks. Web seeds only serve the root record."
+        );
+        return;
+    }
+    let urls_for = |cid: &Cid| mag.urls_for(cid);
+
+    let chunks = match chunk::resolve(client, file, urls_for, config, health).await {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            eprintln!("Unable to read the chunks of the file\n{}", e);
+            return;
         }
+    };
+
+    let Some(path) = output_path(output, mag) else {
+        let mut stdout = io::stdout().lock();
+        let result = chunk::fetch(
+            client,
+            &chunks,
+            urls_for,
+            config,
+            health,
+            parallel,
+            &mut stdout,
+        )
+        .await;
+        if let Err(e) = result {
+            eprintln!("Resource not found\n{}", e);
+        }
+        return;
+    };
+
+    let existing = chunk::check_existing(&path, &chunks).expect("Unable to read output file");
+    if !may_write(&path, existing, output.force) {
+        return;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).expect("Unable to create output directory");
+    }
+    let tmp_path = temp_path(&path).expect("Unable to write output file");
+    let mut out =
+        io::BufWriter::new(fs::File::create(&tmp_path).expect("Unable to write output file"));
+    let result = chunk::fetch(
+        client, &chunks, urls_for, config, health, parallel, &mut out,
+    )
+    .await;
+    let written = result.map_err(|e| e.to_string()).and_then(|_| {
+        let file = out.into_inner().map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+        fs::rename(&tmp_path, &path).map_err(|e| e.to_string())
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        eprintln!("Resource not found\n{}", e);
     }
+}
 
-    eprintln!("Resource not found");
+/// Resolve the file `get` should write to, or None for stdout
+fn output_path(output: &OutputArgs, mag: &MagnetLink) -> Option<PathBuf> {
+    let file = match (&output.file, output.remote_name || output.dir.is_some()) {
+        (Some(file), _) => file.clone(),
+        (None, true) => PathBuf::from(mag.file_name()),
+        (None, false) => return None,
+    };
+    match &output.dir {
+        Some(dir) => Some(dir.join(file)),
+        None => Some(file),
+    }
 }
 
-fn cmd_add(file: Option<PathBuf>) {
-    match file {
-        Some(file) => cmd_add_file(file),
-        None => cmd_add_stdin(),
+fn write_output(path: Option<&Path>, body: &[u8]) {
+    match path {
+        Some(path) => {
+            let written = match path.parent() {
+                Some(parent) => fs::create_dir_all(parent),
+                None => Ok(()),
+            }
+            .and_then(|_| write_atomic(path, body));
+            if let Err(e) = written {
+                eprintln!("Unable to write {}: {}", path.display(), e);
+            }
+        }
+        None => {
+            io::stdout()
+                .write_all(body)
+                .expect("Unable to write to stdout");
+        }
     }
 }
 
-fn cmd_link(ws: Vec<String>) {
-    let ws_urls: Vec<Url> = ws
+fn cmd_push(
+    file: PathBuf,
+    to: Vec<String>,
+    token: Option<&str>,
+    timeouts: Timeouts,
+    progress: Progress,
+    throttle: Throttle,
+) {
+    let servers: Vec<Url> = to
         .iter()
-        .map(|s| Url::parse(s).expect("Invalid url"))
+        .map(|s| into_base_url(Url::parse(s).expect("Invalid url")))
         .collect();
 
-    let mut cids: HashSet<Cid> = HashSet::new();
+    let cid = {
+        let mut reader = io::BufReader::new(fs::File::open(&file).expect("Unable to open file"));
+        Cid::read(&mut reader).expect("Unable to read file")
+    };
+
+    let client = build_client(timeouts).expect("Unable to build HTTP client");
+
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_time()
+        .enable_io()
+        .build()
+        .expect("Unable to create tokio runtime");
+
+    let results = runtime.block_on(push_cid_to_all(
+        &client, &servers, &cid, &file, token, &progress, &throttle,
+    ));
 
-    for url in &ws_urls {
-        match reqwest::blocking::get(url.as_str()) {
-            Ok(response) => {
-                let body = response.bytes().expect("Unable to read response");
-                let body_cid = Cid::of(&body);
-                cids.insert(body_cid);
+    let mut rs = Vec::new();
+    for (server, result) in results {
+        match result {
+            Ok(PushOutcome::AlreadyPresent) => {
+                eprintln!("{} already has {}", server, cid);
+                rs.push(server);
             }
-            Err(e) => {
-                eprintln!("Error fetching URL: {}", e);
+            Ok(PushOutcome::Uploaded) => {
+                eprintln!("Uploaded {} to {}", cid, server);
+                rs.push(server);
             }
+            Err(e) => eprintln!("Error pushing to {}\n\tError: {}", server, e),
         }
     }
 
-    if cids.is_empty() {
-        eprintln!("Unable to reach any of the provided URLs");
+    if rs.is_empty() {
+        eprintln!("Unable to push to any of the servers");
         return;
     }
 
-    if cids.len() != 1 {
-        eprintln!("URLs do not point to the same resource");
+    let mag = MagnetLink {
+        cid,
+        rs,
+        ws: Vec::new(),
+        gw: Vec::new(),
+        btmh: None,
+        btih: None,
+        dn: file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.to_string()),
+    };
+
+    println!("{}", mag);
+}
+
+/// Mirror the given CIDs, or everything the source lists if `cids` is None
+fn cmd_mirror(
+    from: &str,
+    to: &str,
+    cids: Option<Vec<String>>,
+    state: Option<&Path>,
+    config: MirrorConfig,
+    timeouts: Timeouts,
+) {
+    let from = into_base_url(Url::parse(from).expect("Invalid url"));
+    let to = into_base_url(Url::parse(to).expect("Invalid url"));
+    let cids: Option<Vec<Cid>> = cids.map(|cids| {
+        cids.iter()
+            .map(|s| Cid::parse(s).expect("Invalid CID"))
+            .collect()
+    });
+
+    let client = build_client(timeouts).expect("Unable to build HTTP client");
+
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_time()
+        .enable_io()
+        .build()
+        .expect("Unable to create tokio runtime");
+
+    let mut report = |cid: &Cid, result: &Result<MirrorOutcome, RequestError>| match result {
+        Ok(MirrorOutcome::AlreadyPresent) => eprintln!("{} already has {}", to, cid),
+        Ok(MirrorOutcome::Copied { size }) => eprintln!("Copied {} ({} bytes)", cid, size),
+        Err(e) => eprintln!("Error copying {}\n\tError: {}", cid, e),
+    };
+
+    let summary = match cids {
+        Some(cids) => {
+            runtime.block_on(mirror_cids(&client, &from, &to, cids, &config, &mut report))
+        }
+        None => {
+            match runtime.block_on(mirror_all(&client, &from, &to, &config, state, &mut report)) {
+                Ok(summary) => summary,
+                Err(e) => {
+                    eprintln!("Unable to list content on {}\n\tError: {}", from, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+
+    eprintln!(
+        "Copied {}, already present {}, failed {}",
+        summary.copied, summary.already_present, summary.failed
+    );
+    if summary.failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn cmd_has(server: &str, cids: Vec<String>, missing: bool, timeouts: Timeouts) {
+    let server = into_base_url(Url::parse(server).expect("Invalid url"));
+
+    let cids = if cids.is_empty() {
+        io::stdin()
+            .lines()
+            .map(|line| line.expect("Unable to read stdin"))
+            .filter(|line| !line.trim().is_empty())
+            .collect()
+    } else {
+        cids
+    };
+    let cids: Vec<Cid> = cids
+        .iter()
+        .map(|s| Cid::parse(s.trim()).expect("Invalid CID"))
+        .collect();
+
+    let client = build_client(timeouts).expect("Unable to build HTTP client");
+
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_time()
+        .enable_io()
+        .build()
+        .expect("Unable to create tokio runtime");
+
+    let has = match runtime.block_on(has_cids(&client, &server, &cids)) {
+        Ok(has) => has,
+        Err(e) => {
+            eprintln!("Unable to check {}\n\tError: {}", server, e);
+            return;
+        }
+    };
+
+    if missing {
+        for cid in has.missing {
+            println!("{}", cid);
+        }
+    } else {
+        for blob in has.present {
+            println!("{}\t{}", blob.cid, blob.size);
+        }
+    }
+}
+
+fn cmd_gc(store: &Store, policy: &GcPolicy, dry_run: bool) {
+    let report = collect_garbage(store, policy, dry_run).expect("Unable to collect garbage");
+    for blob in &report.removed {
+        println!("{}\t{}", blob.cid, blob.size);
+    }
+    let removed_size: u64 = report.removed.iter().map(|blob| blob.size).sum();
+    eprintln!(
+        "{} {} blobs ({} bytes). {} bytes left.",
+        if dry_run { "Would remove" } else { "Removed" },
+        report.removed.len(),
+        removed_size,
+        report.size
+    );
+}
+
+fn cmd_fsck(store: &Store, peers: &[Url], timeouts: Timeouts, dry_run: bool) {
+    let report = fsck(store, dry_run).expect("Unable to check directory");
+    for cid in &report.corrupted {
+        println!("corrupted\t{}", cid);
+    }
+    for path in &report.removed {
+        println!("stray\t{}", path.display());
+    }
+    for (path, err) in &report.errors {
+        println!("error\t{}\t{}", path.display(), err);
+    }
+
+    let mut unrepaired = report.corrupted.len();
+    if !dry_run && !peers.is_empty() && !report.corrupted.is_empty() {
+        let client = build_client(timeouts).expect("Unable to build HTTP client");
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_time()
+            .enable_io()
+            .build()
+            .expect("Unable to create tokio runtime");
+        for cid in &report.corrupted {
+            if let Some(peer) = runtime.block_on(repair(&client, store, cid, peers)) {
+                println!("repaired\t{}\t{}", cid, peer);
+                unrepaired -= 1;
+            }
+        }
+    }
+
+    eprintln!(
+        "Checked {} blobs. {} corrupted ({} {}), {} stray files {}, {} files unreadable.",
+        report.checked,
+        report.corrupted.len(),
+        unrepaired,
+        if dry_run {
+            "to quarantine"
+        } else {
+            "unrepaired"
+        },
+        report.removed.len(),
+        if dry_run { "to remove" } else { "removed" },
+        report.errors.len(),
+    );
+    if unrepaired > 0 || !report.errors.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn cmd_pin(store: &Store, command: PinCommands) {
+    let _lock = PinSet::lock(store).expect("Unable to lock pins");
+    let mut pins = PinSet::load(store).expect("Unable to read pins");
+    match command {
+        PinCommands::Add { name, cid } => {
+            let cid = Cid::parse(&cid).expect("Invalid CID");
+            if !store.contains(&cid) {
+                eprintln!("Warning: {} is not in {}", cid, store.dir().display());
+            }
+            pins.add(&name, cid);
+            pins.save(store).expect("Unable to save pins");
+        }
+        PinCommands::Rm { name } => {
+            if pins.remove(&name).is_none() {
+                eprintln!("No pin named {}", name);
+                return;
+            }
+            pins.save(store).expect("Unable to save pins");
+        }
+        PinCommands::Ls => {
+            for (name, cid) in &pins.pins {
+                println!("{}\t{}", name, cid);
+            }
+        }
+    }
+}
+
+fn cmd_car(store: &Store, command: CarCommands) {
+    match command {
+        CarCommands::Export { cids } => {
+            let cids: Vec<Cid> = cids
+                .iter()
+                .map(|s| Cid::parse(s).expect("Invalid CID"))
+                .collect();
+            let stdout = io::BufWriter::new(io::stdout().lock());
+            if let Err(e) = car::export(store, &cids, stdout) {
+                eprintln!("Unable to export CAR\n\tError: {}", e);
+                std::process::exit(1);
+            }
+        }
+        CarCommands::Import { file } => {
+            let result = match file {
+                Some(file) => {
+                    let file = fs::File::open(&file).expect("Unable to open file");
+                    car::import(store, io::BufReader::new(file))
+                }
+                None => car::import(store, io::stdin().lock()),
+            };
+            match result {
+                Ok(summary) => {
+                    eprintln!(
+                        "Added {}, already present {}",
+                        summary.added, summary.already_present
+                    );
+                    for root in summary.roots {
+                        println!("{}", root);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Unable to import CAR\n\tError: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// The bandwidth limit for `--limit-rate`, if any
+fn throttle(limit_rate: Option<u64>) -> Throttle {
+    limit_rate.map(Throttle::new).unwrap_or_default()
+}
+
+/// Parse server URLs given on the command line
+fn parse_servers(urls: &[String]) -> Vec<Url> {
+    urls.iter()
+        .map(|s| into_base_url(Url::parse(s).expect("Invalid url")))
+        .collect()
+}
+
+/// Open the content cache in the default location, if there is one
+fn open_cache(max_size: u64) -> Option<ContentCache> {
+    ContentCache::default_dir().map(|dir| ContentCache::new(dir, max_size))
+}
+
+fn cmd_cache(command: CacheCommands) {
+    let max_size = match command {
+        CacheCommands::Gc { max_size } => max_size,
+        _ => DEFAULT_MAX_SIZE,
+    };
+    let Some(cache) = open_cache(max_size) else {
+        eprintln!("Unable to find a cache directory");
         return;
+    };
+
+    match command {
+        CacheCommands::Ls => {
+            for entry in cache.entries().expect("Unable to read cache") {
+                println!("{}\t{}", entry.cid, entry.size);
+            }
+        }
+        CacheCommands::Size => {
+            println!("{}", cache.size().expect("Unable to read cache"));
+        }
+        CacheCommands::Clear => {
+            cache.clear().expect("Unable to clear cache");
+        }
+        CacheCommands::Gc { .. } => {
+            for entry in cache.gc().expect("Unable to collect garbage") {
+                println!("{}\t{}", entry.cid, entry.size);
+            }
+        }
     }
+}
 
-    let cid = cids.into_iter().next().unwrap();
+fn cmd_add(file: Option<PathBuf>, quota: &Quota, chunker: Option<Chunker>) {
+    // Content is added to the current directory, so that is the store the
+    // quota applies to
+    let max_size = if *quota == Quota::default() {
+        None
+    } else {
+        let usage = Usage::scan(&Store::new(".")).expect("Unable to read current directory");
+        quota.remaining(&usage, "")
+    };
+    match (file, chunker) {
+        (Some(file), Some(chunker)) => cmd_add_chunked(file, max_size, chunker),
+        (Some(file), None) => cmd_add_file(file, max_size),
+        (None, _) => cmd_add_stdin(max_size),
+    }
+}
+
+fn cmd_link(ws: Vec<String>, timeouts: Timeouts) {
+    let ws_urls: Vec<Url> = ws
+        .iter()
+        .map(|s| Url::parse(s).expect("Invalid url"))
+        .collect();
+
+    let Some((cid, _)) = fetch_cid(&ws_urls, timeouts, |_| Ok(())) else {
+        return;
+    };
 
     let mag = MagnetLink {
         cid,
         ws: ws_urls,
+        gw: Vec::new(),
         rs: Vec::new(),
         btmh: None,
+        btih: None,
         dn: None,
     };
 
-    println!("{}", mag.to_string());
+    println!("{}", mag);
+}
+
+fn cmd_link_torrent(file: PathBuf, timeouts: Timeouts) {
+    let bytes = fs::read(&file).expect("Unable to read torrent file");
+    let torrent = TorrentInfo::from_metainfo(&bytes).expect("Unable to parse torrent file");
+    link_torrent(torrent, timeouts);
+}
+
+fn cmd_link_bittorrent_magnet(url: &str, timeouts: Timeouts) {
+    let torrent = TorrentInfo::parse_magnet(url).expect("Unable to parse magnet link");
+    link_torrent(torrent, timeouts);
+}
+
+/// Print a hybrid magnet link for a torrent, computing the CID by fetching
+/// the payload from the torrent's web seeds. Web seeds whose payload doesn't
+/// match the torrent are left out, so the CID and infohashes name the same
+/// content.
+fn link_torrent(torrent: TorrentInfo, timeouts: Timeouts) {
+    if torrent.ws.is_empty() {
+        eprintln!("Torrent has no web seeds to fetch the content from");
+        return;
+    }
+
+    let Some((cid, ws)) = fetch_cid(&torrent.ws, timeouts, |payload| {
+        torrent
+            .check_payload(payload)
+            .map_err(|err| err.to_string())
+    }) else {
+        return;
+    };
+
+    let mag = MagnetLink {
+        cid,
+        ws,
+        gw: Vec::new(),
+        rs: Vec::new(),
+        btmh: torrent.btmh,
+        btih: torrent.btih,
+        dn: torrent.name,
+    };
+
+    println!("{}", mag);
 }
 
-fn cmd_add_file(file: PathBuf) {
-    let bytes = fs::read(&file).expect("Unable to read file");
-    let cid = Cid::of(&bytes);
-    let cid_pathbuf = PathBuf::from(cid.to_string());
-    fs::write(&cid_pathbuf, bytes).expect("Unable to write file");
+/// Fetch each URL and compute the CID of the content, skipping content that
+/// `check` turns away.
+/// Returns the CID and the URLs that served it, or None (and reports why) if
+/// none of the URLs could be reached, or if they do not all point to the
+/// same content.
+fn fetch_cid(
+    urls: &[Url],
+    timeouts: Timeouts,
+    check: impl Fn(&[u8]) -> Result<(), String>,
+) -> Option<(Cid, Vec<Url>)> {
+    let client = build_client(timeouts).expect("Unable to build HTTP client");
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_time()
+        .enable_io()
+        .build()
+        .expect("Unable to create tokio runtime");
+
+    let mut cids: HashSet<Cid> = HashSet::new();
+    let mut served = Vec::new();
+
+    for url in urls {
+        let body = runtime.block_on(async {
+            client
+                .get(url.as_str())
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await
+        });
+        match body {
+            Ok(body) => match check(&body) {
+                Ok(()) => {
+                    cids.insert(Cid::of(&body));
+                    served.push(url.clone());
+                }
+                Err(e) => eprintln!("Skipping {}: {}", url, e),
+            },
+            Err(e) => {
+                eprintln!("Error fetching URL: {}", e);
+            }
+        }
+    }
+
+    if cids.is_empty() {
+        eprintln!("Unable to fetch the content from any of the provided URLs");
+        return None;
+    }
+
+    if cids.len() != 1 {
+        eprintln!("URLs do not point to the same resource");
+        return None;
+    }
+
+    cids.into_iter().next().map(|cid| (cid, served))
+}
+
+fn cmd_add_file(file: PathBuf, max_size: Option<u64>) {
+    let reader = fs::File::open(&file).expect("Unable to read file");
+    let cid = add_reader(reader, max_size, "File");
     println!("{}", cid);
 }
 
-fn cmd_add_stdin() {
-    let mut bytes = Vec::new();
-    io::stdin()
-        .read_to_end(&mut bytes)
-        .expect("Unable to read stdin");
-    let cid = Cid::of(&bytes);
-    let cid_pathbuf = PathBuf::from(cid.to_string());
-    fs::write(&cid_pathbuf, bytes).expect("Unable to write file");
+/// Add a file as chunks plus the records listing them, and print the CID
+/// of the root record
+fn cmd_add_chunked(file: PathBuf, max_size: Option<u64>, chunker: Chunker) {
+    let size = fs::metadata(&file).expect("Unable to read file").len();
+    if let Some(max_size) = max_size
+        && size > max_size
+    {
+        eprintln!(
+            "File exceeds quota. At most {} more bytes allowed.",
+            max_size
+        );
+        std::process::exit(1);
+    }
+    let reader = fs::File::open(&file).expect("Unable to read file");
+    match chunk::add(&Store::new("."), reader, chunker) {
+        Ok(cid) => println!("{}", cid),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn cmd_add_stdin(max_size: Option<u64>) {
+    add_reader(io::stdin().lock(), max_size, "Input");
+}
+
+/// Stream content into the current directory under its CID, stopping as
+/// soon as it exceeds the quota. Exits if it does, or on any error.
+fn add_reader(reader: impl Read, max_size: Option<u64>, what: &str) -> Cid {
+    match Store::new(".").add_reader(reader, max_size.unwrap_or(u64::MAX)) {
+        Ok((cid, _)) => cid,
+        Err(StoreError::TooLarge { max_size }) => {
+            eprintln!(
+                "{} exceeds quota. At most {} more bytes allowed.",
+                what, max_size
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Unable to add {}: {}", what.to_lowercase(), e);
+            std::process::exit(1);
+        }
+    }
 }
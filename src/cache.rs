@@ -0,0 +1,289 @@
+use crate::cid::Cid;
+use crate::store::{STALE_TEMP_AGE, TEMP_SUFFIX};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Default maximum size of the cache in bytes (1 GiB)
+pub const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// A local, content-addressed cache of verified blobs.
+///
+/// Each blob is stored in a file named after its CID. The file's modified
+/// time doubles as its last-used time, which we use to evict the least
+/// recently used blobs when the cache grows beyond `max_size`.
+///
+/// Because blobs are keyed by CID, the cache is self-certifying. Blobs are
+/// re-verified on read, so a corrupted cache can never produce wrong bytes.
+#[derive(Debug, Clone)]
+pub struct ContentCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// A blob in the cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub cid: Cid,
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+impl ContentCache {
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+        }
+    }
+
+    /// Default location of the content cache in the user's cache directory
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("magnetize").join("blobs"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the file holding the blob for a CID
+    pub fn path(&self, cid: &Cid) -> PathBuf {
+        self.dir.join(cid.to_string())
+    }
+
+    /// Get a blob from the cache, verifying it against the CID.
+    /// Blobs that fail verification are removed, and treated as a miss.
+    pub fn get(&self, cid: &Cid) -> io::Result<Option<Vec<u8>>> {
+        let path = self.path(cid);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        if Cid::of(&bytes).with_codec(cid.codec()) != *cid {
+            tracing::warn!(%cid, "removing corrupted cache entry");
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+
+        // Mark as recently used
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now())?;
+
+        Ok(Some(bytes))
+    }
+
+    /// Add a verified blob to the cache, then evict old blobs if the cache is
+    /// over its size limit.
+    /// Blobs larger than the whole cache are not stored.
+    pub fn put(&self, cid: &Cid, bytes: &[u8]) -> io::Result<()> {
+        if bytes.len() as u64 > self.max_size {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        // Write to a temp file and rename, so we never leave a partial blob
+        // under a CID name. The temp file has a unique name, so concurrent
+        // writes of the same blob don't clobber each other.
+        let nonce: u64 = rand::random();
+        let tmp_path = self
+            .dir
+            .join(format!("{}.{:016x}{}", cid, nonce, TEMP_SUFFIX));
+        let result =
+            fs::write(&tmp_path, bytes).and_then(|_| fs::rename(&tmp_path, self.path(cid)));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result?;
+        self.gc()?;
+        Ok(())
+    }
+
+    /// List the blobs in the cache, least recently used first
+    pub fn entries(&self) -> io::Result<Vec<CacheEntry>> {
+        let read_dir = match fs::read_dir(&self.dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut entries = Vec::new();
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry?;
+            let Some(cid) = dir_entry
+                .file_name()
+                .to_str()
+                .and_then(|s| Cid::parse(s).ok())
+            else {
+                continue;
+            };
+            let metadata = dir_entry.metadata()?;
+            entries.push(CacheEntry {
+                cid,
+                size: metadata.len(),
+                last_used: metadata.modified()?,
+            });
+        }
+        entries.sort_by_key(|entry| entry.last_used);
+        Ok(entries)
+    }
+
+    /// Total size of the blobs in the cache in bytes
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.entries()?.iter().map(|entry| entry.size).sum())
+    }
+
+    /// Remove everything from the cache
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Evict least recently used blobs until the cache fits in `max_size`,
+    /// and remove anything that isn't a blob (e.g. temp files left behind by
+    /// an interrupted write). Recent temp files are left alone, since they may
+    /// belong to another process writing to the cache.
+    /// Returns the evicted entries.
+    pub fn gc(&self) -> io::Result<Vec<CacheEntry>> {
+        if let Ok(read_dir) = fs::read_dir(&self.dir) {
+            let now = SystemTime::now();
+            for dir_entry in read_dir {
+                let dir_entry = dir_entry?;
+                let name = dir_entry.file_name();
+                let name = name.to_string_lossy();
+                if Cid::parse(&name).is_ok() || !dir_entry.file_type()?.is_file() {
+                    continue;
+                }
+                if name.ends_with(TEMP_SUFFIX) {
+                    let age = now
+                        .duration_since(dir_entry.metadata()?.modified()?)
+                        .unwrap_or(Duration::ZERO);
+                    if age < STALE_TEMP_AGE {
+                        continue;
+                    }
+                }
+                match fs::remove_file(dir_entry.path()) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+        }
+
+        let entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+        let mut evicted = Vec::new();
+        for entry in entries {
+            if size <= self.max_size {
+                break;
+            }
+            fs::remove_file(self.path(&entry.cid))?;
+            size -= entry.size;
+            evicted.push(entry);
+        }
+        Ok(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "magnetize-cache-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let cache = ContentCache::new(test_dir("put-get"), DEFAULT_MAX_SIZE);
+        let cid = Cid::of(b"hello world");
+
+        assert_eq!(cache.get(&cid).unwrap(), None);
+        cache.put(&cid, b"hello world").unwrap();
+        assert_eq!(cache.get(&cid).unwrap(), Some(b"hello world".to_vec()));
+        assert_eq!(cache.size().unwrap(), 11);
+
+        cache.clear().unwrap();
+        assert_eq!(cache.get(&cid).unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_removes_corrupted_entry() {
+        let cache = ContentCache::new(test_dir("corrupted"), DEFAULT_MAX_SIZE);
+        let cid = Cid::of(b"hello world");
+
+        cache.put(&cid, b"hello world").unwrap();
+        fs::write(cache.dir().join(cid.to_string()), b"bit rot").unwrap();
+
+        assert_eq!(cache.get(&cid).unwrap(), None);
+        assert!(cache.entries().unwrap().is_empty());
+
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn test_gc_evicts_least_recently_used() {
+        let cache = ContentCache::new(test_dir("lru"), 10);
+        let old = Cid::of(b"aaaaa");
+        let new = Cid::of(b"bbbbb");
+
+        cache.put(&old, b"aaaaa").unwrap();
+        cache.put(&new, b"bbbbb").unwrap();
+
+        // Make `old` the least recently used, regardless of timer resolution
+        File::options()
+            .write(true)
+            .open(cache.dir().join(old.to_string()))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+
+        cache.put(&Cid::of(b"ccccc"), b"ccccc").unwrap();
+
+        let cids: Vec<Cid> = cache.entries().unwrap().iter().map(|e| e.cid).collect();
+        assert_eq!(cids.len(), 2);
+        assert!(!cids.contains(&old));
+        assert!(cids.contains(&new));
+
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn test_gc_removes_stray_files() {
+        let cache = ContentCache::new(test_dir("stray"), DEFAULT_MAX_SIZE);
+        let cid = Cid::of(b"hello world");
+
+        cache.put(&cid, b"hello world").unwrap();
+        let stray = cache.dir().join("stray");
+        fs::write(&stray, b"stray").unwrap();
+        let stale_temp = cache.dir().join(format!("{}.1{}", cid, TEMP_SUFFIX));
+        fs::write(&stale_temp, b"partial").unwrap();
+        File::options()
+            .write(true)
+            .open(&stale_temp)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * STALE_TEMP_AGE)
+            .unwrap();
+        let fresh_temp = cache.dir().join(format!("{}.2{}", cid, TEMP_SUFFIX));
+        fs::write(&fresh_temp, b"in progress").unwrap();
+
+        cache.gc().unwrap();
+        assert!(!stray.exists());
+        assert!(!stale_temp.exists());
+        assert!(fresh_temp.exists());
+        assert_eq!(cache.get(&cid).unwrap(), Some(b"hello world".to_vec()));
+
+        cache.clear().unwrap();
+    }
+}
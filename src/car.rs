@@ -0,0 +1,520 @@
+use crate::cid::Cid;
+use crate::drisl::{self, DrislError, Value};
+use crate::store::{Store, StoreError};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+/// Media type of a CARv1 file in the IPFS gateway specs
+pub const CAR_MEDIA_TYPE: &str = "application/vnd.ipld.car; version=1";
+
+/// Largest CAR header we'll read. Headers only list the roots.
+const MAX_HEADER_SIZE: u64 = 1024 * 1024;
+
+/// Longest multihash digest in a CID we'll read. sha-256 digests are 32
+/// bytes, and no common hash has more than 64.
+const MAX_DIGEST_SIZE: u64 = 64;
+
+/// What happened when importing a CAR file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Roots listed in the CAR header
+    pub roots: Vec<Cid>,
+    /// Blocks added to the store
+    pub added: u64,
+    /// Blocks the store already had
+    pub already_present: u64,
+}
+
+/// Write the blobs for `cids` from a store as a CARv1 file, with the same
+/// CIDs as roots. Blobs are streamed from disk, so they can be of any size.
+/// See <https://ipld.io/specs/transport/car/carv1/>
+///
+/// Every blob is checked to exist before anything is written, and each one
+/// is hashed as it is copied, so a blob that no longer matches its CID fails
+/// the export instead of ending up in the CAR.
+/// Returns the number of bytes written.
+pub fn export(store: &Store, cids: &[Cid], mut writer: impl Write) -> Result<u64, CarError> {
+    let mut sizes = Vec::with_capacity(cids.len());
+    for cid in cids {
+        match fs::metadata(store.path(cid)) {
+            Ok(metadata) => sizes.push(metadata.len()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(CarError::Missing(*cid));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let header = header(cids);
+    writer.write_all(&header)?;
+    let mut written = header.len() as u64;
+    for (cid, size) in cids.iter().zip(sizes) {
+        let prefix = block_prefix(cid, size);
+        writer.write_all(&prefix)?;
+        // Open each blob only when it is its turn, so exporting many blobs
+        // doesn't hold a file open for each
+        let file = fs::File::open(store.path(cid))?;
+        let mut tee = Tee {
+            reader: file.take(size),
+            writer: &mut writer,
+            copied: 0,
+        };
+        let actual = Cid::read(&mut tee)?.with_codec(cid.codec());
+        if tee.copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("blob {} shrank while it was being exported", cid),
+            )
+            .into());
+        }
+        if actual != *cid {
+            return Err(CarError::IntegrityError {
+                expected: *cid,
+                actual,
+            });
+        }
+        written += prefix.len() as u64 + size;
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Copies what is read from `reader` to `writer`, so it can be hashed and
+/// written in one pass
+struct Tee<R, W> {
+    reader: R,
+    writer: W,
+    copied: u64,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..len])?;
+        self.copied += len as u64;
+        Ok(len)
+    }
+}
+
+/// Add every block of a CARv1 file to a store.
+///
+/// Each block is streamed into the store and verified against its CID
+/// before it is moved into place, so blocks of any size can be imported.
+/// Import stops at the first block that doesn't match, or whose CID isn't a
+/// raw or DRISL sha-256 CID, leaving the blocks before it in the store.
+pub fn import(store: &Store, reader: impl Read) -> Result<ImportSummary, CarError> {
+    let mut car = CarReader::new(reader)?;
+    let mut summary = ImportSummary {
+        roots: car.roots().to_vec(),
+        ..ImportSummary::default()
+    };
+    fs::create_dir_all(store.dir())?;
+    while let Some(block) = car.next_block()? {
+        let cid = block.cid();
+        if store.contains(&cid) {
+            block.verify()?;
+            summary.already_present += 1;
+            continue;
+        }
+        match store.put_reader(&cid, block) {
+            Ok(_) => summary.added += 1,
+            Err(StoreError::IntegrityError { expected, actual }) => {
+                return Err(CarError::IntegrityError { expected, actual });
+            }
+            Err(StoreError::Io(err)) => return Err(err.into()),
+            Err(err) => return Err(io::Error::other(err).into()),
+        }
+    }
+    Ok(summary)
+}
+
+/// The varint-prefixed header of a CARv1 file: the dag-cbor map
+/// `{"roots": [CIDs], "version": 1}`
+pub fn header(roots: &[Cid]) -> Vec<u8> {
+    let header = Value::Map(BTreeMap::from([
+        (
+            "roots".to_string(),
+            Value::Array(roots.iter().copied().map(Value::Link).collect()),
+        ),
+        ("version".to_string(), Value::Integer(1)),
+    ]));
+    let cbor = drisl::encode(&header).expect("CAR headers are always valid DRISL");
+
+    let mut out = Vec::with_capacity(cbor.len() + 2);
+    write_varint(&mut out, cbor.len() as u64);
+    out.extend_from_slice(&cbor);
+    out
+}
+
+/// What comes before the data of a block in a CAR file: the varint length
+/// of the section, then the CID
+pub fn block_prefix(cid: &Cid, size: u64) -> Vec<u8> {
+    let cid_bytes = cid.to_bytes();
+    let mut out = Vec::with_capacity(cid_bytes.len() + 10);
+    write_varint(&mut out, cid_bytes.len() as u64 + size);
+    out.extend_from_slice(&cid_bytes);
+    out
+}
+
+/// Reads the blocks of a CARv1 file one at a time, without holding a whole
+/// block in memory
+pub struct CarReader<R> {
+    reader: R,
+    roots: Vec<Cid>,
+    /// Bytes of the current block's data that haven't been read yet
+    unread: u64,
+}
+
+impl<R: Read> CarReader<R> {
+    /// Start reading a CAR file, reading its header
+    pub fn new(mut reader: R) -> Result<Self, CarError> {
+        let Some(len) = read_varint(&mut reader)? else {
+            return Err(CarError::Invalid("empty file".to_string()));
+        };
+        if len > MAX_HEADER_SIZE {
+            return Err(CarError::Invalid("header too large".to_string()));
+        }
+        let bytes = read_exact_vec(&mut reader, len)?;
+        let roots = parse_header(&bytes)?;
+        Ok(Self {
+            reader,
+            roots,
+            unread: 0,
+        })
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// Read the CID of the next block. None at the end of the file.
+    /// Whatever is left of the data of the previous block is skipped.
+    pub fn next_block(&mut self) -> Result<Option<Block<'_, R>>, CarError> {
+        let skipped = io::copy(&mut (&mut self.reader).take(self.unread), &mut io::sink())?;
+        if skipped != self.unread {
+            return Err(truncated().into());
+        }
+        self.unread = 0;
+        let Some(len) = read_varint(&mut self.reader)? else {
+            return Ok(None);
+        };
+        let (cid, cid_len) = read_cid(&mut self.reader, len)?;
+        self.unread = len - cid_len;
+        Ok(Some(Block { cid, car: self }))
+    }
+}
+
+/// A block of a CAR file. Reading it reads its data, which is only verified
+/// against its CID by `verify` or by whoever reads it, like
+/// `Store::put_reader`.
+pub struct Block<'a, R> {
+    cid: Cid,
+    car: &'a mut CarReader<R>,
+}
+
+impl<R: Read> Block<'_, R> {
+    pub fn cid(&self) -> Cid {
+        self.cid
+    }
+
+    /// Size of the block's data in bytes
+    pub fn size(&self) -> u64 {
+        self.car.unread
+    }
+
+    /// Read the rest of the block's data, checking that it matches its CID
+    pub fn verify(mut self) -> Result<(), CarError> {
+        let actual = Cid::read(&mut self)?.with_codec(self.cid.codec());
+        if actual != self.cid {
+            return Err(CarError::IntegrityError {
+                expected: self.cid,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Block<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.car.unread == 0 {
+            return Ok(0);
+        }
+        let max =
+            usize::try_from(self.car.unread).map_or(buf.len(), |unread| unread.min(buf.len()));
+        let len = self.car.reader.read(&mut buf[..max])?;
+        if len == 0 && max > 0 {
+            return Err(truncated());
+        }
+        self.car.unread -= len as u64;
+        Ok(len)
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "file ends in the middle of a section",
+    )
+}
+
+/// Read exactly `len` bytes, without trusting `len` for the allocation
+fn read_exact_vec(reader: &mut impl Read, len: u64) -> Result<Vec<u8>, CarError> {
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(truncated().into());
+    }
+    Ok(bytes)
+}
+
+/// Read the CIDv1 at the start of a section of `section_len` bytes:
+/// version, codec and multihash code varints, then the varint digest length
+/// and the digest. Returns the CID and its length in bytes.
+fn read_cid(reader: &mut impl Read, section_len: u64) -> Result<(Cid, u64), CarError> {
+    let invalid = || CarError::Invalid("section doesn't start with a CIDv1".to_string());
+    let mut cid_bytes = Vec::with_capacity(40);
+    let mut next = |cid_bytes: &mut Vec<u8>| -> Result<u64, CarError> {
+        read_varint_into(reader, cid_bytes)?.ok_or_else(|| truncated().into())
+    };
+    let version = next(&mut cid_bytes)?;
+    if version != 1 {
+        return Err(CarError::UnsupportedCid(format!("CID version {}", version)));
+    }
+    next(&mut cid_bytes)?;
+    next(&mut cid_bytes)?;
+    let digest_len = next(&mut cid_bytes)?;
+    if digest_len > MAX_DIGEST_SIZE {
+        return Err(CarError::UnsupportedCid(describe_cid(&cid_bytes)));
+    }
+    let cid_len = cid_bytes.len() as u64 + digest_len;
+    if cid_len > section_len {
+        return Err(invalid());
+    }
+    cid_bytes.extend(read_exact_vec(reader, digest_len)?);
+    let cid = Cid::parse_bytes(cid_bytes.clone())
+        .map_err(|_| CarError::UnsupportedCid(describe_cid(&cid_bytes)))?;
+    Ok((cid, cid_len))
+}
+
+/// A short description of a CID we can't represent, for error messages
+fn describe_cid(cid_bytes: &[u8]) -> String {
+    match decode_varint(cid_bytes.get(1..).unwrap_or_default()) {
+        Some((codec, _)) => format!("codec 0x{:x}", codec),
+        None => "unknown codec".to_string(),
+    }
+}
+
+fn parse_header(bytes: &[u8]) -> Result<Vec<Cid>, CarError> {
+    let header = drisl::decode(bytes).map_err(|err| match err {
+        DrislError::UnsupportedCid(cid_bytes) => CarError::UnsupportedCid(describe_cid(&cid_bytes)),
+        err => CarError::Invalid(format!("header: {}", err)),
+    })?;
+    let Value::Map(mut entries) = header else {
+        return Err(CarError::Invalid("header is not a map".to_string()));
+    };
+    let roots = match entries.remove("roots") {
+        Some(Value::Array(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                Value::Link(cid) => Ok(cid),
+                _ => Err(CarError::Invalid("root is not a CID".to_string())),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => Vec::new(),
+    };
+    match entries.remove("version") {
+        Some(Value::Integer(1)) => Ok(roots),
+        Some(Value::Integer(version)) => Err(CarError::Invalid(format!(
+            "unsupported CAR version {}",
+            version
+        ))),
+        _ => Err(CarError::Invalid("header has no version".to_string())),
+    }
+}
+
+/// Write an unsigned LEB128 varint, as used by multiformats
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Decode a varint at the start of `bytes`, returning its value and length
+fn decode_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value: u64 = 0;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Read a varint. None if the reader is at its end.
+fn read_varint(reader: &mut impl Read) -> Result<Option<u64>, CarError> {
+    read_varint_into(reader, &mut Vec::with_capacity(10))
+}
+
+/// Read a varint, appending its bytes to `bytes`. None if the reader is at
+/// its end.
+fn read_varint_into(reader: &mut impl Read, bytes: &mut Vec<u8>) -> Result<Option<u64>, CarError> {
+    let start = bytes.len();
+    let mut byte = [0u8];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            if bytes.len() == start {
+                return Ok(None);
+            }
+            return Err(CarError::Invalid("file ends in a varint".to_string()));
+        }
+        bytes.push(byte[0]);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        if bytes.len() - start == 10 {
+            return Err(CarError::Invalid("varint too long".to_string()));
+        }
+    }
+    Ok(decode_varint(&bytes[start..]).map(|(value, _)| value))
+}
+
+#[derive(Debug, Error)]
+pub enum CarError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid CAR file: {0}")]
+    Invalid(String),
+    #[error("Unsupported CID ({0}). Only raw and DRISL sha-256 CIDs can be stored.")]
+    UnsupportedCid(String),
+    #[error("Block doesn't match its CID. Expected: {expected} Got: {actual}")]
+    IntegrityError { expected: Cid, actual: Cid },
+    #[error("{0} is not in the store")]
+    Missing(Cid),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::test_store;
+
+    #[test]
+    fn test_export_import_roundtrip() {
+        let from = test_store("car", "from");
+        let hello = Cid::of(b"hello world");
+        let empty = Cid::of(b"");
+        let record_bytes = drisl::to_vec(&vec![hello, empty]).unwrap();
+        let record = drisl::cid_of(&record_bytes);
+        fs::write(from.path(&hello), "hello world").unwrap();
+        fs::write(from.path(&empty), "").unwrap();
+        fs::write(from.path(&record), &record_bytes).unwrap();
+
+        let mut car = Vec::new();
+        let written = export(&from, &[hello, empty, record], &mut car).unwrap();
+        assert_eq!(written, car.len() as u64);
+
+        let to = test_store("car", "to");
+        fs::write(to.path(&empty), "").unwrap();
+        let summary = import(&to, car.as_slice()).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                roots: vec![hello, empty, record],
+                added: 2,
+                already_present: 1,
+            }
+        );
+        assert_eq!(fs::read(to.path(&hello)).unwrap(), b"hello world");
+        assert_eq!(fs::read(to.path(&record)).unwrap(), record_bytes);
+
+        assert!(matches!(
+            export(&from, &[Cid::of(b"nope")], &mut Vec::new()),
+            Err(CarError::Missing(_))
+        ));
+
+        // A blob that rotted on disk isn't exported
+        fs::write(from.path(&hello), "hello wordl").unwrap();
+        assert!(matches!(
+            export(&from, &[hello], &mut Vec::new()),
+            Err(CarError::IntegrityError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_header() {
+        let cid = Cid::of(b"hello world");
+        let header = header(&[cid]);
+        let mut expected = vec![0x3a, 0xa2, 0x65];
+        expected.extend_from_slice(b"roots");
+        expected.extend_from_slice(&[0x81, 0xd8, 0x2a, 0x58, 0x25, 0x00]);
+        expected.extend_from_slice(&cid.to_bytes());
+        expected.push(0x67);
+        expected.extend_from_slice(b"version");
+        expected.push(0x01);
+        assert_eq!(header, expected);
+
+        assert_eq!(parse_header(&header[1..]).unwrap(), vec![cid]);
+    }
+
+    #[test]
+    fn test_rejects_bad_blocks() {
+        let cid = Cid::of(b"hello world");
+        let mut car = header(&[cid]);
+        car.extend_from_slice(&block_prefix(&cid, 11));
+        car.extend_from_slice(b"hello wordl");
+        let mut reader = CarReader::new(car.as_slice()).unwrap();
+        let block = reader.next_block().unwrap().unwrap();
+        assert_eq!(block.cid(), cid);
+        assert_eq!(block.size(), 11);
+        assert!(matches!(
+            block.verify(),
+            Err(CarError::IntegrityError { .. })
+        ));
+        let store = test_store("car", "bad");
+        assert!(matches!(
+            import(&store, car.as_slice()),
+            Err(CarError::IntegrityError { .. })
+        ));
+        assert_eq!(fs::read_dir(store.dir()).unwrap().count(), 0);
+
+        // A dag-pb block
+        let mut car = header(&[]);
+        let mut section = vec![0x01, 0x70, 0x12, 0x20];
+        section.extend_from_slice(&[0u8; 32]);
+        section.extend_from_slice(b"data");
+        write_varint(&mut car, section.len() as u64);
+        car.extend_from_slice(&section);
+        let mut reader = CarReader::new(car.as_slice()).unwrap();
+        assert!(matches!(
+            reader.next_block(),
+            Err(CarError::UnsupportedCid(codec)) if codec == "codec 0x70"
+        ));
+
+        // Truncated
+        let mut car = header(&[cid]);
+        car.extend_from_slice(&block_prefix(&cid, 11));
+        car.extend_from_slice(b"hello");
+        let mut reader = CarReader::new(car.as_slice()).unwrap();
+        let mut block = reader.next_block().unwrap().unwrap();
+        let err = io::copy(&mut block, &mut io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 300, 16384, u64::MAX] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            assert_eq!(decode_varint(&bytes), Some((value, bytes.len())));
+            assert_eq!(read_varint(&mut bytes.as_slice()).unwrap(), Some(value));
+        }
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 300);
+        assert_eq!(bytes, vec![0xac, 0x02]);
+    }
+}
@@ -0,0 +1,547 @@
+use crate::cid::{Cid, Codec};
+use crate::drisl::{self, DrislError};
+use crate::file::{Existing, write_atomic};
+use crate::health::HealthCache;
+use crate::request::{Client, FetchConfig, RequestError, race_get_and_check_cid};
+use crate::store::Store;
+use crate::url::Url;
+use fastcdc::v2020::StreamCDC;
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use thiserror::Error;
+
+/// Chunk size used unless told otherwise
+pub const DEFAULT_CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// Smallest chunk size allowed. Content-defined chunks vary from a quarter
+/// to four times the chunk size, within the bounds FastCDC supports.
+pub const MIN_CHUNK_SIZE: u32 = 1024;
+
+/// Largest chunk size allowed. Chunks are held in memory while they are
+/// added and fetched.
+pub const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Most links in one record. Bigger files get a tree of records, so each
+/// record stays well under `drisl::MAX_RECORD_SIZE`.
+const MAX_LINKS: usize = 4096;
+
+/// Largest raw chunk chunking makes: content-defined chunks can be up to
+/// four times the chunk size
+const MAX_RAW_CHUNK_SIZE: u64 = 4 * MAX_CHUNK_SIZE as u64;
+
+/// Most chunks a file of `size` bytes can have. Every chunk but the last is
+/// at least a quarter of the smallest chunk size.
+fn max_chunks(size: u64) -> u64 {
+    size / (MIN_CHUNK_SIZE as u64 / 4) + 1
+}
+
+/// Levels of records allowed above the chunks. Eight levels of 4096 links
+/// are far more than any file needs.
+const MAX_DEPTH: usize = 8;
+
+/// A file split into chunks, stored as a DRISL record. The CID of the
+/// record names the whole file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkedFile {
+    /// Size of the whole file in bytes
+    pub size: u64,
+    /// The content of the file, in order
+    pub chunks: Vec<Chunk>,
+}
+
+/// A piece of a chunked file: a raw blob, or for big files, the DRISL
+/// record of another `ChunkedFile` covering a run of chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub cid: Cid,
+    pub size: u64,
+}
+
+impl ChunkedFile {
+    fn from_chunks(chunks: Vec<Chunk>) -> Self {
+        Self {
+            size: chunks.iter().map(|chunk| chunk.size).sum(),
+            chunks,
+        }
+    }
+
+    /// Check that the sizes of the chunks add up to the size of the file,
+    /// and that there are no more chunks, nor bigger ones, than chunking
+    /// could have made
+    fn check(&self) -> Result<(), DrislError> {
+        if self.chunks.len() as u64 > max_chunks(self.size) {
+            return Err(DrislError::Custom(format!(
+                "A {} byte file can't have {} chunks",
+                self.size,
+                self.chunks.len()
+            )));
+        }
+        for chunk in &self.chunks {
+            let too_big = chunk.cid.codec() == Codec::Raw && chunk.size > MAX_RAW_CHUNK_SIZE;
+            if chunk.size == 0 || too_big {
+                return Err(DrislError::Custom(format!(
+                    "Chunk {} can't be {} bytes",
+                    chunk.cid, chunk.size
+                )));
+            }
+        }
+        let total = self
+            .chunks
+            .iter()
+            .try_fold(0u64, |total, chunk| total.checked_add(chunk.size));
+        if total != Some(self.size) {
+            return Err(DrislError::Custom(format!(
+                "Chunks of a {} byte file add up to {:?} bytes",
+                self.size, total
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How to split a file into chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunker {
+    /// Chunks of the same size, except the last one
+    Fixed(u32),
+    /// Content-defined chunks (FastCDC) of this size on average. An edit
+    /// only changes the chunks around it, so versions of a file share most
+    /// of their chunks.
+    ContentDefined(u32),
+}
+
+impl Chunker {
+    pub fn chunk_size(self) -> u32 {
+        match self {
+            Chunker::Fixed(size) | Chunker::ContentDefined(size) => size,
+        }
+    }
+
+    fn split<'a>(
+        self,
+        reader: impl Read + 'a,
+    ) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a> {
+        match self {
+            Chunker::Fixed(size) => Box::new(FixedChunks { reader, size }),
+            Chunker::ContentDefined(size) => Box::new(
+                StreamCDC::new(reader, size / 4, size, size * 4)
+                    .map(|chunk| chunk.map(|chunk| chunk.data).map_err(io::Error::from)),
+            ),
+        }
+    }
+}
+
+struct FixedChunks<R> {
+    reader: R,
+    size: u32,
+}
+
+impl<R: Read> Iterator for FixedChunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = Vec::with_capacity(self.size as usize);
+        match (&mut self.reader)
+            .take(self.size as u64)
+            .read_to_end(&mut chunk)
+        {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(chunk)),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// Split content into chunks, and add each chunk to a store as a raw blob,
+/// along with the records listing them. Chunks the store already has are
+/// not written again.
+///
+/// Returns the CID of the root record, which names the whole file.
+pub fn add(store: &Store, reader: impl Read, chunker: Chunker) -> Result<Cid, ChunkError> {
+    let chunk_size = chunker.chunk_size();
+    if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+        return Err(ChunkError::InvalidChunkSize(chunk_size));
+    }
+    fs::create_dir_all(store.dir())?;
+
+    let mut chunks = Vec::new();
+    for data in chunker.split(reader) {
+        let data = data?;
+        let cid = Cid::of(&data);
+        put(store, &cid, &data)?;
+        chunks.push(Chunk {
+            cid,
+            size: data.len() as u64,
+        });
+    }
+
+    while chunks.len() > MAX_LINKS {
+        chunks = chunks
+            .chunks(MAX_LINKS)
+            .map(|run| put_record(store, &ChunkedFile::from_chunks(run.to_vec())))
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(put_record(store, &ChunkedFile::from_chunks(chunks))?.cid)
+}
+
+fn put_record(store: &Store, file: &ChunkedFile) -> Result<Chunk, ChunkError> {
+    let bytes = drisl::to_vec(file)?;
+    let cid = drisl::cid_of(&bytes);
+    put(store, &cid, &bytes)?;
+    Ok(Chunk {
+        cid,
+        size: file.size,
+    })
+}
+
+fn put(store: &Store, cid: &Cid, bytes: &[u8]) -> io::Result<()> {
+    if !store.contains(cid) {
+        write_atomic(&store.path(cid), bytes)?;
+    }
+    Ok(())
+}
+
+/// The raw chunks of a file, in order. The records of big files are
+/// fetched from the sources `urls_for` gives for each CID, and checked
+/// like any other content.
+pub async fn resolve<F>(
+    client: &Client,
+    file: ChunkedFile,
+    urls_for: F,
+    config: &FetchConfig,
+    health: &mut HealthCache,
+) -> Result<Vec<Chunk>, RequestError>
+where
+    F: Fn(&Cid) -> Vec<Url>,
+{
+    file.check()?;
+    let max_chunks = max_chunks(file.size);
+    // Records linked more than once, like those of repeated content, are
+    // only fetched once
+    let mut records: HashMap<Cid, ChunkedFile> = HashMap::new();
+    let mut chunks = file.chunks;
+    for _ in 0..MAX_DEPTH {
+        if chunks.iter().all(|chunk| chunk.cid.codec() == Codec::Raw) {
+            return Ok(chunks);
+        }
+        let mut expanded = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            if chunk.cid.codec() == Codec::Raw {
+                expanded.push(chunk);
+                continue;
+            }
+            let record = match records.entry(chunk.cid) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let urls = health.rank(urls_for(&chunk.cid));
+                    let (_, bytes) =
+                        race_get_and_check_cid(client, &urls, &chunk.cid, config, health).await?;
+                    let record: ChunkedFile = drisl::from_slice(&bytes)?;
+                    record.check()?;
+                    entry.insert(record)
+                }
+            };
+            if record.size != chunk.size {
+                return Err(DrislError::Custom(format!(
+                    "{} covers {} bytes, but is linked as {} bytes",
+                    chunk.cid, record.size, chunk.size
+                ))
+                .into());
+            }
+            if (expanded.len() + record.chunks.len()) as u64 > max_chunks {
+                return Err(DrislError::Custom(format!(
+                    "A {} byte file can't have more than {} chunks",
+                    file.size, max_chunks
+                ))
+                .into());
+            }
+            expanded.extend_from_slice(&record.chunks);
+        }
+        chunks = expanded;
+    }
+    Err(DrislError::Custom("Chunked file is nested too deep".to_string()).into())
+}
+
+/// Fetch raw chunks, `parallel` at a time, and write them to `out` in
+/// order. Each chunk is raced across the sources `urls_for` gives for it,
+/// and checked against its own CID, so a bad source only costs a chunk.
+///
+/// Returns the number of bytes written.
+pub async fn fetch<F>(
+    client: &Client,
+    chunks: &[Chunk],
+    urls_for: F,
+    config: &FetchConfig,
+    health: &mut HealthCache,
+    parallel: usize,
+    out: &mut impl Write,
+) -> Result<u64, RequestError>
+where
+    F: Fn(&Cid) -> Vec<Url>,
+{
+    // Fetches in parallel can't share the health cache, so each records
+    // into its own, merged back as it finishes
+    let ranking = health.clone();
+    let mut fetches = stream::iter(chunks)
+        .map(|chunk| {
+            let urls = ranking.rank(urls_for(&chunk.cid));
+            async move {
+                let mut chunk_health = HealthCache::default();
+                let result =
+                    race_get_and_check_cid(client, &urls, &chunk.cid, config, &mut chunk_health)
+                        .await;
+                (chunk, result, chunk_health)
+            }
+        })
+        .buffered(parallel.max(1));
+
+    let mut written = 0;
+    while let Some((chunk, result, chunk_health)) = fetches.next().await {
+        health.merge(&chunk_health);
+        let (_, data) = result?;
+        if data.len() as u64 != chunk.size {
+            return Err(RequestError::IntegrityError(format!(
+                "Chunk {} is {} bytes. Expected: {}",
+                chunk.cid,
+                data.len(),
+                chunk.size
+            )));
+        }
+        out.write_all(&data)?;
+        written += chunk.size;
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+/// Check whether a path already holds a chunked file, chunk by chunk
+pub fn check_existing(path: &Path, chunks: &[Chunk]) -> io::Result<Existing> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Existing::Missing),
+        Err(err) => return Err(err),
+    };
+    let metadata = file.metadata()?;
+    let size: u64 = chunks.iter().map(|chunk| chunk.size).sum();
+    if !metadata.is_file() || metadata.len() != size {
+        return Ok(Existing::Differs);
+    }
+    for chunk in chunks {
+        if Cid::read(&mut (&mut file).take(chunk.size))? != chunk.cid {
+            return Ok(Existing::Differs);
+        }
+    }
+    Ok(Existing::Matches)
+}
+
+#[derive(Debug, Error)]
+pub enum ChunkError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Unable to encode record: {0}")]
+    Record(#[from] DrislError),
+    #[error("Chunk size must be between {MIN_CHUNK_SIZE} and {MAX_CHUNK_SIZE} bytes, not {0}")]
+    InvalidChunkSize(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::test_store;
+    use axum::Router;
+    use axum::extract::Path;
+    use axum::http::StatusCode;
+
+    /// Bytes that don't repeat, so content-defined chunking finds boundaries
+    fn content(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn read_record(store: &Store, cid: &Cid) -> ChunkedFile {
+        drisl::from_slice(&fs::read(store.path(cid)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_add_fixed() {
+        let store = test_store("chunk", "fixed");
+        let data = content(2500);
+        let root = add(&store, data.as_slice(), Chunker::Fixed(1024)).unwrap();
+        assert_eq!(root.codec(), Codec::Drisl);
+
+        let file = read_record(&store, &root);
+        assert_eq!(file.size, 2500);
+        let sizes: Vec<u64> = file.chunks.iter().map(|chunk| chunk.size).collect();
+        assert_eq!(sizes, vec![1024, 1024, 452]);
+        assert_eq!(
+            fs::read(store.path(&file.chunks[2].cid)).unwrap(),
+            &data[2048..]
+        );
+
+        assert!(matches!(
+            add(&store, data.as_slice(), Chunker::Fixed(10)),
+            Err(ChunkError::InvalidChunkSize(10))
+        ));
+    }
+
+    #[test]
+    fn test_content_defined_chunks_survive_edits() {
+        let store = test_store("chunk", "cdc");
+        let data = content(64 * 1024);
+        let mut edited = b"inserted at the start".to_vec();
+        edited.extend_from_slice(&data);
+
+        let chunker = Chunker::ContentDefined(4096);
+        let before = read_record(&store, &add(&store, data.as_slice(), chunker).unwrap());
+        let after = read_record(&store, &add(&store, edited.as_slice(), chunker).unwrap());
+        assert_eq!(after.size, edited.len() as u64);
+        let shared = after
+            .chunks
+            .iter()
+            .filter(|chunk| before.chunks.contains(chunk))
+            .count();
+        assert!(shared >= before.chunks.len() - 2, "only {} shared", shared);
+    }
+
+    #[test]
+    fn test_big_files_get_a_tree_of_records() {
+        let store = test_store("chunk", "tree");
+        let data = content(MAX_LINKS * 1024 + 1);
+        let root = add(&store, data.as_slice(), Chunker::Fixed(1024)).unwrap();
+        let file = read_record(&store, &root);
+        assert_eq!(file.size, data.len() as u64);
+        assert_eq!(file.chunks.len(), 2);
+        assert!(
+            file.chunks
+                .iter()
+                .all(|chunk| chunk.cid.codec() == Codec::Drisl)
+        );
+        assert_eq!(read_record(&store, &file.chunks[1].cid).chunks.len(), 1);
+    }
+
+    #[test]
+    fn test_check() {
+        let chunk = |size| Chunk {
+            cid: Cid::of(b"chunk"),
+            size,
+        };
+        let file =
+            |sizes: &[u64]| ChunkedFile::from_chunks(sizes.iter().copied().map(chunk).collect());
+        assert!(file(&[1024, 300, 1]).check().is_ok());
+        assert!(file(&[]).check().is_ok());
+
+        let mut wrong_size = file(&[1024]);
+        wrong_size.size = 1000;
+        assert!(wrong_size.check().is_err());
+
+        assert!(file(&[1024, 0]).check().is_err());
+        assert!(file(&[MAX_RAW_CHUNK_SIZE + 1]).check().is_err());
+        assert!(file(&[1; 3]).check().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_and_fetch() {
+        let store = test_store("chunk", "fetch");
+        let data = content(MAX_LINKS * 1024 + 3000);
+        let root = add(&store, data.as_slice(), Chunker::Fixed(1024)).unwrap();
+
+        let served = store.clone();
+        let app = Router::new().route(
+            "/{cid}",
+            axum::routing::get(move |Path(cid): Path<String>| async move {
+                let cid = Cid::parse(&cid).unwrap();
+                fs::read(served.path(&cid)).map_err(|_| StatusCode::NOT_FOUND)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = Client::new();
+        let config = FetchConfig::default();
+        let mut health = HealthCache::default();
+        let urls_for = |cid: &Cid| vec![base.join(&cid.to_string()).unwrap()];
+
+        let chunks = resolve(
+            &client,
+            read_record(&store, &root),
+            urls_for,
+            &config,
+            &mut health,
+        )
+        .await
+        .unwrap();
+        assert_eq!(chunks.len(), MAX_LINKS + 3);
+
+        let mut out = Vec::new();
+        let written = fetch(
+            &client,
+            &chunks,
+            urls_for,
+            &config,
+            &mut health,
+            4,
+            &mut out,
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(out, data);
+
+        let path = store.dir().join("out");
+        assert_eq!(check_existing(&path, &chunks).unwrap(), Existing::Missing);
+        fs::write(&path, &data).unwrap();
+        assert_eq!(check_existing(&path, &chunks).unwrap(), Existing::Matches);
+        let mut edited = data.clone();
+        edited[5000] ^= 1;
+        fs::write(&path, &edited).unwrap();
+        assert_eq!(check_existing(&path, &chunks).unwrap(), Existing::Differs);
+        assert_eq!(
+            health.get(&base).unwrap().successes,
+            chunks.len() as u64 + 2
+        );
+
+        // A record linked twice is only fetched once
+        let half = content(MAX_LINKS * 1024);
+        let repeated = [half.as_slice(), half.as_slice()].concat();
+        let root = add(&store, repeated.as_slice(), Chunker::Fixed(1024)).unwrap();
+        let successes = health.get(&base).unwrap().successes;
+        let repeated_chunks = resolve(
+            &client,
+            read_record(&store, &root),
+            urls_for,
+            &config,
+            &mut health,
+        )
+        .await
+        .unwrap();
+        assert_eq!(repeated_chunks.len(), 2 * MAX_LINKS);
+        assert_eq!(health.get(&base).unwrap().successes, successes + 1);
+
+        // A chunk that went missing
+        fs::remove_file(store.path(&chunks[5].cid)).unwrap();
+        let result = fetch(
+            &client,
+            &chunks,
+            urls_for,
+            &config,
+            &mut health,
+            4,
+            &mut Vec::new(),
+        )
+        .await;
+        assert!(matches!(result, Err(RequestError::SourcesExhausted(_))));
+    }
+}
@@ -1,4 +1,6 @@
+use crate::drisl::LINK;
 use data_encoding;
+use serde::de::Visitor;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::io::{self, Read};
@@ -6,13 +8,44 @@ use std::result;
 
 const CID_VERSION: u8 = 0x01;
 const MULTICODEC_RAW: u8 = 0x55;
+const MULTICODEC_DRISL: u8 = 0x71;
 const MULTIHASH_SHA256: u8 = 0x12;
 
-/// Represents a CIDv1 with SHA-256 hash using raw codec (0x55)
-/// The struct itself holds only the SHA-256 hash bytes.
-/// To get a CIDV1 bytes representation, use the `to_cid_bytes` method.
+/// The kind of data a CID points to. See https://dasl.ing/cid.html
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-pub struct Cid([u8; 32]);
+pub enum Codec {
+    /// Opaque bytes (0x55)
+    Raw,
+    /// A DRISL (deterministic dag-cbor) record, see `crate::drisl` (0x71)
+    Drisl,
+}
+
+impl Codec {
+    /// The multicodec code of the codec
+    pub fn code(self) -> u8 {
+        match self {
+            Codec::Raw => MULTICODEC_RAW,
+            Codec::Drisl => MULTICODEC_DRISL,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            MULTICODEC_RAW => Some(Codec::Raw),
+            MULTICODEC_DRISL => Some(Codec::Drisl),
+            _ => None,
+        }
+    }
+}
+
+/// Represents a CIDv1 with SHA-256 hash using the raw (0x55) or DRISL (0x71)
+/// codec. The struct itself holds only the codec and the SHA-256 hash bytes.
+/// To get a CIDV1 bytes representation, use the `to_bytes` method.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Cid {
+    codec: Codec,
+    hash: [u8; 32],
+}
 
 impl Cid {
     /// Parse a CIDv1 from bytes representing a CIDv1
@@ -26,11 +59,10 @@ impl Cid {
         let hash_algo = cid_bytes[2];
         let hash_len = cid_bytes[3];
 
-        if version != CID_VERSION
-            || codec != MULTICODEC_RAW
-            || hash_algo != MULTIHASH_SHA256
-            || hash_len != 32
-        {
+        let Some(codec) = Codec::from_code(codec) else {
+            return Err(CidError::new("Invalid CID format"));
+        };
+        if version != CID_VERSION || hash_algo != MULTIHASH_SHA256 || hash_len != 32 {
             return Err(CidError::new("Invalid CID format"));
         }
 
@@ -38,7 +70,7 @@ impl Cid {
         let mut hash = [0u8; 32];
         // Copy bytes 4 to 36 to fill the hash array
         hash.copy_from_slice(&cid_bytes[4..36]);
-        Ok(Self(hash))
+        Ok(Self { codec, hash })
     }
 
     /// Parse a CIDv1 from a string representation.
@@ -55,17 +87,20 @@ impl Cid {
         Self::parse_bytes(cid_bytes)
     }
 
-    /// Create a CIDv1 by hashing raw bytes
+    /// Create a raw CIDv1 by hashing raw bytes
     pub fn of(bytes: impl AsRef<[u8]>) -> Self {
         let sha256_hash = Sha256::digest(bytes.as_ref());
         let sha256_hash_array: [u8; 32] = sha256_hash
             .as_slice()
             .try_into()
             .expect("SHA256 hash should be 32 bytes");
-        Self(sha256_hash_array)
+        Self {
+            codec: Codec::Raw,
+            hash: sha256_hash_array,
+        }
     }
 
-    /// Create a CIDv1 by streaming-reading and streaming-hashing bytes from a reader
+    /// Create a raw CIDv1 by streaming-reading and streaming-hashing bytes from a reader
     pub fn read<R: Read>(reader: &mut R) -> Result<Self, io::Error> {
         let mut hasher = Sha256::new();
         // Streaming hash the bytes from the reader.
@@ -76,7 +111,23 @@ impl Cid {
             .as_slice()
             .try_into()
             .expect("SHA256 hash should be 32 bytes");
-        Ok(Self(hash_array))
+        Ok(Self {
+            codec: Codec::Raw,
+            hash: hash_array,
+        })
+    }
+
+    /// The codec of the data this CID points to
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// The same hash with another codec.
+    ///
+    /// Hashing bytes gives a raw CID, so to verify bytes against any CID,
+    /// compare `Cid::of(bytes).with_codec(cid.codec())` with it.
+    pub fn with_codec(self, codec: Codec) -> Self {
+        Self { codec, ..self }
     }
 
     /// Get the byte representation of a valid CIDv1
@@ -89,8 +140,8 @@ impl Cid {
         // version 1
         cid_bytes.push(CID_VERSION);
 
-        // raw codec (0x55)
-        cid_bytes.push(MULTICODEC_RAW);
+        // raw (0x55) or DRISL (0x71) codec
+        cid_bytes.push(self.codec.code());
 
         // sha2-256 hash algorithm (0x12)
         cid_bytes.push(MULTIHASH_SHA256);
@@ -99,28 +150,38 @@ impl Cid {
         cid_bytes.push(32);
 
         // append the hash itself
-        cid_bytes.extend_from_slice(&self.0.as_ref());
+        cid_bytes.extend_from_slice(self.hash.as_ref());
 
         // Return the CID bytes
         cid_bytes
     }
+}
 
-    /// Get the string representation of a valid CIDv1
-    /// See https://dasl.ing/cid.html
-    pub fn to_string(&self) -> String {
-        let cid_bytes = self.to_bytes();
-
-        // Convert to base32
-        let mut encoded = String::with_capacity(cid_bytes.len() * 2);
+/// Incrementally hash bytes into a CID, for data that arrives in chunks
+#[derive(Debug, Clone, Default)]
+pub struct CidHasher(Sha256);
 
-        // Add the "b" multibase prefix for base32
-        encoded.push('b');
+impl CidHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // Encode the CID bytes using base32 lower
-        encoded.push_str(&data_encoding::BASE32_NOPAD.encode(&cid_bytes));
+    /// Hash the next chunk of bytes
+    pub fn update(&mut self, bytes: impl AsRef<[u8]>) {
+        self.0.update(bytes.as_ref());
+    }
 
-        // Return the encoded string (lowercase)
-        encoded.to_lowercase()
+    /// Get the raw CID of all the bytes hashed so far
+    pub fn finalize(self) -> Cid {
+        let digest = self.0.finalize();
+        let hash_array: [u8; 32] = digest
+            .as_slice()
+            .try_into()
+            .expect("SHA256 hash should be 32 bytes");
+        Cid {
+            codec: Codec::Raw,
+            hash: hash_array,
+        }
     }
 }
 
@@ -129,7 +190,24 @@ impl Serialize for Cid {
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(&self.to_string())
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            // DRISL recognizes the name, and encodes the CID as a link
+            serializer.serialize_newtype_struct(LINK, &LinkBytes(&self.to_bytes()))
+        }
+    }
+}
+
+/// CID bytes, serialized as bytes rather than as a sequence
+struct LinkBytes<'a>(&'a [u8]);
+
+impl Serialize for LinkBytes<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(self.0)
     }
 }
 
@@ -138,14 +216,46 @@ impl<'de> Deserialize<'de> for Cid {
     where
         D: serde::Deserializer<'de>,
     {
-        let s = String::deserialize(deserializer)?;
-        Cid::parse(&s).map_err(serde::de::Error::custom)
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            Cid::parse(&s).map_err(serde::de::Error::custom)
+        } else {
+            deserializer.deserialize_newtype_struct(LINK, LinkVisitor)
+        }
     }
 }
 
+struct LinkVisitor;
+
+impl<'de> Visitor<'de> for LinkVisitor {
+    type Value = Cid;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a CID link")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Cid, E> {
+        Cid::parse_bytes(bytes.to_vec()).map_err(E::custom)
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> Result<Cid, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(self)
+    }
+}
+
+/// Get the string representation of a valid CIDv1
+/// See https://dasl.ing/cid.html
 impl std::fmt::Display for Cid {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        let cid_bytes = self.to_bytes();
+
+        // Encode the CID bytes using base32 lower, with the "b" multibase
+        // prefix for base32
+        let encoded = data_encoding::BASE32_NOPAD.encode(&cid_bytes);
+        write!(f, "b{}", encoded.to_lowercase())
     }
 }
 
@@ -208,7 +318,7 @@ mod tests {
         let cid2 = Cid::of("data2".as_bytes());
 
         // Check that different inputs create different CIDs
-        assert_ne!(cid1.0, cid2.0);
+        assert_ne!(cid1.hash, cid2.hash);
         assert_ne!(cid1.to_string(), cid2.to_string());
     }
 
@@ -218,7 +328,7 @@ mod tests {
         let cid2 = Cid::of("same data".as_bytes());
 
         // Check that identical inputs create the same CID
-        assert_eq!(cid1.0, cid2.0);
+        assert_eq!(cid1.hash, cid2.hash);
         assert_eq!(cid1.to_string(), cid2.to_string());
     }
 
@@ -236,4 +346,27 @@ mod tests {
         assert_eq!(cid[3], 32);
         assert_eq!(cid.len(), 36);
     }
+
+    #[test]
+    fn test_drisl_cid() {
+        let cid = Cid::of(b"\xa0").with_codec(Codec::Drisl);
+        let bytes = cid.to_bytes();
+        assert_eq!(bytes[1], MULTICODEC_DRISL);
+        assert!(cid.to_string().starts_with("bafyrei"));
+        assert_eq!(Cid::parse(&cid.to_string()).unwrap(), cid);
+        assert_ne!(cid, Cid::of(b"\xa0"));
+        assert_eq!(cid.codec(), Codec::Drisl);
+
+        let mut unknown = bytes.clone();
+        unknown[1] = 0x70;
+        assert!(Cid::parse_bytes(unknown).is_err());
+    }
+
+    #[test]
+    fn test_cid_hasher_matches_cid_of() {
+        let mut hasher = CidHasher::new();
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        assert_eq!(hasher.finalize(), Cid::of(b"hello world"));
+    }
 }
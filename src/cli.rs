@@ -1,5 +1,7 @@
-pub use clap::Parser;
-use clap::Subcommand;
+use crate::config::CONFIG_ENV;
+use crate::util::{parse_byte_rate, parse_duration, parse_rate, parse_size};
+use clap::{Args, Subcommand};
+pub use clap::{CommandFactory, FromArgMatches, Parser};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -9,6 +11,15 @@ use std::path::PathBuf;
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Config file to use instead of /etc/magnetize.toml and ~/.config/magnetize/config.toml",
+        value_name = "FILE",
+        env = CONFIG_ENV
+    )]
+    pub config: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug, Serialize, Deserialize)]
@@ -18,6 +29,104 @@ pub enum Commands {
         #[arg(help = "URL to fetch")]
         #[arg(value_name = "URL")]
         url: String,
+
+        #[command(flatten)]
+        output: OutputArgs,
+
+        #[arg(
+            long,
+            help = "RASL server to also fetch from, after the link's own sources. Can be given more than once.",
+            value_name = "URL"
+        )]
+        mirror: Vec<String>,
+
+        #[arg(
+            long,
+            help = "IPFS trustless gateway to also fetch from, after the link's own sources. Can be given more than once.",
+            value_name = "URL"
+        )]
+        gateway: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Maximum number of sources to request from at the same time",
+            value_name = "N",
+            default_value_t = 3
+        )]
+        race: usize,
+
+        #[arg(
+            long,
+            help = "Maximum number of chunks of a chunked file to download at the same time",
+            value_name = "N",
+            default_value_t = 4
+        )]
+        parallel: usize,
+
+        #[arg(
+            long,
+            help = "Milliseconds to wait for a source before also trying the next one",
+            value_name = "MILLISECONDS",
+            default_value_t = 250
+        )]
+        stagger: u64,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a connection to a source",
+            value_name = "SECONDS",
+            default_value_t = 10
+        )]
+        connect_timeout: u64,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a source to send more of the content",
+            value_name = "SECONDS",
+            default_value_t = 30
+        )]
+        read_timeout: u64,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a source to finish sending the content [default: no limit]",
+            value_name = "SECONDS"
+        )]
+        timeout: Option<u64>,
+
+        #[arg(
+            long,
+            help = "Times to retry a source after a transient failure (timeouts, 5xx, 429)",
+            value_name = "N",
+            default_value_t = 2
+        )]
+        retries: u32,
+
+        #[arg(long, help = "Don't read from or write to the local content cache")]
+        no_cache: bool,
+
+        #[arg(
+            long,
+            help = "Maximum size of the local content cache (e.g. 512M, 1G)",
+            value_name = "SIZE",
+            default_value = "1G",
+            value_parser = parse_size
+        )]
+        cache_size: u64,
+
+        #[arg(
+            long,
+            help = "Limit downloads to this many bytes per second, across all sources (e.g. 10M)",
+            value_name = "RATE",
+            value_parser = parse_byte_rate
+        )]
+        limit_rate: Option<u64>,
+
+        #[arg(
+            long,
+            help = "Report progress as newline-delimited JSON events on stderr, instead of a progress bar"
+        )]
+        json_events: bool,
     },
 
     #[command(about = "Create a magnet link from one or more HTTP URLs")]
@@ -25,8 +134,39 @@ pub enum Commands {
         #[arg(
             long_help = "Create a magnet link from one or more HTTP URLs. Fetches the content, generates a CID, and returns the magnet link."
         )]
-        #[arg(value_name = "URL")]
+        #[arg(value_name = "URL", conflicts_with_all = ["from_torrent", "from_magnet"])]
         url: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Create a magnet link from a single-file .torrent file, using its web seeds",
+            value_name = "FILE",
+            conflicts_with = "from_magnet"
+        )]
+        from_torrent: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Create a magnet link from a BitTorrent v1 or v2 magnet link, using its web seeds",
+            value_name = "MAGNET_URL"
+        )]
+        from_magnet: Option<String>,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a connection to a URL",
+            value_name = "SECONDS",
+            default_value_t = 10
+        )]
+        connect_timeout: u64,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a URL to send more of the content",
+            value_name = "SECONDS",
+            default_value_t = 30
+        )]
+        read_timeout: u64,
     },
 
     #[command(about = "Add data to current directory. Creates a file using the CID as filename.")]
@@ -36,23 +176,607 @@ pub enum Commands {
             value_name = "FILE"
         )]
         file: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Refuse content larger than this (e.g. 100M)",
+            value_name = "SIZE",
+            value_parser = parse_size
+        )]
+        max_blob_size: Option<u64>,
+
+        #[arg(
+            long,
+            help = "Refuse content that would grow the content in the current directory beyond this size (e.g. 10G)",
+            value_name = "SIZE",
+            value_parser = parse_size
+        )]
+        max_store_size: Option<u64>,
+
+        #[arg(
+            long,
+            help = "Split the file into chunks stored as their own blobs, plus a record listing them. Prints the CID of the record.",
+            requires = "file"
+        )]
+        chunked: bool,
+
+        #[arg(
+            long,
+            help = "Average size of chunks (e.g. 256K, 1M)",
+            value_name = "SIZE",
+            default_value = "1M",
+            value_parser = parse_size,
+            requires = "chunked"
+        )]
+        chunk_size: u64,
+
+        #[arg(
+            long,
+            help = "Split at fixed offsets, instead of at boundaries picked from the content (FastCDC)",
+            requires = "chunked"
+        )]
+        fixed_size: bool,
     },
 
-    #[command(about = "Serve content addressed files over HTTP")]
-    Serve {
+    #[command(about = "Upload a file to one or more magnetize servers, and print a magnet link")]
+    Push {
+        #[arg(help = "File to upload", value_name = "FILE")]
+        file: PathBuf,
+
+        #[arg(
+            long,
+            help = "Server to upload to. Can be given more than once.",
+            value_name = "URL",
+            required = true
+        )]
+        to: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Bearer token for the servers",
+            value_name = "TOKEN",
+            env = "MAG_TOKEN",
+            hide_env_values = true
+        )]
+        token: Option<String>,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a connection to a server",
+            value_name = "SECONDS",
+            default_value_t = 10
+        )]
+        connect_timeout: u64,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for an upload to finish [default: no limit]",
+            value_name = "SECONDS"
+        )]
+        timeout: Option<u64>,
+
+        #[arg(
+            long,
+            help = "Limit uploads to this many bytes per second, across all servers (e.g. 10M)",
+            value_name = "RATE",
+            value_parser = parse_byte_rate
+        )]
+        limit_rate: Option<u64>,
+
+        #[arg(
+            long,
+            help = "Report progress as newline-delimited JSON events on stderr, instead of a progress bar"
+        )]
+        json_events: bool,
+    },
+
+    #[command(about = "Copy content from one magnetize server to another")]
+    Mirror {
+        #[arg(long, help = "Server to copy from", value_name = "URL")]
+        from: String,
+
+        #[arg(long, help = "Server to copy to", value_name = "URL")]
+        to: String,
+
+        #[arg(
+            help = "CIDs to copy",
+            value_name = "CID",
+            required_unless_present = "all"
+        )]
+        cids: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Copy everything the source server lists. The source must have listing enabled.",
+            conflicts_with = "cids"
+        )]
+        all: bool,
+
+        #[arg(
+            long,
+            help = "Save progress of --all to FILE. Resumes an interrupted mirror, and later runs only copy content added since the last complete one.",
+            value_name = "FILE",
+            requires = "all"
+        )]
+        state: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Bearer token for the destination server",
+            value_name = "TOKEN",
+            env = "MAG_TOKEN",
+            hide_env_values = true
+        )]
+        token: Option<String>,
+
+        #[arg(
+            long,
+            help = "Maximum number of blobs to copy at the same time",
+            value_name = "N",
+            default_value_t = 4
+        )]
+        concurrency: usize,
+
         #[arg(
-            help = "Directory to serve. Creates directory if it doesn't already exist.",
+            long,
+            help = "Times to retry a transfer after a transient failure (timeouts, 5xx, 429)",
+            value_name = "N",
+            default_value_t = 2
+        )]
+        retries: u32,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a connection to a server",
+            value_name = "SECONDS",
+            default_value_t = 10
+        )]
+        connect_timeout: u64,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a single download or upload to finish [default: no limit]",
+            value_name = "SECONDS"
+        )]
+        timeout: Option<u64>,
+
+        #[arg(
+            long,
+            help = "Limit downloads and uploads to this many bytes per second, across all transfers (e.g. 10M)",
+            value_name = "RATE",
+            value_parser = parse_byte_rate
+        )]
+        limit_rate: Option<u64>,
+
+        #[arg(
+            long,
+            help = "Report transfers as newline-delimited JSON events on stderr"
+        )]
+        json_events: bool,
+    },
+
+    #[command(about = "Check which CIDs a magnetize server has")]
+    Has {
+        #[arg(long, help = "Server to check", value_name = "URL")]
+        server: String,
+
+        #[arg(
+            help = "CIDs to check. If none are provided, reads one CID per line from stdin.",
+            value_name = "CID"
+        )]
+        cids: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Print the CIDs the server doesn't have, instead of the ones it has"
+        )]
+        missing: bool,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a connection to the server",
+            value_name = "SECONDS",
+            default_value_t = 10
+        )]
+        connect_timeout: u64,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for the server to answer",
+            value_name = "SECONDS",
+            default_value_t = 30
+        )]
+        read_timeout: u64,
+    },
+
+    #[command(about = "Remove unpinned content from a directory served by mag serve")]
+    #[command(group = clap::ArgGroup::new("limits").required(true).multiple(true))]
+    Gc {
+        #[arg(
+            help = "Directory to collect garbage in",
+            value_name = "DIRECTORY",
+            default_value = "public"
+        )]
+        dir: PathBuf,
+
+        #[arg(
+            long,
+            help = "Remove unpinned content added longer ago than this (e.g. 12h, 30d)",
+            value_name = "AGE",
+            value_parser = parse_duration,
+            group = "limits"
+        )]
+        older_than: Option<std::time::Duration>,
+
+        #[arg(
+            long,
+            help = "Remove the oldest unpinned content until the directory fits in this size (e.g. 500G)",
+            value_name = "SIZE",
+            value_parser = parse_size,
+            group = "limits"
+        )]
+        max_size: Option<u64>,
+
+        #[arg(long, help = "List what would be removed, without removing anything")]
+        dry_run: bool,
+    },
+
+    #[command(about = "Inspect the settings from config files")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    #[command(about = "Check the integrity of a directory served by mag serve")]
+    Fsck {
+        #[arg(
+            help = "Directory to check",
+            value_name = "DIRECTORY",
+            default_value = "public"
+        )]
+        dir: PathBuf,
+
+        #[arg(
+            long,
+            help = "Server to fetch corrupted content from. Can be given more than once.",
+            value_name = "URL"
+        )]
+        repair_from: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a connection to a server to repair from",
+            value_name = "SECONDS",
+            default_value_t = 10
+        )]
+        connect_timeout: u64,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a server to send more of the content",
+            value_name = "SECONDS",
+            default_value_t = 30
+        )]
+        read_timeout: u64,
+
+        #[arg(
+            long,
+            help = "Seconds to wait for a server to finish sending the content [default: no limit]",
+            value_name = "SECONDS"
+        )]
+        timeout: Option<u64>,
+
+        #[arg(long, help = "Report problems without fixing anything")]
+        dry_run: bool,
+    },
+
+    #[command(about = "Manage named pins, which protect content from mag gc")]
+    Pin {
+        #[arg(
+            long,
+            help = "Directory served by mag serve",
             value_name = "DIRECTORY",
             default_value = "public"
         )]
         dir: PathBuf,
 
+        #[command(subcommand)]
+        command: PinCommands,
+    },
+
+    #[command(about = "Export content to and import content from CAR files")]
+    Car {
+        #[arg(
+            long,
+            help = "Directory served by mag serve",
+            value_name = "DIRECTORY",
+            default_value = "public"
+        )]
+        dir: PathBuf,
+
+        #[command(subcommand)]
+        command: CarCommands,
+    },
+
+    #[command(about = "Manage the local cache of content fetched with get")]
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+
+    #[command(about = "Serve content addressed files over HTTP")]
+    Serve(Box<ServeArgs>),
+}
+
+/// Options of `mag serve`
+#[derive(Args, Debug, Serialize, Deserialize)]
+pub struct ServeArgs {
+    #[arg(
+        help = "Directory to serve. Creates directory if it doesn't already exist.",
+        value_name = "DIRECTORY",
+        default_value = "public"
+    )]
+    pub dir: PathBuf,
+
+    #[arg(
+        long,
+        help = "Address to listen on",
+        value_name = "ADDRESS",
+        default_value = "0.0.0.0:3000"
+    )]
+    pub addr: String,
+
+    #[arg(
+        long,
+        help = "Bearer token clients must present to upload content. Uploads are disabled without a token.",
+        value_name = "TOKEN",
+        env = "MAG_AUTH_TOKEN",
+        hide_env_values = true
+    )]
+    pub auth_token: Option<String>,
+
+    #[arg(
+        long,
+        help = "List all content as JSON at /api/v1/blobs and /.well-known/rasl/, e.g. for mag mirror --all"
+    )]
+    pub enable_listing: bool,
+
+    #[arg(
+        long,
+        help = "Hash content while serving it, and abort the transfer (and quarantine the content) if it doesn't match its CID"
+    )]
+    pub verify_on_read: bool,
+
+    #[arg(long, help = "Serve Prometheus metrics at /metrics")]
+    pub enable_metrics: bool,
+
+    #[arg(
+        long,
+        help = "On SIGTERM or SIGINT, how long to wait for requests in progress before closing their connections",
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        default_value = "30s"
+    )]
+    pub drain_timeout: std::time::Duration,
+
+    #[arg(
+        long,
+        help = "Serve HTTPS with the certificate chain in this PEM file. Reloaded when the file changes.",
+        value_name = "FILE",
+        requires = "tls_key"
+    )]
+    pub tls_cert: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Private key for --tls-cert, as a PEM file",
+        value_name = "FILE",
+        requires = "tls_cert"
+    )]
+    pub tls_key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Also listen for plain HTTP on this address, and redirect it to HTTPS (e.g. 0.0.0.0:80)",
+        value_name = "ADDRESS",
+        requires = "tls_cert"
+    )]
+    pub http_redirect_addr: Option<String>,
+
+    #[arg(
+        long,
+        help = "Let web apps on this origin fetch content cross-origin (e.g. https://app.example), or * for any origin. Can be given more than once.",
+        value_name = "ORIGIN"
+    )]
+    pub cors_origin: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Most reads (GET, HEAD and existence checks) per second from each client, or from everyone with the auth token",
+        value_name = "N",
+        value_parser = parse_rate
+    )]
+    pub read_requests_per_sec: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Most bytes per second served to each client (e.g. 10M). Downloads are slowed down to fit.",
+        value_name = "SIZE",
+        value_parser = parse_byte_rate
+    )]
+    pub read_bytes_per_sec: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Most writes (uploads, pins and deletes) per second from each client, or from everyone with the auth token",
+        value_name = "N",
+        value_parser = parse_rate
+    )]
+    pub write_requests_per_sec: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Most bytes per second uploaded by each client (e.g. 1M). Uploads are slowed down to fit.",
+        value_name = "SIZE",
+        value_parser = parse_byte_rate
+    )]
+    pub write_bytes_per_sec: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Most requests in progress at once, including downloads still being sent",
+        value_name = "N"
+    )]
+    pub max_concurrent_requests: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Reject uploads larger than this (e.g. 100M)",
+        value_name = "SIZE",
+        value_parser = parse_size
+    )]
+    pub max_blob_size: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Reject uploads that would grow the directory beyond this size (e.g. 500G)",
+        value_name = "SIZE",
+        value_parser = parse_size
+    )]
+    pub max_store_size: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Reject uploads that would take the content uploaded by one client beyond this size (e.g. 10G). Clients with the auth token count as one.",
+        value_name = "SIZE",
+        value_parser = parse_size
+    )]
+    pub max_client_size: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Check the integrity of the directory this often, like mag fsck (e.g. 24h)",
+        value_name = "INTERVAL",
+        value_parser = parse_duration
+    )]
+    pub scrub_interval: Option<std::time::Duration>,
+
+    #[arg(
+        long,
+        help = "Server to fetch corrupted content from. Can be given more than once.",
+        value_name = "URL"
+    )]
+    pub repair_from: Vec<String>,
+}
+
+/// Where `get` writes the content. Defaults to stdout.
+#[derive(Args, Debug, Serialize, Deserialize)]
+pub struct OutputArgs {
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "Write to FILE instead of stdout",
+        value_name = "FILE",
+        conflicts_with = "remote_name"
+    )]
+    pub file: Option<PathBuf>,
+
+    #[arg(
+        short = 'O',
+        long,
+        help = "Write to a file named after the magnet link's display name (dn), or the CID if it has none"
+    )]
+    pub remote_name: bool,
+
+    #[arg(
+        long,
+        help = "Directory to write the file to. Implies -O unless -o is given.",
+        value_name = "DIR"
+    )]
+    pub dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Overwrite the output file if it exists with different content"
+    )]
+    pub force: bool,
+}
+
+#[derive(Subcommand, Debug, Serialize, Deserialize)]
+pub enum PinCommands {
+    #[command(
+        about = "Pin a CID under a name. Pinning a DRISL record also pins everything it links to."
+    )]
+    Add {
+        #[arg(value_name = "NAME")]
+        name: String,
+
+        #[arg(value_name = "CID")]
+        cid: String,
+    },
+
+    #[command(about = "Remove a pin")]
+    Rm {
+        #[arg(value_name = "NAME")]
+        name: String,
+    },
+
+    #[command(about = "List pins")]
+    Ls,
+}
+
+#[derive(Subcommand, Debug, Serialize, Deserialize)]
+pub enum CarCommands {
+    #[command(about = "Write content as a CARv1 file to stdout, with the CIDs as roots")]
+    Export {
+        #[arg(value_name = "CID", required = true)]
+        cids: Vec<String>,
+    },
+
+    #[command(about = "Verify every block of a CARv1 file and add it to the directory")]
+    Import {
+        #[arg(
+            help = "CAR file to import. If file is not provided, reads from stdin.",
+            value_name = "FILE"
+        )]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug, Serialize, Deserialize)]
+pub enum ConfigCommands {
+    #[command(
+        about = "Print the settings each command uses, from the command line environment, config files and defaults"
+    )]
+    Show {
+        #[arg(
+            help = "Only show the settings of this command",
+            value_name = "COMMAND"
+        )]
+        command: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Serialize, Deserialize)]
+pub enum CacheCommands {
+    #[command(about = "List the CIDs in the cache, least recently used first")]
+    Ls,
+
+    #[command(about = "Print the total size of the cache in bytes")]
+    Size,
+
+    #[command(about = "Remove everything from the cache")]
+    Clear,
+
+    #[command(about = "Evict least recently used content until the cache fits in a size limit")]
+    Gc {
         #[arg(
             long,
-            help = "Address to listen on",
-            value_name = "ADDRESS",
-            default_value = "0.0.0.0:3000"
+            help = "Maximum size of the cache (e.g. 512M, 1G)",
+            value_name = "SIZE",
+            default_value = "1G",
+            value_parser = parse_size
         )]
-        addr: String,
+        max_size: u64,
     },
 }
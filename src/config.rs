@@ -0,0 +1,549 @@
+use clap::{ArgAction, Command};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Config file shared by everyone on the machine
+pub const SYSTEM_CONFIG: &str = "/etc/magnetize.toml";
+
+/// Environment variable with the path of the config file to use instead of
+/// the default ones
+pub const CONFIG_ENV: &str = "MAG_CONFIG";
+
+/// Section with defaults for every command except `serve`
+const CLIENT_SECTION: &str = "client";
+
+/// Commands that can't be configured
+const UNCONFIGURABLE: [&str; 2] = ["config", "help"];
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Unable to read config file {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("Invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Invalid config file {path}: unknown section [{section}]")]
+    UnknownSection { path: PathBuf, section: String },
+    #[error("Invalid config file {path}: unknown setting {key} in [{section}]")]
+    UnknownSetting {
+        path: PathBuf,
+        section: String,
+        key: String,
+    },
+    #[error(
+        "Invalid config file {path}: {key} in [{section}] must be a string, number, boolean or array of them"
+    )]
+    InvalidValue {
+        path: PathBuf,
+        section: String,
+        key: String,
+    },
+}
+
+/// A setting from a config file, in the same syntax as on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Setting {
+    values: Vec<String>,
+    /// The file the setting came from
+    path: PathBuf,
+}
+
+/// Settings from config files.
+///
+/// A config file has a section for each command, with settings named after
+/// the command's options, like `[serve]` with `addr = "0.0.0.0:80"`. Values
+/// take the same syntax as on the command line, e.g. `timeout = "30s"` or
+/// `max-blob-size = "1G"`, and options that can be given more than once
+/// take arrays. The `[client]` section sets defaults for every command
+/// except `serve` that has the option.
+///
+/// Settings become the defaults of their options, so options given on the
+/// command line or in environment variables take precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Files that were looked for, and whether they were found
+    pub files: Vec<(PathBuf, bool)>,
+    sections: BTreeMap<String, BTreeMap<String, Setting>>,
+}
+
+impl Config {
+    /// The user's config file, in `$XDG_CONFIG_HOME/magnetize/config.toml`
+    /// or the platform's equivalent
+    pub fn user_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("magnetize").join("config.toml"))
+    }
+
+    /// The config file given with `--config` or `MAG_CONFIG`, found before
+    /// the command line is parsed, since parsing depends on it
+    pub fn explicit_path(args: impl IntoIterator<Item = OsString>) -> Option<PathBuf> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            }
+            if arg == "--config" {
+                return args.next().map(PathBuf::from);
+            }
+            if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+                return Some(PathBuf::from(path));
+            }
+        }
+        std::env::var_os(CONFIG_ENV).map(PathBuf::from)
+    }
+
+    /// Load the config file given explicitly, which must exist. Without
+    /// one, load the system config file and then the user's, both of which
+    /// are optional. Settings in the user's file replace the system's.
+    pub fn load(explicit: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = Config::default();
+        match explicit {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+                    path: path.to_path_buf(),
+                    source,
+                })?;
+                config.merge(path, &text)?;
+                config.files.push((path.to_path_buf(), true));
+            }
+            None => {
+                let paths = std::iter::once(PathBuf::from(SYSTEM_CONFIG)).chain(Self::user_path());
+                for path in paths {
+                    let found = match fs::read_to_string(&path) {
+                        Ok(text) => {
+                            config.merge(&path, &text)?;
+                            true
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+                        Err(source) => return Err(ConfigError::Io { path, source }),
+                    };
+                    config.files.push((path, found));
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Add the settings in a config file, replacing any already set
+    fn merge(&mut self, path: &Path, text: &str) -> Result<(), ConfigError> {
+        let table: toml::Table = text.parse().map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        for (section, settings) in table {
+            let toml::Value::Table(settings) = settings else {
+                return Err(ConfigError::UnknownSection {
+                    path: path.to_path_buf(),
+                    section,
+                });
+            };
+            for (key, value) in settings {
+                let values = match value {
+                    toml::Value::Array(items) => items.into_iter().map(setting_value).collect(),
+                    value => setting_value(value).map(|value| vec![value]),
+                };
+                let Some(values) = values else {
+                    return Err(ConfigError::InvalidValue {
+                        path: path.to_path_buf(),
+                        section,
+                        key,
+                    });
+                };
+                let setting = Setting {
+                    values,
+                    path: path.to_path_buf(),
+                };
+                self.sections
+                    .entry(section.clone())
+                    .or_default()
+                    .insert(key, setting);
+            }
+        }
+        Ok(())
+    }
+
+    /// The settings for a command: its own section over the client section
+    fn settings_for(&self, command: &str) -> BTreeMap<&str, &Setting> {
+        let mut settings = BTreeMap::new();
+        if command != "serve"
+            && let Some(client) = self.sections.get(CLIENT_SECTION)
+        {
+            settings.extend(client.iter().map(|(key, setting)| (key.as_str(), setting)));
+        }
+        if let Some(own) = self.sections.get(command) {
+            settings.extend(own.iter().map(|(key, setting)| (key.as_str(), setting)));
+        }
+        settings
+    }
+
+    /// Make the settings the defaults of the options of each command, and
+    /// let each option be set with an environment variable named after the
+    /// command and the option, like `MAG_GET_CACHE_SIZE` for `--cache-size`
+    /// of `mag get`. Options that have their own variable, like `MAG_TOKEN`,
+    /// keep it.
+    /// Fails on sections and settings that don't match any command or
+    /// option, so typos don't go unnoticed.
+    pub fn apply(&self, mut cli: Command) -> Result<Command, ConfigError> {
+        for (section, settings) in &self.sections {
+            let Some(setting) = settings.values().next() else {
+                continue;
+            };
+            let is_command = cli
+                .find_subcommand(section)
+                .is_some_and(|_| !UNCONFIGURABLE.contains(&section.as_str()));
+            if section != CLIENT_SECTION && !is_command {
+                return Err(ConfigError::UnknownSection {
+                    path: setting.path.clone(),
+                    section: section.clone(),
+                });
+            }
+            for (key, setting) in settings {
+                let known = configurable(&cli)
+                    .filter(|command| section != CLIENT_SECTION || command.get_name() != "serve")
+                    .filter(|command| section == CLIENT_SECTION || command.get_name() == section)
+                    .any(|command| find_arg(command, key).is_some());
+                if !known {
+                    return Err(ConfigError::UnknownSetting {
+                        path: setting.path.clone(),
+                        section: section.clone(),
+                        key: key.clone(),
+                    });
+                }
+            }
+        }
+
+        let names: Vec<String> = configurable(&cli)
+            .map(|command| command.get_name().to_string())
+            .collect();
+        for name in names {
+            let settings = self.settings_for(&name);
+            cli = cli.mut_subcommand(&name, |mut command| {
+                let ids: Vec<_> = command
+                    .get_arguments()
+                    .filter(|arg| arg.get_env().is_none())
+                    .map(|arg| arg.get_id().clone())
+                    .filter(|id| id != "help" && id != "config")
+                    .collect();
+                for id in ids {
+                    let var = env_var(&name, id.as_str());
+                    command = command.mut_arg(id, |arg| arg.env(var));
+                }
+                for (key, setting) in settings {
+                    let Some(id) = find_arg(&command, key).map(|arg| arg.get_id().clone()) else {
+                        continue;
+                    };
+                    command = command.mut_arg(id, |arg| {
+                        let secret = arg.is_hide_env_values_set();
+                        let arg = arg.default_values(setting.values.clone()).required(false);
+                        // Secrets must not show up in --help either
+                        if secret {
+                            arg.hide_default_value(true)
+                        } else {
+                            arg
+                        }
+                    });
+                }
+                command
+            });
+        }
+        Ok(cli)
+    }
+
+    /// Print the effective settings of each command, or of one command, as
+    /// a config file. Each setting is followed by where it comes from.
+    pub fn show(&self, cli: &Command, only: Option<&str>) -> String {
+        let mut out = String::new();
+        for (path, found) in &self.files {
+            let status = if *found { "loaded" } else { "not found" };
+            let _ = writeln!(out, "# {}: {}", path.display(), status);
+        }
+
+        for command in
+            configurable(cli).filter(|command| only.is_none_or(|only| command.get_name() == only))
+        {
+            let settings = self.settings_for(command.get_name());
+            let _ = writeln!(out, "\n[{}]", command.get_name());
+            let mut lines = BTreeMap::new();
+            for arg in command.get_arguments() {
+                let id = arg.get_id().as_str();
+                if id == "help" || id == "config" {
+                    continue;
+                }
+                let key = arg.get_long().unwrap_or(id).replace('_', "-");
+                let from_env = arg
+                    .get_env()
+                    .and_then(|name| Some((name, std::env::var(name).ok()?)));
+                let (values, source) = if let Some((name, value)) = from_env {
+                    (vec![value], format!("env {}", name.to_string_lossy()))
+                } else if let Some(setting) = settings.get(key.as_str()) {
+                    (setting.values.clone(), setting.path.display().to_string())
+                } else if !arg.get_default_values().is_empty() {
+                    let values = arg
+                        .get_default_values()
+                        .iter()
+                        .map(|value| value.to_string_lossy().into_owned())
+                        .collect();
+                    (values, "default".to_string())
+                } else {
+                    continue;
+                };
+                let value = if arg.is_hide_env_values_set() && source != "default" {
+                    toml_value("<hidden>")
+                } else if matches!(arg.get_action(), ArgAction::Append) {
+                    let items: Vec<String> = values.iter().map(|value| toml_value(value)).collect();
+                    format!("[{}]", items.join(", "))
+                } else {
+                    toml_value(&values.concat())
+                };
+                let line = format!("{} = {} # {}", key, value, source);
+                lines.insert(key, line);
+            }
+            for line in lines.values() {
+                let _ = writeln!(out, "{}", line);
+            }
+        }
+        out
+    }
+}
+
+/// The environment variable for an option of a command
+fn env_var(command: &str, id: &str) -> String {
+    format!("MAG_{}_{}", command, id)
+        .replace('-', "_")
+        .to_uppercase()
+}
+
+/// The commands that take settings from config files
+fn configurable(cli: &Command) -> impl Iterator<Item = &Command> {
+    cli.get_subcommands()
+        .filter(|command| !UNCONFIGURABLE.contains(&command.get_name()))
+}
+
+/// The option of a command that a setting is named after: its long name, or
+/// for positional arguments, its name with dashes
+fn find_arg<'a>(command: &'a Command, key: &str) -> Option<&'a clap::Arg> {
+    command.get_arguments().find(|arg| {
+        let id = arg.get_id().as_str();
+        id != "help" && id != "config" && arg.get_long().unwrap_or(id).replace('_', "-") == key
+    })
+}
+
+/// A config value in command line syntax
+fn setting_value(value: toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Format a value as it would be written in a config file
+fn toml_value(value: &str) -> String {
+    if value.parse::<i64>().is_ok() || value == "true" || value == "false" {
+        value.to_string()
+    } else {
+        toml::Value::String(value.to_string()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Cli, Commands};
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn config(text: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        config.merge(Path::new("test.toml"), text)?;
+        Ok(config)
+    }
+
+    fn parse(config: &Config, args: &[&str]) -> Commands {
+        let matches = config
+            .apply(Cli::command())
+            .unwrap()
+            .try_get_matches_from(args)
+            .unwrap();
+        Cli::from_arg_matches(&matches).unwrap().command
+    }
+
+    #[test]
+    fn test_settings_become_defaults() {
+        let config = config(
+            r#"
+            [client]
+            timeout = 60
+            connect-timeout = 5
+
+            [push]
+            to = ["https://a.example/", "https://b.example/"]
+            connect-timeout = 3
+
+            [serve]
+            dir = "/srv/magnetize"
+            enable-listing = true
+            max-blob-size = "1G"
+            "#,
+        )
+        .unwrap();
+
+        let Commands::Push {
+            to,
+            timeout,
+            connect_timeout,
+            ..
+        } = parse(&config, &["mag", "push", "file"])
+        else {
+            panic!("expected push");
+        };
+        assert_eq!(to, vec!["https://a.example/", "https://b.example/"]);
+        assert_eq!(timeout, Some(60));
+        assert_eq!(connect_timeout, 3);
+
+        let Commands::Serve(serve) = parse(&config, &["mag", "serve"]) else {
+            panic!("expected serve");
+        };
+        assert_eq!(serve.dir, PathBuf::from("/srv/magnetize"));
+        assert!(serve.enable_listing);
+        assert_eq!(serve.max_blob_size, Some(1 << 30));
+        assert_eq!(serve.addr, "0.0.0.0:3000");
+    }
+
+    #[test]
+    fn test_options_have_env_vars() {
+        let cli = Config::default().apply(Cli::command()).unwrap();
+        let env = |command: &str, id: &str| {
+            let command = cli.find_subcommand(command).unwrap();
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_id() == id)
+                .unwrap();
+            arg.get_env()
+                .map(|name| name.to_string_lossy().into_owned())
+        };
+        assert_eq!(
+            env("get", "cache_size").as_deref(),
+            Some("MAG_GET_CACHE_SIZE")
+        );
+        assert_eq!(env("serve", "dir").as_deref(), Some("MAG_SERVE_DIR"));
+        assert_eq!(env("push", "token").as_deref(), Some("MAG_TOKEN"));
+        assert_eq!(
+            env("serve", "auth_token").as_deref(),
+            Some("MAG_AUTH_TOKEN")
+        );
+    }
+
+    #[test]
+    fn test_command_line_overrides_settings() {
+        let config = config("[push]\nto = [\"https://a.example/\"]\ntimeout = 60\n").unwrap();
+        let Commands::Push { to, timeout, .. } = parse(
+            &config,
+            &[
+                "mag",
+                "push",
+                "file",
+                "--to",
+                "https://c.example/",
+                "--timeout",
+                "5",
+            ],
+        ) else {
+            panic!("expected push");
+        };
+        assert_eq!(to, vec!["https://c.example/"]);
+        assert_eq!(timeout, Some(5));
+    }
+
+    #[test]
+    fn test_later_files_replace_settings() {
+        let mut config = config("[serve]\naddr = \"127.0.0.1:1\"\ndir = \"a\"\n").unwrap();
+        config
+            .merge(Path::new("user.toml"), "[serve]\naddr = \"127.0.0.1:2\"\n")
+            .unwrap();
+        let Commands::Serve(serve) = parse(&config, &["mag", "serve"]) else {
+            panic!("expected serve");
+        };
+        assert_eq!(serve.addr, "127.0.0.1:2");
+        assert_eq!(serve.dir, PathBuf::from("a"));
+    }
+
+    #[test]
+    fn test_unknown_settings_are_rejected() {
+        let unknown_section = config("[serv]\naddr = \"x\"\n").unwrap();
+        assert!(matches!(
+            unknown_section.apply(Cli::command()),
+            Err(ConfigError::UnknownSection { .. })
+        ));
+        let unknown_setting = config("[serve]\nadr = \"x\"\n").unwrap();
+        assert!(matches!(
+            unknown_setting.apply(Cli::command()),
+            Err(ConfigError::UnknownSetting { .. })
+        ));
+        let server_only = config("[client]\nenable-listing = true\n").unwrap();
+        assert!(matches!(
+            server_only.apply(Cli::command()),
+            Err(ConfigError::UnknownSetting { .. })
+        ));
+        assert!(matches!(
+            config("[serve]\nquota = { max = 1 }\n"),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn test_explicit_path() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            Config::explicit_path(args(&["mag", "--config", "a.toml", "serve"])),
+            Some(PathBuf::from("a.toml"))
+        );
+        assert_eq!(
+            Config::explicit_path(args(&["mag", "serve", "--config=b.toml"])),
+            Some(PathBuf::from("b.toml"))
+        );
+    }
+
+    #[test]
+    fn test_show() {
+        let config = config("[serve]\naddr = \"127.0.0.1:80\"\nauth-token = \"secret\"\n").unwrap();
+        let cli = config.apply(Cli::command()).unwrap();
+        let shown = config.show(&cli, Some("serve"));
+        assert!(shown.contains("[serve]\n"));
+        assert!(shown.contains("addr = \"127.0.0.1:80\" # test.toml\n"));
+        assert!(shown.contains("dir = \"public\" # default\n"));
+        assert!(!shown.contains("secret"));
+        assert!(!shown.contains("[push]"));
+    }
+
+    #[test]
+    fn test_help_hides_secrets() {
+        let config =
+            config("[serve]\nauth-token = \"supersecret\"\n[push]\ntoken = \"tok2\"\n").unwrap();
+        let mut cli = config.apply(Cli::command()).unwrap();
+        for (command, flag, secret) in [
+            ("serve", "--auth-token", "supersecret"),
+            ("push", "--token", "tok2"),
+        ] {
+            let help = cli
+                .find_subcommand_mut(command)
+                .unwrap()
+                .render_long_help()
+                .to_string();
+            assert!(help.contains(flag));
+            assert!(
+                !help.contains(secret),
+                "{} --help shows {}",
+                command,
+                secret
+            );
+        }
+    }
+}
@@ -0,0 +1,926 @@
+use crate::cid::{Cid, Codec};
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Media type of a dag-cbor block in the IPFS gateway specs. DRISL is the
+/// deterministic subset of dag-cbor, so every DRISL record is one.
+pub const DRISL_MEDIA_TYPE: &str = "application/vnd.ipld.dag-cbor";
+
+/// Largest record we'll decode. Records are for manifests, indexes and
+/// metadata; bulk data belongs in raw blobs they link to.
+pub const MAX_RECORD_SIZE: u64 = 1024 * 1024;
+
+/// CBOR tag for CID links
+const CBOR_TAG_CID: u64 = 42;
+
+/// Nesting allowed in a record, so decoding can't overflow the stack
+const MAX_DEPTH: usize = 64;
+
+/// Name `Cid` gives its newtype when serialized for a format that isn't
+/// human-readable, so DRISL can tell links from other bytes
+pub(crate) const LINK: &str = "$drisl::Link";
+
+/// A DRISL data model value. See <https://dasl.ing/drisl.html>
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    /// Integers from -2^64 to 2^64 - 1
+    Integer(i128),
+    /// Finite 64-bit floats
+    Float(f64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    /// Maps with text keys, encoded shortest key first, then bytewise
+    Map(BTreeMap<String, Value>),
+    /// A CID link, encoded as tag 42
+    Link(Cid),
+}
+
+impl Value {
+    /// Every CID this value links to, depth first
+    pub fn links(&self) -> Vec<Cid> {
+        let mut links = Vec::new();
+        self.collect_links(&mut links);
+        links
+    }
+
+    fn collect_links(&self, links: &mut Vec<Cid>) {
+        match self {
+            Value::Link(cid) => links.push(*cid),
+            Value::Array(items) => items.iter().for_each(|item| item.collect_links(links)),
+            Value::Map(entries) => entries
+                .values()
+                .for_each(|value| value.collect_links(links)),
+            _ => {}
+        }
+    }
+}
+
+/// The CID of an encoded record
+pub fn cid_of(bytes: impl AsRef<[u8]>) -> Cid {
+    Cid::of(bytes).with_codec(Codec::Drisl)
+}
+
+/// Encode a serde value as a DRISL record.
+///
+/// `Cid` fields become links. Note that serde serializes `Vec<u8>` as an
+/// array of integers, not as bytes.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, DrislError> {
+    encode(&value.serialize(ValueSerializer)?)
+}
+
+/// Decode a DRISL record into a serde value.
+/// Fails if the record isn't in its one deterministic encoding.
+pub fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DrislError> {
+    T::deserialize(decode(bytes)?)
+}
+
+/// Encode a value in its deterministic form
+pub fn encode(value: &Value) -> Result<Vec<u8>, DrislError> {
+    let mut out = Vec::new();
+    encode_into(&mut out, value)?;
+    Ok(out)
+}
+
+fn encode_into(out: &mut Vec<u8>, value: &Value) -> Result<(), DrislError> {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Integer(n) => match (u64::try_from(*n), u64::try_from(-1 - *n)) {
+            (Ok(n), _) => write_cbor_head(out, 0, n),
+            (_, Ok(n)) => write_cbor_head(out, 1, n),
+            _ => return Err(DrislError::Unsupported(format!("integer {}", n))),
+        },
+        Value::Float(f) => {
+            if !f.is_finite() {
+                return Err(DrislError::Unsupported(format!("float {}", f)));
+            }
+            // Floats are always 64-bit, so each has only one encoding
+            out.push(0xfb);
+            out.extend_from_slice(&f.to_be_bytes());
+        }
+        Value::Bytes(bytes) => {
+            write_cbor_head(out, 2, bytes.len() as u64);
+            out.extend_from_slice(bytes);
+        }
+        Value::Text(text) => {
+            write_cbor_head(out, 3, text.len() as u64);
+            out.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(items) => {
+            write_cbor_head(out, 4, items.len() as u64);
+            for item in items {
+                encode_into(out, item)?;
+            }
+        }
+        Value::Map(entries) => {
+            write_cbor_head(out, 5, entries.len() as u64);
+            let mut entries: Vec<_> = entries.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| key_order(a, b));
+            for (key, value) in entries {
+                write_cbor_head(out, 3, key.len() as u64);
+                out.extend_from_slice(key.as_bytes());
+                encode_into(out, value)?;
+            }
+        }
+        Value::Link(cid) => {
+            let cid_bytes = cid.to_bytes();
+            write_cbor_head(out, 6, CBOR_TAG_CID);
+            // CIDs are prefixed with the identity multibase, 0x00
+            write_cbor_head(out, 2, cid_bytes.len() as u64 + 1);
+            out.push(0x00);
+            out.extend_from_slice(&cid_bytes);
+        }
+    }
+    Ok(())
+}
+
+/// Map keys are sorted shortest first, then bytewise
+fn key_order(a: &str, b: &str) -> std::cmp::Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.as_bytes().cmp(b.as_bytes()))
+}
+
+/// Write the head of a CBOR item: its major type and argument, in the
+/// shortest form
+fn write_cbor_head(out: &mut Vec<u8>, major: u8, arg: u64) {
+    let major = major << 5;
+    match arg {
+        0..=23 => out.push(major | arg as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, arg as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(arg as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(arg as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&arg.to_be_bytes());
+        }
+    }
+}
+
+/// Decode a record, rejecting anything but the deterministic encoding:
+/// shortest-form lengths, sorted text keys without duplicates, 64-bit finite
+/// floats, tag 42 as the only tag, and no trailing bytes.
+pub fn decode(bytes: &[u8]) -> Result<Value, DrislError> {
+    let mut pos = 0;
+    let value = decode_value(bytes, &mut pos, 0)?;
+    if pos != bytes.len() {
+        return Err(DrislError::Invalid("trailing bytes".to_string()));
+    }
+    Ok(value)
+}
+
+fn decode_value(bytes: &[u8], pos: &mut usize, depth: usize) -> Result<Value, DrislError> {
+    let invalid = |msg: &str| DrislError::Invalid(msg.to_string());
+    if depth > MAX_DEPTH {
+        return Err(invalid("nested too deep"));
+    }
+    let initial = *bytes.get(*pos).ok_or_else(|| invalid("truncated"))?;
+    *pos += 1;
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+    let arg = match info {
+        0..=23 => info as u64,
+        24..=27 => {
+            let len = 1 << (info - 24);
+            let arg_bytes = bytes
+                .get(*pos..*pos + len)
+                .ok_or_else(|| invalid("truncated"))?;
+            *pos += len;
+            let arg = arg_bytes
+                .iter()
+                .fold(0, |arg, byte| (arg << 8) | *byte as u64);
+            let shortest = match info {
+                24 => arg >= 24,
+                25 => arg > 0xff,
+                26 => arg > 0xffff,
+                _ => arg > 0xffff_ffff,
+            };
+            // Simple values and floats aren't lengths
+            if major != 7 && !shortest {
+                return Err(invalid("length not in its shortest form"));
+            }
+            arg
+        }
+        _ => return Err(invalid("indefinite or reserved length")),
+    };
+    let mut take = |len: u64| -> Result<&[u8], DrislError> {
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| pos.checked_add(len))
+            .filter(|end| *end <= bytes.len())
+            .ok_or_else(|| invalid("truncated"))?;
+        let taken = &bytes[*pos..end];
+        *pos = end;
+        Ok(taken)
+    };
+    Ok(match major {
+        0 => Value::Integer(arg as i128),
+        1 => Value::Integer(-1 - arg as i128),
+        2 => Value::Bytes(take(arg)?.to_vec()),
+        3 => Value::Text(
+            String::from_utf8(take(arg)?.to_vec()).map_err(|_| invalid("invalid UTF-8"))?,
+        ),
+        4 => {
+            let mut items = Vec::new();
+            for _ in 0..arg {
+                items.push(decode_value(bytes, pos, depth + 1)?);
+            }
+            Value::Array(items)
+        }
+        5 => {
+            let mut entries = BTreeMap::new();
+            let mut previous: Option<String> = None;
+            for _ in 0..arg {
+                let Value::Text(key) = decode_value(bytes, pos, depth + 1)? else {
+                    return Err(invalid("map key is not text"));
+                };
+                if previous
+                    .as_deref()
+                    .is_some_and(|previous| key_order(previous, &key).is_ge())
+                {
+                    return Err(invalid("map keys not sorted, or duplicated"));
+                }
+                let value = decode_value(bytes, pos, depth + 1)?;
+                previous = Some(key.clone());
+                entries.insert(key, value);
+            }
+            Value::Map(entries)
+        }
+        6 if arg == CBOR_TAG_CID => {
+            let Value::Bytes(link) = decode_value(bytes, pos, depth + 1)? else {
+                return Err(invalid("CID link is not bytes"));
+            };
+            match link.split_first() {
+                Some((0x00, cid_bytes)) => Value::Link(
+                    Cid::parse_bytes(cid_bytes.to_vec())
+                        .map_err(|_| DrislError::UnsupportedCid(cid_bytes.to_vec()))?,
+                ),
+                _ => return Err(invalid("CID link without the 0x00 prefix")),
+            }
+        }
+        6 => return Err(invalid("tags other than 42 are not allowed")),
+        _ => match info {
+            20 => Value::Bool(false),
+            21 => Value::Bool(true),
+            22 => Value::Null,
+            27 => {
+                let f = f64::from_bits(arg);
+                if !f.is_finite() {
+                    return Err(invalid("NaN and infinities are not allowed"));
+                }
+                Value::Float(f)
+            }
+            25 | 26 => return Err(invalid("floats must be 64-bit")),
+            _ => return Err(invalid("unsupported simple value")),
+        },
+    })
+}
+
+/// Serializes serde values into `Value`s
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = DrislError;
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeArray;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeMap;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<Value, DrislError> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, DrislError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, DrislError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, DrislError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, DrislError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Value, DrislError> {
+        Ok(Value::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, DrislError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, DrislError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, DrislError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, DrislError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Value, DrislError> {
+        i128::try_from(v)
+            .map(Value::Integer)
+            .map_err(|_| DrislError::Unsupported(format!("integer {}", v)))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, DrislError> {
+        Ok(Value::Float(v.into()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, DrislError> {
+        Ok(Value::Float(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, DrislError> {
+        Ok(Value::Text(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, DrislError> {
+        Ok(Value::Text(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, DrislError> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Value, DrislError> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, DrislError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, DrislError> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, DrislError> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, DrislError> {
+        Ok(Value::Text(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Value, DrislError> {
+        let value = value.serialize(self)?;
+        if name != LINK {
+            return Ok(value);
+        }
+        match value {
+            Value::Bytes(cid_bytes) => Cid::parse_bytes(cid_bytes.clone())
+                .map(Value::Link)
+                .map_err(|_| DrislError::UnsupportedCid(cid_bytes)),
+            _ => Err(DrislError::Custom("CID link is not bytes".to_string())),
+        }
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, DrislError> {
+        let value = value.serialize(self)?;
+        Ok(Value::Map(BTreeMap::from([(variant.to_string(), value)])))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, DrislError> {
+        Ok(SerializeArray {
+            variant: None,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, DrislError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, DrislError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, DrislError> {
+        Ok(SerializeArray {
+            variant: Some(variant),
+            items: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeMap, DrislError> {
+        Ok(SerializeMap {
+            variant: None,
+            entries: BTreeMap::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap, DrislError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeMap, DrislError> {
+        Ok(SerializeMap {
+            variant: Some(variant),
+            entries: BTreeMap::new(),
+            key: None,
+        })
+    }
+}
+
+/// Wrap the value of an enum variant as `{variant: value}`
+fn wrap_variant(variant: Option<&'static str>, value: Value) -> Value {
+    match variant {
+        Some(variant) => Value::Map(BTreeMap::from([(variant.to_string(), value)])),
+        None => value,
+    }
+}
+
+struct SerializeArray {
+    variant: Option<&'static str>,
+    items: Vec<Value>,
+}
+
+impl SerializeArray {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), DrislError> {
+        self.items.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, DrislError> {
+        Ok(wrap_variant(self.variant, Value::Array(self.items)))
+    }
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = Value;
+    type Error = DrislError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), DrislError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, DrislError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = Value;
+    type Error = DrislError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), DrislError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, DrislError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = Value;
+    type Error = DrislError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), DrislError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, DrislError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeArray {
+    type Ok = Value;
+    type Error = DrislError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), DrislError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, DrislError> {
+        self.finish()
+    }
+}
+
+struct SerializeMap {
+    variant: Option<&'static str>,
+    entries: BTreeMap<String, Value>,
+    key: Option<String>,
+}
+
+impl SerializeMap {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<(), DrislError> {
+        self.entries.insert(key, value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, DrislError> {
+        Ok(wrap_variant(self.variant, Value::Map(self.entries)))
+    }
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Value;
+    type Error = DrislError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), DrislError> {
+        match key.serialize(ValueSerializer)? {
+            Value::Text(key) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => Err(DrislError::Unsupported(
+                "map key that isn't text".to_string(),
+            )),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), DrislError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| DrislError::Custom("map value without a key".to_string()))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Value, DrislError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Value;
+    type Error = DrislError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), DrislError> {
+        self.insert(key.to_string(), value)
+    }
+
+    fn end(self) -> Result<Value, DrislError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeMap {
+    type Ok = Value;
+    type Error = DrislError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), DrislError> {
+        self.insert(key.to_string(), value)
+    }
+
+    fn end(self) -> Result<Value, DrislError> {
+        self.finish()
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = DrislError;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DrislError> {
+        match self {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(v) => visitor.visit_bool(v),
+            Value::Integer(n) => match (u64::try_from(n), i64::try_from(n)) {
+                (Ok(n), _) => visitor.visit_u64(n),
+                (_, Ok(n)) => visitor.visit_i64(n),
+                _ => visitor.visit_i128(n),
+            },
+            Value::Float(f) => visitor.visit_f64(f),
+            Value::Bytes(bytes) => visitor.visit_byte_buf(bytes),
+            Value::Text(text) => visitor.visit_string(text),
+            Value::Array(items) => {
+                let mut seq = SeqDeserializer::new(items.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Value::Map(entries) => {
+                let mut map = MapDeserializer::new(entries.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+            Value::Link(cid) => visitor.visit_byte_buf(cid.to_bytes()),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DrislError> {
+        match self {
+            Value::Null => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DrislError> {
+        if name != LINK {
+            return visitor.visit_newtype_struct(self);
+        }
+        match self {
+            Value::Link(cid) => visitor.visit_byte_buf(cid.to_bytes()),
+            _ => Err(de::Error::custom("expected a CID link")),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DrislError> {
+        match self {
+            Value::Text(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Value::Map(entries) if entries.len() == 1 => {
+                let (variant, value) = entries.into_iter().next().expect("map has one entry");
+                visitor.visit_enum(EnumAccess { variant, value })
+            }
+            _ => Err(de::Error::custom("expected an enum variant")),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DrislError> for Value {
+    type Deserializer = Value;
+
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+/// An enum variant written as `{variant: value}`
+struct EnumAccess {
+    variant: String,
+    value: Value,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = DrislError;
+    type Variant = Value;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Value), DrislError> {
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((variant, self.value))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Value {
+    type Error = DrislError;
+
+    fn unit_variant(self) -> Result<(), DrislError> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, DrislError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DrislError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DrislError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DrislError {
+    #[error("Invalid DRISL: {0}")]
+    Invalid(String),
+    #[error("DRISL can't represent {0}")]
+    Unsupported(String),
+    /// The bytes of a CID link we can't represent
+    #[error("Unsupported CID link. Only raw and DRISL sha-256 CIDs are supported.")]
+    UnsupportedCid(Vec<u8>),
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl ser::Error for DrislError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        DrislError::Custom(msg.to_string())
+    }
+}
+
+impl de::Error for DrislError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        DrislError::Custom(msg.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Release {
+        name: String,
+        size: u64,
+        offset: i32,
+        ratio: f64,
+        files: Vec<Cid>,
+        parent: Option<Cid>,
+        kind: Kind,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Kind {
+        Stable,
+        Nightly { build: u32 },
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let release = Release {
+            name: "v1".to_string(),
+            size: u64::MAX,
+            offset: -300,
+            ratio: 0.5,
+            files: vec![Cid::of(b"a"), Cid::of(b"b")],
+            parent: None,
+            kind: Kind::Nightly { build: 7 },
+        };
+        let bytes = to_vec(&release).unwrap();
+        assert_eq!(from_slice::<Release>(&bytes).unwrap(), release);
+
+        let value = decode(&bytes).unwrap();
+        assert_eq!(value.links(), release.files);
+        let Value::Map(entries) = &value else {
+            panic!("not a map");
+        };
+        assert_eq!(entries["parent"], Value::Null);
+        assert_eq!(entries["kind"], {
+            let build = BTreeMap::from([("build".to_string(), Value::Integer(7))]);
+            Value::Map(BTreeMap::from([("Nightly".to_string(), Value::Map(build))]))
+        });
+        assert_eq!(cid_of(&bytes).codec(), Codec::Drisl);
+
+        let stable = Release {
+            kind: Kind::Stable,
+            ..release
+        };
+        assert_eq!(
+            from_slice::<Release>(&to_vec(&stable).unwrap()).unwrap(),
+            stable
+        );
+    }
+
+    #[test]
+    fn test_deterministic_encoding() {
+        let value = Value::Map(BTreeMap::from([
+            ("bb".to_string(), Value::Integer(-1)),
+            ("a".to_string(), Value::Integer(24)),
+            ("c".to_string(), Value::Link(Cid::of(b""))),
+            ("f".to_string(), Value::Float(1.5)),
+        ]));
+        let bytes = encode(&value).unwrap();
+        let mut expected = vec![
+            0xa4, 0x61, b'a', 0x18, 24, 0x61, b'c', 0xd8, 0x2a, 0x58, 37, 0x00,
+        ];
+        expected.extend_from_slice(&Cid::of(b"").to_bytes());
+        expected.extend_from_slice(&[0x61, b'f', 0xfb]);
+        expected.extend_from_slice(&1.5f64.to_be_bytes());
+        expected.extend_from_slice(&[0x62, b'b', b'b', 0x20]);
+        assert_eq!(bytes, expected);
+        assert_eq!(decode(&bytes).unwrap(), value);
+
+        assert_eq!(
+            encode(&Value::Integer(-(1 << 64))).unwrap(),
+            vec![0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+        assert!(encode(&Value::Integer(1 << 64)).is_err());
+        assert!(encode(&Value::Float(f64::NAN)).is_err());
+    }
+
+    #[test]
+    fn test_rejects_non_deterministic_encodings() {
+        for bytes in [
+            // 1 with a one-byte argument
+            &[0x18, 0x01][..],
+            // Indefinite-length array
+            &[0x9f, 0xff],
+            // Keys out of order, and duplicated
+            &[0xa2, 0x62, b'a', b'a', 0x00, 0x61, b'b', 0x00],
+            &[0xa2, 0x61, b'a', 0x00, 0x61, b'a', 0x00],
+            // Integer key
+            &[0xa1, 0x00, 0x00],
+            // 32-bit float
+            &[0xfa, 0x3f, 0xc0, 0x00, 0x00],
+            // Undefined
+            &[0xf7],
+            // Tag 1
+            &[0xc1, 0x00],
+            // Trailing bytes
+            &[0x00, 0x00],
+            // Truncated
+            &[0x62, b'a'],
+        ] {
+            assert!(
+                matches!(decode(bytes), Err(DrislError::Invalid(_))),
+                "{:x?}",
+                bytes
+            );
+        }
+
+        // A dag-pb link
+        let mut bytes = vec![0xd8, 0x2a, 0x58, 37, 0x00, 0x01, 0x70, 0x12, 0x20];
+        bytes.extend_from_slice(&[0u8; 32]);
+        assert!(matches!(decode(&bytes), Err(DrislError::UnsupportedCid(_))));
+    }
+}
@@ -0,0 +1,206 @@
+use crate::cid::Cid;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Longest file name (in bytes) that common filesystems allow
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// Names that Windows reserves for devices, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turn an untrusted name (like a magnet link's `dn`) into a safe file name.
+///
+/// Only the last path component is kept, so the name can't traverse out of
+/// the target directory. Control characters and characters that are invalid
+/// on common filesystems are dropped or replaced, and reserved device names
+/// are prefixed. Returns None if nothing usable is left.
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("");
+
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows silently drops trailing dots and spaces
+    let name = name.trim().trim_end_matches(['.', ' ']);
+
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+
+    let stem = name.split('.').next().unwrap_or("");
+    let mut name = if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    };
+
+    if name.len() > MAX_FILE_NAME_LEN {
+        let mut end = MAX_FILE_NAME_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+
+    Some(name)
+}
+
+/// Write a file atomically. The bytes are written to a temp file in the same
+/// directory, then renamed into place, so the file either has the old
+/// contents or the new ones, never something in between.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = temp_path(path)?;
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// A hidden temp file next to `path`, to write to before renaming it into
+/// place
+pub fn temp_path(path: &Path) -> io::Result<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let file_name = path.file_name().ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Not a file path",
+    ))?;
+
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    Ok(dir.join(tmp_name))
+}
+
+/// What we found at a path we'd like to write content to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existing {
+    /// Nothing there
+    Missing,
+    /// A file with exactly the content of the CID
+    Matches,
+    /// Something else
+    Differs,
+}
+
+/// Check whether a path already holds the content for a CID
+pub fn check_existing(path: &Path, cid: &Cid) -> io::Result<Existing> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Existing::Missing),
+        Err(err) => return Err(err),
+    };
+    if !file.metadata()?.is_file() {
+        return Ok(Existing::Differs);
+    }
+    if Cid::read(&mut file)?.with_codec(cid.codec()) == *cid {
+        Ok(Existing::Matches)
+    } else {
+        Ok(Existing::Differs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_keeps_ordinary_names() {
+        assert_eq!(
+            sanitize_file_name("report.pdf"),
+            Some("report.pdf".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("my file (1).tar.gz"),
+            Some("my file (1).tar.gz".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sanitize_path_traversal() {
+        assert_eq!(
+            sanitize_file_name("../../etc/passwd"),
+            Some("passwd".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("..\\..\\windows\\system.ini"),
+            Some("system.ini".to_string())
+        );
+        assert_eq!(sanitize_file_name("/tmp/"), None);
+        assert_eq!(sanitize_file_name(".."), None);
+        assert_eq!(sanitize_file_name("."), None);
+        assert_eq!(sanitize_file_name(""), None);
+    }
+
+    #[test]
+    fn test_sanitize_special_characters() {
+        assert_eq!(
+            sanitize_file_name("evil\nname\u{7}.txt"),
+            Some("evilname.txt".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("what?<now>.txt"),
+            Some("what__now_.txt".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("trailing. . "),
+            Some("trailing".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sanitize_reserved_names() {
+        assert_eq!(sanitize_file_name("CON"), Some("_CON".to_string()));
+        assert_eq!(sanitize_file_name("nul.txt"), Some("_nul.txt".to_string()));
+        assert_eq!(sanitize_file_name("console"), Some("console".to_string()));
+    }
+
+    #[test]
+    fn test_sanitize_long_names() {
+        let name = "é".repeat(200);
+        let sanitized = sanitize_file_name(&name).unwrap();
+        assert!(sanitized.len() <= MAX_FILE_NAME_LEN);
+        assert!(sanitized.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn test_write_atomic_and_check_existing() {
+        let dir = std::env::temp_dir().join(format!("magnetize-file-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hello.txt");
+        let cid = Cid::of(b"hello world");
+
+        assert_eq!(check_existing(&path, &cid).unwrap(), Existing::Missing);
+
+        write_atomic(&path, b"hello world").unwrap();
+        assert_eq!(check_existing(&path, &cid).unwrap(), Existing::Matches);
+
+        write_atomic(&path, b"goodbye").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"goodbye");
+        assert_eq!(check_existing(&path, &cid).unwrap(), Existing::Differs);
+
+        // No temp files left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
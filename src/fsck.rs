@@ -0,0 +1,186 @@
+use crate::cid::Cid;
+use crate::request::{Client, RequestError};
+use crate::store::{STALE_TEMP_AGE, Store, TEMP_SUFFIX};
+use crate::url::Url;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// What a check of a store found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Number of blobs that were re-hashed
+    pub checked: u64,
+    /// Blobs that didn't match their CID, and were moved to quarantine
+    pub corrupted: Vec<Cid>,
+    /// Stray files and stale temp files that were removed
+    pub removed: Vec<PathBuf>,
+    /// Files that couldn't be checked, and why
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+impl FsckReport {
+    /// Did the check find anything wrong with the blobs?
+    /// Files that couldn't be checked don't count.
+    pub fn is_clean(&self) -> bool {
+        self.corrupted.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Check the integrity of every blob in a store.
+///
+/// Every blob is re-hashed. Blobs that don't match their CID are moved to
+/// the store's quarantine directory, so they are never served. Files that
+/// aren't blobs are removed, except for temp files of uploads that may still
+/// be in progress. With `dry_run`, nothing is changed, but the report lists
+/// what would be.
+///
+/// Files that are removed while the check runs (e.g. by a concurrent delete)
+/// are skipped. Other errors with a single file are listed in the report, and
+/// the check goes on with the next one.
+pub fn fsck(store: &Store, dry_run: bool) -> io::Result<FsckReport> {
+    let mut report = FsckReport::default();
+    let now = SystemTime::now();
+
+    for dir_entry in fs::read_dir(store.dir())? {
+        let path = dir_entry?.path();
+        match check_file(store, &path, now, dry_run, &mut report) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                tracing::error!(path = %path.display(), "unable to check file: {}", err);
+                report.errors.push((path, err.to_string()));
+            }
+            Ok(()) => {}
+        }
+    }
+
+    Ok(report)
+}
+
+fn check_file(
+    store: &Store,
+    path: &Path,
+    now: SystemTime,
+    dry_run: bool,
+    report: &mut FsckReport,
+) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    // The only directory in a store is the metadata directory
+    if !metadata.is_file() {
+        return Ok(());
+    }
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    if let Ok(cid) = Cid::parse(&name) {
+        let actual = Cid::read(&mut fs::File::open(path)?)?.with_codec(cid.codec());
+        report.checked += 1;
+        if actual != cid {
+            tracing::error!(%cid, %actual, "blob doesn't match its CID");
+            if !dry_run {
+                store.quarantine(&cid)?;
+            }
+            report.corrupted.push(cid);
+        }
+        return Ok(());
+    }
+
+    if name.ends_with(TEMP_SUFFIX) {
+        let age = now
+            .duration_since(metadata.modified()?)
+            .unwrap_or(Duration::ZERO);
+        if age < STALE_TEMP_AGE {
+            return Ok(());
+        }
+    }
+    if !dry_run {
+        fs::remove_file(path)?;
+    }
+    report.removed.push(path.to_path_buf());
+    Ok(())
+}
+
+/// Fetch a blob from peer servers into the store, e.g. to replace a
+/// corrupted blob. Peers are tried in order, and the store verifies the
+/// content against the CID. Returns the peer the blob came from, or None if
+/// no peer could provide it.
+pub async fn repair(client: &Client, store: &Store, cid: &Cid, peers: &[Url]) -> Option<Url> {
+    for peer in peers {
+        match fetch_into_store(client, store, cid, peer).await {
+            Ok(()) => return Some(peer.clone()),
+            Err(err) => tracing::warn!(%cid, %peer, "unable to repair from peer: {}", err),
+        }
+    }
+    None
+}
+
+async fn fetch_into_store(
+    client: &Client,
+    store: &Store,
+    cid: &Cid,
+    peer: &Url,
+) -> Result<(), RequestError> {
+    let url = peer.join(&cid.to_string())?;
+    let response = client.get(url).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(RequestError::HttpStatus(status, None));
+    }
+    store
+        .put_stream(cid, response.bytes_stream())
+        .await
+        .map_err(|err| RequestError::IntegrityError(err.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::test_store;
+
+    fn set_age(path: &std::path::Path, age: Duration) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_fsck() {
+        let store = test_store("fsck", "fsck");
+        let good = Cid::of("good");
+        let rotten = Cid::of("rotten");
+        fs::write(store.path(&good), "good").unwrap();
+        fs::write(store.path(&rotten), "r0tten").unwrap();
+        let stray = store.dir().join("notes.txt");
+        fs::write(&stray, "stray").unwrap();
+        let stale_temp = store.dir().join(format!("{}.1{}", good, TEMP_SUFFIX));
+        fs::write(&stale_temp, "partial").unwrap();
+        set_age(&stale_temp, 2 * STALE_TEMP_AGE);
+        let fresh_temp = store.dir().join(format!("{}.2{}", good, TEMP_SUFFIX));
+        fs::write(&fresh_temp, "in progress").unwrap();
+
+        let dry_run = fsck(&store, true).unwrap();
+        assert_eq!(dry_run.checked, 2);
+        assert_eq!(dry_run.corrupted, vec![rotten]);
+        assert_eq!(dry_run.removed.len(), 2);
+        assert!(store.contains(&rotten));
+        assert!(stray.exists());
+
+        let report = fsck(&store, false).unwrap();
+        assert_eq!(report.corrupted, vec![rotten]);
+        assert!(store.contains(&good));
+        assert!(!store.contains(&rotten));
+        assert!(!stray.exists());
+        assert!(!stale_temp.exists());
+        assert!(fresh_temp.exists());
+        assert_eq!(fs::read_dir(store.quarantine_dir()).unwrap().count(), 1);
+
+        assert!(fsck(&store, false).unwrap().is_clean());
+    }
+}
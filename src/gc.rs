@@ -0,0 +1,135 @@
+use crate::api::BlobInfo;
+use crate::pins::PinSet;
+use crate::store::Store;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Which unpinned blobs garbage collection removes.
+/// Pinned blobs are always kept, and without any limits nothing is removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcPolicy {
+    /// Remove unpinned blobs that were added longer ago than this
+    pub older_than: Option<Duration>,
+    /// Remove the oldest unpinned blobs until the store fits in this many bytes
+    pub max_size: Option<u64>,
+}
+
+/// What garbage collection removed, or would remove in a dry run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Removed blobs, oldest first
+    pub removed: Vec<BlobInfo>,
+    /// Total size of the blobs left in the store
+    pub size: u64,
+}
+
+/// Remove unpinned blobs from a store according to a policy.
+/// With `dry_run`, nothing is removed, but the report lists what would be.
+///
+/// The pins are locked throughout, so pins can't be changed, by this process
+/// or a server on the same store, while garbage is collected.
+pub fn collect_garbage(store: &Store, policy: &GcPolicy, dry_run: bool) -> io::Result<GcReport> {
+    let _lock = PinSet::lock(store)?;
+    let pinned = PinSet::load(store)?.pinned(store)?;
+    let mut blobs = store.list(None, None, usize::MAX)?;
+    blobs.sort_by_key(|blob| blob.added);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut size: u64 = blobs.iter().map(|blob| blob.size).sum();
+    let mut removed = Vec::new();
+
+    for blob in blobs {
+        if pinned.contains(&blob.cid) {
+            continue;
+        }
+        let is_expired = policy
+            .older_than
+            .is_some_and(|age| now.saturating_sub(blob.added) > age.as_secs());
+        let is_over_quota = policy.max_size.is_some_and(|max_size| size > max_size);
+        if !is_expired && !is_over_quota {
+            continue;
+        }
+        if !dry_run {
+            store.delete(&blob.cid)?;
+        }
+        size -= blob.size;
+        removed.push(blob);
+    }
+
+    Ok(GcReport { removed, size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cid::Cid;
+    use crate::store::test_store;
+    use std::fs;
+
+    /// Add a blob that was added `age` ago
+    fn add(store: &Store, content: &str, age: Duration) -> Cid {
+        let cid = Cid::of(content);
+        let path = store.path(&cid);
+        fs::write(&path, content).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+        cid
+    }
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn test_gc_removes_old_unpinned_blobs() {
+        let store = test_store("gc", "age");
+        let old = add(&store, "old", 10 * DAY);
+        let old_pinned = add(&store, "old pinned", 10 * DAY);
+        let new = add(&store, "new", Duration::ZERO);
+
+        let mut pins = PinSet::default();
+        pins.add("keep", old_pinned);
+        pins.save(&store).unwrap();
+
+        let policy = GcPolicy {
+            older_than: Some(7 * DAY),
+            max_size: None,
+        };
+
+        let dry_run = collect_garbage(&store, &policy, true).unwrap();
+        assert_eq!(dry_run.removed.len(), 1);
+        assert_eq!(dry_run.removed[0].cid, old);
+        assert!(store.contains(&old));
+
+        let report = collect_garbage(&store, &policy, false).unwrap();
+        assert_eq!(report, dry_run);
+        assert!(!store.contains(&old));
+        assert!(store.contains(&old_pinned));
+        assert!(store.contains(&new));
+    }
+
+    #[test]
+    fn test_gc_removes_oldest_blobs_beyond_quota() {
+        let store = test_store("gc", "quota");
+        let oldest = add(&store, "aaaaa", 3 * DAY);
+        let older = add(&store, "bbbbb", 2 * DAY);
+        let newest = add(&store, "ccccc", DAY);
+
+        let policy = GcPolicy {
+            older_than: None,
+            max_size: Some(10),
+        };
+        let report = collect_garbage(&store, &policy, false).unwrap();
+
+        assert_eq!(report.size, 10);
+        assert_eq!(report.removed.len(), 1);
+        assert!(!store.contains(&oldest));
+        assert!(store.contains(&older));
+        assert!(store.contains(&newest));
+    }
+}
@@ -0,0 +1,234 @@
+use crate::file::write_atomic;
+use crate::url::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Weight of the newest sample in the moving average of latency
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+/// How long an integrity failure counts against a mirror
+const INTEGRITY_PENALTY_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// What we remember about a mirror (an origin that serves content).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MirrorHealth {
+    /// Number of successful, verified fetches
+    pub successes: u64,
+    /// Number of failed fetches, including integrity failures
+    pub failures: u64,
+    /// Moving average of the time it takes the mirror to start responding
+    pub latency_ms: Option<f64>,
+    /// Unix timestamp (seconds) of the last time the mirror served content
+    /// that did not match the CID
+    pub last_integrity_failure: Option<u64>,
+}
+
+impl MirrorHealth {
+    /// Score a mirror. Higher is better.
+    /// Mirrors with a recent integrity failure always rank below honest ones.
+    pub fn score(&self, now: u64) -> f64 {
+        // Smoothed success rate, so unknown mirrors start at 0.5
+        let success_rate =
+            (self.successes as f64 + 1.0) / ((self.successes + self.failures) as f64 + 2.0);
+        // Map latency to 0..1, treating unknown latency as middling (1s)
+        let latency_ms = self.latency_ms.unwrap_or(1000.0);
+        let latency_penalty = latency_ms / (latency_ms + 1000.0);
+        let integrity_penalty = match self.last_integrity_failure {
+            Some(at) if now.saturating_sub(at) < INTEGRITY_PENALTY_SECS => 1.0,
+            _ => 0.0,
+        };
+        success_rate - 0.25 * latency_penalty - integrity_penalty
+    }
+}
+
+/// Persisted health of mirrors, keyed by origin.
+/// Used to try historically fast and honest mirrors first.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HealthCache {
+    pub mirrors: HashMap<String, MirrorHealth>,
+}
+
+impl HealthCache {
+    /// Default location of the health cache in the user's cache directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("magnetize").join("mirrors.json"))
+    }
+
+    /// Load the health cache from a file.
+    /// A missing or unreadable cache is not an error. We just start fresh.
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    /// Save the health cache to a file, creating parent directories.
+    /// Writes to a temp file and renames, so readers never see a partial file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(path, &serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Get the health of the mirror serving a URL, if we know anything about it
+    pub fn get(&self, url: &Url) -> Option<&MirrorHealth> {
+        self.mirrors.get(&mirror_key(url))
+    }
+
+    /// Record a successful, verified fetch, and the time it took the mirror
+    /// to start responding
+    pub fn record_success(&mut self, url: &Url, latency: Duration) {
+        let health = self.mirrors.entry(mirror_key(url)).or_default();
+        health.successes += 1;
+        let sample = latency.as_secs_f64() * 1000.0;
+        health.latency_ms = Some(match health.latency_ms {
+            Some(avg) => avg + LATENCY_SMOOTHING * (sample - avg),
+            None => sample,
+        });
+    }
+
+    /// Record a failed fetch
+    pub fn record_failure(&mut self, url: &Url) {
+        self.mirrors.entry(mirror_key(url)).or_default().failures += 1;
+    }
+
+    /// Record a fetch where the content did not match the CID
+    pub fn record_integrity_failure(&mut self, url: &Url) {
+        let health = self.mirrors.entry(mirror_key(url)).or_default();
+        health.failures += 1;
+        health.last_integrity_failure = Some(unix_now());
+    }
+
+    /// Add what another cache recorded, e.g. for fetches that ran in
+    /// parallel. Its latency counts as one new sample.
+    pub fn merge(&mut self, other: &HealthCache) {
+        for (key, theirs) in &other.mirrors {
+            let ours = self.mirrors.entry(key.clone()).or_default();
+            ours.successes += theirs.successes;
+            ours.failures += theirs.failures;
+            if let Some(sample) = theirs.latency_ms {
+                ours.latency_ms = Some(match ours.latency_ms {
+                    Some(avg) => avg + LATENCY_SMOOTHING * (sample - avg),
+                    None => sample,
+                });
+            }
+            ours.last_integrity_failure = ours
+                .last_integrity_failure
+                .max(theirs.last_integrity_failure);
+        }
+    }
+
+    /// Sort URLs so the healthiest mirrors come first.
+    /// The sort is stable, so URLs with equal scores keep their order.
+    pub fn rank(&self, mut urls: Vec<Url>) -> Vec<Url> {
+        let now = unix_now();
+        let default = MirrorHealth::default();
+        urls.sort_by(|a, b| {
+            let a_score = self.get(a).unwrap_or(&default).score(now);
+            let b_score = self.get(b).unwrap_or(&default).score(now);
+            b_score.total_cmp(&a_score)
+        });
+        urls
+    }
+}
+
+/// Mirrors are identified by origin (scheme, host and port)
+fn mirror_key(url: &Url) -> String {
+    url.origin().ascii_serialization()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_rank_prefers_reliable_and_fast_mirrors() {
+        let mut health = HealthCache::default();
+        let flaky = url("https://flaky.example.com/a");
+        let slow = url("https://slow.example.com/a");
+        let fast = url("https://fast.example.com/a");
+
+        health.record_failure(&flaky);
+        health.record_failure(&flaky);
+        health.record_success(&slow, Duration::from_secs(5));
+        health.record_success(&fast, Duration::from_millis(50));
+
+        let ranked = health.rank(vec![flaky.clone(), slow.clone(), fast.clone()]);
+        assert_eq!(ranked, vec![fast, slow, flaky]);
+    }
+
+    #[test]
+    fn test_rank_puts_dishonest_mirrors_last() {
+        let mut health = HealthCache::default();
+        let dishonest = url("https://dishonest.example.com/a");
+        let unknown = url("https://unknown.example.com/a");
+
+        for _ in 0..10 {
+            health.record_success(&dishonest, Duration::from_millis(10));
+        }
+        health.record_integrity_failure(&dishonest);
+
+        let ranked = health.rank(vec![dishonest.clone(), unknown.clone()]);
+        assert_eq!(ranked, vec![unknown, dishonest]);
+    }
+
+    #[test]
+    fn test_rank_is_stable_for_unknown_mirrors() {
+        let health = HealthCache::default();
+        let urls = vec![
+            url("https://b.example.com/"),
+            url("https://a.example.com/"),
+            url("https://c.example.com/"),
+        ];
+        assert_eq!(health.rank(urls.clone()), urls);
+    }
+
+    #[test]
+    fn test_mirrors_are_keyed_by_origin() {
+        let mut health = HealthCache::default();
+        health.record_failure(&url("https://example.com/a"));
+        health.record_failure(&url("https://example.com/b"));
+
+        let mirror = health.get(&url("https://example.com/c")).unwrap();
+        assert_eq!(mirror.failures, 2);
+        assert!(health.get(&url("http://example.com/a")).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir()
+            .join(format!("magnetize-health-test-{}", std::process::id()))
+            .join("mirrors.json");
+
+        let mut health = HealthCache::default();
+        health.record_success(&url("https://example.com/"), Duration::from_millis(100));
+        health.save(&path).unwrap();
+
+        assert_eq!(HealthCache::load(&path), health);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_file_is_empty() {
+        let health = HealthCache::load(Path::new("/nonexistent/magnetize/mirrors.json"));
+        assert!(health.mirrors.is_empty());
+    }
+}
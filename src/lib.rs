@@ -1,8 +1,27 @@
+pub mod api;
+pub mod cache;
+pub mod car;
+pub mod chunk;
 pub mod cid;
 pub mod cli;
+pub mod config;
+pub mod drisl;
 pub mod error;
+pub mod file;
+pub mod fsck;
+pub mod gc;
+pub mod health;
 pub mod magnet;
+pub mod metrics;
+pub mod mirror;
+pub mod pins;
+pub mod progress;
+pub mod quota;
+pub mod ratelimit;
 pub mod request;
 pub mod server;
+pub mod store;
+pub mod tls;
+pub mod torrent;
 pub mod url;
 mod util;
@@ -2,7 +2,11 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::cid::{self, Cid};
-use crate::url::{Url, into_btmh_urn_str, parse_btmh_urn_str, parse_cid_urn_str};
+use crate::file::sanitize_file_name;
+use crate::url::{
+    Url, into_base_url, into_btih_urn_str, into_btmh_urn_str, parse_btih_urn_str,
+    parse_btmh_urn_str, parse_cid_urn_str,
+};
 use crate::util::group;
 use std::result;
 
@@ -15,8 +19,14 @@ pub struct MagnetLink {
     pub rs: Vec<Url>,
     /// Web Seed (HTTP URL for the data)
     pub ws: Vec<Url>,
-    /// BitTorrent infohash
+    /// IPFS trustless gateways, which serve the data as a raw block at
+    /// `/ipfs/{cid}?format=raw`.
+    /// See <https://specs.ipfs.tech/http-gateways/trustless-gateway/>
+    pub gw: Vec<Url>,
+    /// BitTorrent v2 infohash (sha256 multihash)
     pub btmh: Option<String>,
+    /// BitTorrent v1 infohash (SHA-1)
+    pub btih: Option<String>,
     /// Display Name (file name hint)
     pub dn: Option<String>,
 }
@@ -28,7 +38,9 @@ impl MagnetLink {
             cid,
             rs: Vec::new(),
             ws: Vec::new(),
+            gw: Vec::new(),
             btmh: None,
+            btih: None,
             dn: None,
         }
     }
@@ -53,14 +65,21 @@ impl MagnetLink {
 
         let btmh = xts.iter().find_map(|xt| parse_btmh_urn_str(xt).ok());
 
+        let btih = xts.iter().find_map(|xt| parse_btih_urn_str(xt).ok());
+
         let rs = query
             .get("rs")
-            .map(|v| v.into_iter().filter_map(|s| Url::parse(s).ok()).collect())
+            .map(|v| v.iter().filter_map(|s| Url::parse(s).ok()).collect())
             .unwrap_or(Vec::new());
 
         let ws = query
             .get("ws")
-            .map(|v| v.into_iter().filter_map(|s| Url::parse(s).ok()).collect())
+            .map(|v| v.iter().filter_map(|s| Url::parse(s).ok()).collect())
+            .unwrap_or(Vec::new());
+
+        let gw = query
+            .get("gw")
+            .map(|v| v.iter().filter_map(|s| Url::parse(s).ok()).collect())
             .unwrap_or(Vec::new());
 
         let dn = query
@@ -72,22 +91,74 @@ impl MagnetLink {
             cid,
             rs,
             ws,
+            gw,
             btmh,
+            btih,
             dn,
         })
     }
 
+    /// Add fallback RASL hosts and gateways after the link's own, like the
+    /// default mirrors of `mag get`. Hosts the link already has are skipped.
+    pub fn add_fallbacks(&mut self, rs: &[Url], gw: &[Url]) {
+        for (hosts, fallbacks) in [(&mut self.rs, rs), (&mut self.gw, gw)] {
+            for url in fallbacks {
+                let base = into_base_url(url.clone());
+                if !hosts.iter().any(|host| into_base_url(host.clone()) == base) {
+                    hosts.push(url.clone());
+                }
+            }
+        }
+    }
+
     /// Returns a vec of all the URLS that you can hit to download the file.
     pub fn urls(&self) -> Vec<Url> {
-        let cid_string = self.cid.to_string();
+        let (rasl_urls, gw_urls) = self.host_urls(&self.cid);
+        rasl_urls
+            .into_iter()
+            .chain(self.ws.clone())
+            .chain(gw_urls)
+            .collect()
+    }
+
+    /// URLs to download another CID from the same hosts, like the chunks of
+    /// a chunked file. Web seeds only serve the file itself, so only RASL
+    /// hosts and gateways are used.
+    pub fn urls_for(&self, cid: &Cid) -> Vec<Url> {
+        let (rasl_urls, gw_urls) = self.host_urls(cid);
+        rasl_urls.into_iter().chain(gw_urls).collect()
+    }
+
+    /// URLs for a CID on the RASL hosts, and on the gateways
+    fn host_urls(&self, cid: &Cid) -> (Vec<Url>, Vec<Url>) {
+        let cid_string = cid.to_string();
         // Join CID to the end of RASL URLs
         let rasl_urls = self
             .rs
             .iter()
             .filter_map(|url| into_rasl_url(url).ok())
-            .filter_map(|rasl_url| rasl_url.join(&cid_string).ok());
-        let ws_urls = self.ws.clone().into_iter();
-        rasl_urls.chain(ws_urls).collect()
+            .filter_map(|rasl_url| rasl_url.join(&cid_string).ok())
+            .collect();
+        // Ask gateways for the raw block, which is the data itself
+        let gw_urls = self
+            .gw
+            .iter()
+            .filter_map(|url| {
+                into_base_url(url.clone())
+                    .join(&format!("ipfs/{}?format=raw", cid_string))
+                    .ok()
+            })
+            .collect();
+        (rasl_urls, gw_urls)
+    }
+
+    /// A safe file name for the data.
+    /// Uses the display name if it survives sanitization, otherwise the CID.
+    pub fn file_name(&self) -> String {
+        self.dn
+            .as_deref()
+            .and_then(sanitize_file_name)
+            .unwrap_or_else(|| self.cid.to_string())
     }
 }
 
@@ -100,12 +171,16 @@ impl From<&MagnetLink> for Url {
 
             let cid_urn =
                 Url::try_from(&magnet.cid).expect("Should be able to construct URL from cid");
-            query.append_pair("xt", &cid_urn.to_string());
+            query.append_pair("xt", cid_urn.as_ref());
 
             if let Some(btmh) = &magnet.btmh {
                 query.append_pair("xt", into_btmh_urn_str(btmh).as_str());
             }
 
+            if let Some(btih) = &magnet.btih {
+                query.append_pair("xt", into_btih_urn_str(btih).as_str());
+            }
+
             if let Some(dn) = &magnet.dn {
                 query.append_pair("dn", dn);
             }
@@ -117,15 +192,19 @@ impl From<&MagnetLink> for Url {
             for value in magnet.ws.iter() {
                 query.append_pair("ws", value.as_str());
             }
+
+            for value in magnet.gw.iter() {
+                query.append_pair("gw", value.as_str());
+            }
         }
 
         url
     }
 }
 
-impl ToString for MagnetLink {
-    fn to_string(&self) -> String {
-        Url::from(self).to_string()
+impl std::fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", Url::from(self))
     }
 }
 
@@ -158,7 +237,7 @@ impl From<cid::CidError> for Error {
 /// See <https://dasl.ing/rasl.html>.
 fn into_rasl_url(url: &Url) -> Result<Url, Error> {
     let authority = url.authority();
-    if authority == "" {
+    if authority.is_empty() {
         return Err(Error::InvalidRaslEndpoint(format!(
             "URL has no authority: {}",
             url
@@ -193,6 +272,42 @@ mod tests {
         assert_eq!(result.dn, Some("example_file".to_string()));
     }
 
+    #[test]
+    fn test_parse_hybrid_magnet_link() {
+        let magnet_link = "magnet:?xt=urn:cid:bafkreiayssqzzbn2cu5mx52dvrheh7aajsermbfsn6ggtypih2rk7r6er4&xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e";
+        let result = MagnetLink::parse(magnet_link).unwrap();
+
+        assert_eq!(
+            result.btih,
+            Some("c12fe1c06bba254a9dc9f519b335aa7c1367a88a".to_string())
+        );
+        assert_eq!(
+            result.btmh,
+            Some(
+                "1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e".to_string()
+            )
+        );
+
+        // Roundtrip
+        let parsed = MagnetLink::parse(&result.to_string()).unwrap();
+        assert_eq!(parsed, result);
+    }
+
+    #[test]
+    fn test_add_fallbacks() {
+        let mut mag = MagnetLink::parse(
+            "magnet:?xt=urn:cid:bafkreiayssqzzbn2cu5mx52dvrheh7aajsermbfsn6ggtypih2rk7r6er4&rs=https://a.example",
+        )
+        .unwrap();
+        let a = Url::parse("https://a.example/").unwrap();
+        let b = Url::parse("https://b.example/").unwrap();
+        let gw = Url::parse("https://ipfs.io/").unwrap();
+        mag.add_fallbacks(&[a, b.clone()], std::slice::from_ref(&gw));
+
+        assert_eq!(mag.rs, vec![Url::parse("https://a.example").unwrap(), b]);
+        assert_eq!(mag.gw, vec![gw]);
+    }
+
     #[test]
     fn test_parse_minimal_magnet_link() {
         let magnet_link =
@@ -246,7 +361,9 @@ mod tests {
             cid: Cid::parse("bafkreiayssqzzbn2cu5mx52dvrheh7aajsermbfsn6ggtypih2rk7r6er4").unwrap(),
             rs: Vec::new(),
             ws: vec![Url::parse("https://example.com/file.txt").unwrap()],
+            gw: Vec::new(),
             btmh: Some("d41d8cd98f00b204e9800998ecf8427e".to_string()),
+            btih: None,
             dn: Some("example_file".to_string()),
         };
 
@@ -264,7 +381,9 @@ mod tests {
             cid: Cid::parse("bafkreiayssqzzbn2cu5mx52dvrheh7aajsermbfsn6ggtypih2rk7r6er4").unwrap(),
             rs: Vec::new(),
             ws: vec![],
+            gw: vec![],
             btmh: None,
+            btih: None,
             dn: None,
         };
 
@@ -283,6 +402,26 @@ mod tests {
         assert!(parsed.ws.is_empty());
     }
 
+    #[test]
+    fn test_file_name() {
+        let mut magnet_link = MagnetLink::new(
+            Cid::parse("bafkreiayssqzzbn2cu5mx52dvrheh7aajsermbfsn6ggtypih2rk7r6er4").unwrap(),
+        );
+        assert_eq!(
+            magnet_link.file_name(),
+            "bafkreiayssqzzbn2cu5mx52dvrheh7aajsermbfsn6ggtypih2rk7r6er4"
+        );
+
+        magnet_link.dn = Some("../secret/report.pdf".to_string());
+        assert_eq!(magnet_link.file_name(), "report.pdf");
+
+        magnet_link.dn = Some("..".to_string());
+        assert_eq!(
+            magnet_link.file_name(),
+            "bafkreiayssqzzbn2cu5mx52dvrheh7aajsermbfsn6ggtypih2rk7r6er4"
+        );
+    }
+
     #[test]
     fn test_urls_method() {
         let cid_str = "bafkreiayssqzzbn2cu5mx52dvrheh7aajsermbfsn6ggtypih2rk7r6er4";
@@ -296,7 +435,9 @@ mod tests {
                 Url::parse("https://direct1.example.com/file.txt").unwrap(),
                 Url::parse("https://direct2.example.com/another-file.txt").unwrap(),
             ],
+            gw: Vec::new(),
             btmh: None,
+            btih: None,
             dn: None,
         };
 
@@ -331,4 +472,59 @@ mod tests {
             urls.contains(&Url::parse("https://direct2.example.com/another-file.txt").unwrap())
         );
     }
+
+    #[test]
+    fn test_gateway_urls() {
+        let cid_str = "bafkreiayssqzzbn2cu5mx52dvrheh7aajsermbfsn6ggtypih2rk7r6er4";
+        let magnet_link = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:cid:{}&gw=https://ipfs.example.com&gw=http://127.0.0.1:8080/gateway",
+            cid_str
+        ))
+        .unwrap();
+        assert_eq!(magnet_link.gw.len(), 2);
+        assert_eq!(
+            magnet_link.urls(),
+            vec![
+                Url::parse(&format!(
+                    "https://ipfs.example.com/ipfs/{}?format=raw",
+                    cid_str
+                ))
+                .unwrap(),
+                Url::parse(&format!(
+                    "http://127.0.0.1:8080/gateway/ipfs/{}?format=raw",
+                    cid_str
+                ))
+                .unwrap(),
+            ]
+        );
+
+        // Roundtrip
+        let parsed = MagnetLink::parse(&magnet_link.to_string()).unwrap();
+        assert_eq!(parsed, magnet_link);
+    }
+
+    #[test]
+    fn test_urls_for_other_cids() {
+        let magnet_link = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:cid:{}&rs=https://rasl.example.com&ws=https://example.com/file&gw=https://ipfs.example.com",
+            Cid::of(b"root")
+        ))
+        .unwrap();
+        let chunk = Cid::of(b"chunk");
+        assert_eq!(
+            magnet_link.urls_for(&chunk),
+            vec![
+                Url::parse(&format!(
+                    "https://rasl.example.com/.well-known/rasl/{}",
+                    chunk
+                ))
+                .unwrap(),
+                Url::parse(&format!(
+                    "https://ipfs.example.com/ipfs/{}?format=raw",
+                    chunk
+                ))
+                .unwrap(),
+            ]
+        );
+    }
 }
@@ -0,0 +1,287 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the request latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Where an integrity failure was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    /// A stored blob didn't match its CID while it was being served
+    Read,
+    /// A stored blob didn't match its CID when the store was scrubbed
+    Scrub,
+    /// An uploaded blob didn't match the CID it was uploaded as
+    Upload,
+}
+
+impl IntegrityCheck {
+    fn label(self) -> &'static str {
+        match self {
+            IntegrityCheck::Read => "read",
+            IntegrityCheck::Scrub => "scrub",
+            IntegrityCheck::Upload => "upload",
+        }
+    }
+}
+
+/// Requests with the same method, route and status
+#[derive(Debug, Clone, Default)]
+struct RequestStats {
+    count: u64,
+    /// Number of requests in each latency bucket, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+}
+
+/// Counters for the Prometheus metrics of `mag serve`.
+/// Gauges that describe the store are passed in when rendering, since the
+/// server already keeps track of them.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, String, u16), RequestStats>>,
+    bytes_served: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    integrity_failures: [AtomicU64; 3],
+    gateway_hits: AtomicU64,
+    gateway_misses: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a finished request. `route` is the route pattern, like `/{cid}`,
+    /// rather than the path, so the number of series stays bounded.
+    pub fn record_request(&self, method: &str, route: &str, status: u16, latency: Duration) {
+        let latency = latency.as_secs_f64();
+        let mut requests = self.requests.lock().expect("Metrics lock poisoned");
+        let stats = requests
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default();
+        stats.count += 1;
+        stats.latency_sum += latency;
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| latency <= *le) {
+            stats.buckets[bucket] += 1;
+        }
+    }
+
+    pub fn add_bytes_served(&self, bytes: u64) {
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_uploaded(&self, bytes: u64) {
+        self.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_integrity_failures(&self, check: IntegrityCheck, failures: u64) {
+        self.integrity_failures[check as usize].fetch_add(failures, Ordering::Relaxed);
+    }
+
+    /// Count a trustless gateway request, by whether the store had the block
+    pub fn add_gateway_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.gateway_hits
+        } else {
+            &self.gateway_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    /// See <https://prometheus.io/docs/instrumenting/exposition_formats/>
+    pub fn render(&self, store_blobs: u64, store_size: u64) -> String {
+        let mut out = String::new();
+        let requests = self.requests.lock().expect("Metrics lock poisoned");
+
+        header(
+            &mut out,
+            "magnetize_http_requests_total",
+            "counter",
+            "HTTP requests by method, route and status",
+        );
+        for ((method, route, status), stats) in requests.iter() {
+            let labels = request_labels(method, route, *status);
+            let _ = writeln!(
+                out,
+                "magnetize_http_requests_total{{{}}} {}",
+                labels, stats.count
+            );
+        }
+
+        header(
+            &mut out,
+            "magnetize_http_request_duration_seconds",
+            "histogram",
+            "Time to respond to HTTP requests, up to the response headers",
+        );
+        for ((method, route, status), stats) in requests.iter() {
+            let labels = request_labels(method, route, *status);
+            let mut cumulative = 0;
+            for (le, count) in LATENCY_BUCKETS.iter().zip(stats.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "magnetize_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "magnetize_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, stats.count
+            );
+            let _ = writeln!(
+                out,
+                "magnetize_http_request_duration_seconds_sum{{{}}} {}",
+                labels, stats.latency_sum
+            );
+            let _ = writeln!(
+                out,
+                "magnetize_http_request_duration_seconds_count{{{}}} {}",
+                labels, stats.count
+            );
+        }
+        drop(requests);
+
+        let counters = [
+            (
+                "magnetize_bytes_served_total",
+                "Bytes of content served",
+                &self.bytes_served,
+            ),
+            (
+                "magnetize_bytes_uploaded_total",
+                "Bytes of content uploaded and stored",
+                &self.bytes_uploaded,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        header(
+            &mut out,
+            "magnetize_integrity_failures_total",
+            "counter",
+            "Blobs that didn't match their CID, by where the mismatch was found",
+        );
+        for check in [
+            IntegrityCheck::Read,
+            IntegrityCheck::Scrub,
+            IntegrityCheck::Upload,
+        ] {
+            let _ = writeln!(
+                out,
+                "magnetize_integrity_failures_total{{check=\"{}\"}} {}",
+                check.label(),
+                self.integrity_failures[check as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "magnetize_gateway_cache_requests_total",
+            "counter",
+            "Trustless gateway requests, by whether the block was in the store",
+        );
+        for (result, counter) in [("hit", &self.gateway_hits), ("miss", &self.gateway_misses)] {
+            let _ = writeln!(
+                out,
+                "magnetize_gateway_cache_requests_total{{result=\"{}\"}} {}",
+                result,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let gauges = [
+            (
+                "magnetize_store_blobs",
+                "Number of blobs in the store",
+                store_blobs,
+            ),
+            (
+                "magnetize_store_size_bytes",
+                "Total size of the blobs in the store",
+                store_size,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn request_labels(method: &str, route: &str, status: u16) -> String {
+    format!(
+        "method=\"{}\",route=\"{}\",status=\"{}\"",
+        escape_label(method),
+        escape_label(route),
+        status
+    )
+}
+
+/// Escape a label value for the text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_request("GET", "/{cid}", 200, Duration::from_millis(3));
+        metrics.record_request("GET", "/{cid}", 200, Duration::from_millis(300));
+        metrics.record_request("GET", "/{cid}", 404, Duration::from_secs(60));
+        metrics.add_bytes_served(11);
+        metrics.add_integrity_failures(IntegrityCheck::Scrub, 2);
+        metrics.add_gateway_lookup(true);
+
+        let text = metrics.render(5, 1234);
+        let labels = r#"method="GET",route="/{cid}",status="200""#;
+        for line in [
+            format!("magnetize_http_requests_total{{{}}} 2", labels),
+            format!("magnetize_http_request_duration_seconds_bucket{{{},le=\"0.005\"}} 1", labels),
+            format!("magnetize_http_request_duration_seconds_bucket{{{},le=\"0.25\"}} 1", labels),
+            format!("magnetize_http_request_duration_seconds_bucket{{{},le=\"0.5\"}} 2", labels),
+            format!("magnetize_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2", labels),
+            r#"magnetize_http_request_duration_seconds_bucket{method="GET",route="/{cid}",status="404",le="10"} 0"#.to_string(),
+            r#"magnetize_http_request_duration_seconds_bucket{method="GET",route="/{cid}",status="404",le="+Inf"} 1"#.to_string(),
+            "magnetize_bytes_served_total 11".to_string(),
+            "magnetize_bytes_uploaded_total 0".to_string(),
+            r#"magnetize_integrity_failures_total{check="scrub"} 2"#.to_string(),
+            r#"magnetize_gateway_cache_requests_total{result="hit"} 1"#.to_string(),
+            r#"magnetize_gateway_cache_requests_total{result="miss"} 0"#.to_string(),
+            "magnetize_store_blobs 5".to_string(),
+            "magnetize_store_size_bytes 1234".to_string(),
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+}
@@ -0,0 +1,464 @@
+use crate::cid::Cid;
+use crate::file::write_atomic;
+use crate::request::{
+    Client, Progress, PushOutcome, RequestError, RetryPolicy, StatusCode, Throttle, download_cid,
+    has_cids, head_cid, list_blobs, push_cid, with_retry,
+};
+use crate::url::Url;
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Configuration for copying blobs from one magnetize server to another
+#[derive(Debug, Clone)]
+pub struct MirrorConfig {
+    /// Maximum number of blobs copied at the same time
+    pub concurrency: usize,
+    /// How to retry transient failures of each download and upload
+    pub retry: RetryPolicy,
+    /// Bearer token for the destination server
+    pub token: Option<String>,
+    /// Directory to hold blobs between download and upload
+    pub temp_dir: PathBuf,
+    /// Observer for transfer progress
+    pub progress: Progress,
+    /// Limit on the bandwidth of all downloads and uploads together
+    pub throttle: Throttle,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            retry: RetryPolicy::default(),
+            token: None,
+            temp_dir: std::env::temp_dir(),
+            progress: Progress::default(),
+            throttle: Throttle::default(),
+        }
+    }
+}
+
+/// What happened when mirroring a blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorOutcome {
+    /// The destination already had the blob
+    AlreadyPresent,
+    /// The blob was copied to the destination
+    Copied { size: u64 },
+}
+
+/// Tally of mirrored blobs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MirrorSummary {
+    pub copied: u64,
+    pub already_present: u64,
+    pub failed: u64,
+}
+
+impl MirrorSummary {
+    fn record(&mut self, result: &Result<MirrorOutcome, RequestError>) {
+        match result {
+            Ok(MirrorOutcome::AlreadyPresent) => self.already_present += 1,
+            Ok(MirrorOutcome::Copied { .. }) => self.copied += 1,
+            Err(_) => self.failed += 1,
+        }
+    }
+
+    fn add(&mut self, other: MirrorSummary) {
+        self.copied += other.copied;
+        self.already_present += other.already_present;
+        self.failed += other.failed;
+    }
+}
+
+/// How far before the start of the last complete run the next run lists
+/// blobs from. Covers blobs that were being moved into place while the
+/// source was listing, and the clock's resolution. Blobs that were already
+/// mirrored are skipped cheaply.
+const SINCE_MARGIN_SECS: u64 = 5 * 60;
+
+/// Progress of `mirror_all`, saved after every page of the listing so an
+/// interrupted mirror can pick up where it left off, and after a complete
+/// mirror so the next one only has to look at blobs added since.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct MirrorState {
+    from: Url,
+    to: Url,
+    /// Every blob added before this Unix timestamp has been mirrored
+    #[serde(default)]
+    since: Option<u64>,
+    /// When the source started listing for the current run
+    #[serde(default)]
+    started_at: Option<u64>,
+    /// Every blob in the current run's listing up to and including this
+    /// cursor has been mirrored
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+/// Copy a blob from one server to another.
+///
+/// The blob is downloaded to a temp file with an integrity check, then
+/// uploaded to the destination, which verifies it again. Nothing is
+/// downloaded if the destination already has the blob.
+pub async fn mirror_cid(
+    client: &Client,
+    from: &Url,
+    to: &Url,
+    cid: &Cid,
+    config: &MirrorConfig,
+) -> Result<MirrorOutcome, RequestError> {
+    let existing = head_cid(client, to, cid).await?;
+    if existing.status() == StatusCode::OK {
+        return Ok(MirrorOutcome::AlreadyPresent);
+    }
+
+    let url = from.join(&cid.to_string())?;
+    let nonce: u64 = rand::random();
+    let temp_path = config
+        .temp_dir
+        .join(format!("magnetize-mirror-{}.{:016x}.tmp", cid, nonce));
+
+    let result = copy_via(client, &url, to, cid, &temp_path, config).await;
+    let _ = tokio::fs::remove_file(&temp_path).await;
+    result
+}
+
+async fn copy_via(
+    client: &Client,
+    url: &Url,
+    to: &Url,
+    cid: &Cid,
+    temp_path: &Path,
+    config: &MirrorConfig,
+) -> Result<MirrorOutcome, RequestError> {
+    let size = with_retry(url, &config.retry, &config.progress, || {
+        download_cid(
+            client,
+            url,
+            cid,
+            temp_path,
+            &config.progress,
+            &config.throttle,
+        )
+    })
+    .await?;
+
+    let token = config.token.as_deref();
+    let outcome = with_retry(to, &config.retry, &config.progress, || {
+        push_cid(
+            client,
+            to,
+            cid,
+            temp_path,
+            token,
+            &config.progress,
+            &config.throttle,
+        )
+    })
+    .await?;
+
+    Ok(match outcome {
+        PushOutcome::AlreadyPresent => MirrorOutcome::AlreadyPresent,
+        PushOutcome::Uploaded => MirrorOutcome::Copied { size },
+    })
+}
+
+/// Copy blobs from one server to another, up to `config.concurrency` at a
+/// time. `report` is called with the outcome for each blob as it finishes.
+pub async fn mirror_cids(
+    client: &Client,
+    from: &Url,
+    to: &Url,
+    cids: impl IntoIterator<Item = Cid>,
+    config: &MirrorConfig,
+    report: &mut impl FnMut(&Cid, &Result<MirrorOutcome, RequestError>),
+) -> MirrorSummary {
+    let cids: Vec<Cid> = cids.into_iter().collect();
+    let mut summary = MirrorSummary::default();
+
+    // Find out what the destination already has in one go. Servers without
+    // the bulk check still work, since each blob is checked before copying.
+    let cids = match has_cids(client, to, &cids).await {
+        Ok(has) => {
+            for blob in has.present {
+                let result = Ok(MirrorOutcome::AlreadyPresent);
+                summary.record(&result);
+                report(&blob.cid, &result);
+            }
+            has.missing
+        }
+        Err(err) => {
+            tracing::debug!(%to, "bulk existence check failed: {}", err);
+            cids
+        }
+    };
+
+    let mut results = stream::iter(cids)
+        .map(|cid| async move { (cid, mirror_cid(client, from, to, &cid, config).await) })
+        .buffer_unordered(config.concurrency.max(1));
+
+    while let Some((cid, result)) = results.next().await {
+        summary.record(&result);
+        report(&cid, &result);
+    }
+    summary
+}
+
+/// Copy every blob the source server lists to the destination.
+/// The source must have listing enabled.
+///
+/// If `state_path` is given, the position in the listing is saved there
+/// after every page in which all blobs were mirrored, and a later run with
+/// the same source and destination resumes from it. Once everything has been
+/// mirrored, the state records when the run started, and later runs only
+/// list blobs added since shortly before then.
+pub async fn mirror_all(
+    client: &Client,
+    from: &Url,
+    to: &Url,
+    config: &MirrorConfig,
+    state_path: Option<&Path>,
+    report: &mut impl FnMut(&Cid, &Result<MirrorOutcome, RequestError>),
+) -> Result<MirrorSummary, RequestError> {
+    let mut state = state_path
+        .and_then(|path| load_state(path, from, to))
+        .unwrap_or_else(|| MirrorState {
+            from: from.clone(),
+            to: to.clone(),
+            since: None,
+            started_at: None,
+            cursor: None,
+        });
+    let mut summary = MirrorSummary::default();
+
+    loop {
+        let page = with_retry(from, &config.retry, &config.progress, || {
+            list_blobs(client, from, state.cursor.as_deref(), state.since, None)
+        })
+        .await?;
+        state.started_at.get_or_insert(page.listed_at);
+
+        let cids = page.blobs.into_iter().map(|blob| blob.cid);
+        let page_summary = mirror_cids(client, from, to, cids, config, report).await;
+        summary.add(page_summary);
+
+        let Some(next) = page.cursor else {
+            break;
+        };
+        state.cursor = Some(next);
+        // Only move the saved position past blobs that were all mirrored,
+        // so failed blobs are retried when the mirror is resumed
+        if let Some(path) = state_path
+            && summary.failed == 0
+        {
+            save_state(path, &state)?;
+        }
+    }
+
+    if let Some(path) = state_path
+        && summary.failed == 0
+    {
+        state.since = state
+            .started_at
+            .take()
+            .map(|started_at| started_at.saturating_sub(SINCE_MARGIN_SECS));
+        state.cursor = None;
+        save_state(path, &state)?;
+    }
+
+    Ok(summary)
+}
+
+/// Load the saved state of an earlier mirror between the same servers
+fn load_state(path: &Path, from: &Url, to: &Url) -> Option<MirrorState> {
+    let bytes = fs::read(path).ok()?;
+    let state: MirrorState = serde_json::from_slice(&bytes).ok()?;
+    (state.from == *from && state.to == *to).then_some(state)
+}
+
+fn save_state(path: &Path, state: &MirrorState) -> io::Result<()> {
+    write_atomic(path, &serde_json::to_vec(state)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{BlobInfo, BlobList};
+    use crate::server::{TOKEN, count_files, test_server};
+    use axum::Router;
+    use axum::extract::{Path as UrlPath, Query, State};
+    use axum::routing::get;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// Serve `blobs` as a source server that lists them one per page, in
+    /// the order given. Blobs are served with the given content, whether or
+    /// not it matches the CID.
+    async fn paged_source(blobs: Vec<(Cid, &'static str)>) -> Url {
+        async fn list(
+            State(blobs): State<Arc<Vec<(Cid, &'static str)>>>,
+            Query(query): Query<HashMap<String, String>>,
+        ) -> axum::Json<BlobList> {
+            let start = match query.get("cursor") {
+                Some(cursor) => blobs
+                    .iter()
+                    .position(|(cid, _)| cid.to_string() == *cursor)
+                    .map_or(blobs.len(), |i| i + 1),
+                None => 0,
+            };
+            let page: Vec<BlobInfo> = blobs[start..]
+                .iter()
+                .take(1)
+                .map(|(cid, content)| BlobInfo {
+                    cid: *cid,
+                    size: content.len() as u64,
+                    added: 0,
+                })
+                .collect();
+            let cursor = page.last().map(|blob| blob.cid.to_string());
+            axum::Json(BlobList {
+                blobs: page,
+                cursor,
+                listed_at: 1_000,
+            })
+        }
+
+        async fn blob(
+            State(blobs): State<Arc<Vec<(Cid, &'static str)>>>,
+            UrlPath(cid): UrlPath<String>,
+        ) -> &'static str {
+            blobs
+                .iter()
+                .find(|(c, _)| c.to_string() == cid)
+                .map_or("", |(_, content)| *content)
+        }
+
+        let app = Router::new()
+            .route("/api/v1/blobs", get(list))
+            .route("/{cid}", get(blob))
+            .with_state(Arc::new(blobs));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Url::parse(&format!("http://{}/", addr)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mirror_all() {
+        let (from, from_store) = test_server("mirror-from").await;
+        let (to, to_store) = test_server("mirror-to").await;
+        let cids: Vec<Cid> = ["a", "bb", "ccc"].iter().map(Cid::of).collect();
+        for (cid, content) in cids.iter().zip(["a", "bb", "ccc"]) {
+            fs::write(from_store.path(cid), content).unwrap();
+        }
+        // One blob was mirrored by an earlier run
+        fs::write(to_store.path(&cids[1]), "bb").unwrap();
+
+        let config = MirrorConfig {
+            token: Some(TOKEN.to_string()),
+            temp_dir: to_store.dir().to_path_buf(),
+            ..MirrorConfig::default()
+        };
+        let state = from_store.dir().join("state.json");
+        let client = reqwest::Client::new();
+
+        let summary = mirror_all(&client, &from, &to, &config, Some(&state), &mut |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(summary.copied, 2);
+        assert_eq!(summary.already_present, 1);
+        assert_eq!(summary.failed, 0);
+        for cid in &cids {
+            assert!(to_store.contains(cid));
+        }
+        // No temp files are left behind
+        assert_eq!(count_files(to_store.dir()), 3);
+
+        // The next run only looks at blobs added since this one started
+        let saved: serde_json::Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+        assert!(saved["since"].is_u64());
+        assert!(saved["cursor"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_all_resumes_from_state() {
+        let (from, from_store) = test_server("resume-from").await;
+        let (to, to_store) = test_server("resume-to").await;
+        let mut cids: Vec<String> = Vec::new();
+        for content in ["a", "bb", "ccc"] {
+            let cid = Cid::of(content);
+            fs::write(from_store.path(&cid), content).unwrap();
+            cids.push(cid.to_string());
+        }
+        cids.sort();
+
+        // An earlier run got through the first blob in the listing
+        let state = from_store.dir().join("state.json");
+        let saved = serde_json::json!({ "from": from, "to": to, "cursor": cids[0] });
+        fs::write(&state, saved.to_string()).unwrap();
+
+        let config = MirrorConfig {
+            token: Some(TOKEN.to_string()),
+            temp_dir: to_store.dir().to_path_buf(),
+            ..MirrorConfig::default()
+        };
+        let summary = mirror_all(
+            &reqwest::Client::new(),
+            &from,
+            &to,
+            &config,
+            Some(&state),
+            &mut |_, _| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(summary.copied, 2);
+        assert!(!to_store.contains(&Cid::parse(&cids[0]).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_mirror_all_keeps_position_when_a_blob_fails() {
+        let mut blobs: Vec<(Cid, &str)> = ["a", "bb", "ccc"]
+            .into_iter()
+            .map(|content| (Cid::of(content), content))
+            .collect();
+        blobs.sort_by_key(|(cid, _)| cid.to_string());
+        // The second blob in the listing has rotted on the source
+        blobs[1].1 = "rotted";
+        let cids: Vec<Cid> = blobs.iter().map(|(cid, _)| *cid).collect();
+        let from = paged_source(blobs).await;
+        let (to, to_store) = test_server("mirror-fails-to").await;
+
+        let config = MirrorConfig {
+            retry: RetryPolicy::none(),
+            token: Some(TOKEN.to_string()),
+            temp_dir: to_store.dir().to_path_buf(),
+            ..MirrorConfig::default()
+        };
+        let state = to_store.dir().join("state.json");
+        let summary = mirror_all(
+            &reqwest::Client::new(),
+            &from,
+            &to,
+            &config,
+            Some(&state),
+            &mut |_, _| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.copied, 2);
+        assert_eq!(summary.failed, 1);
+        assert!(!to_store.contains(&cids[1]));
+
+        // The saved position is the last page before the failed blob, and
+        // the next run still lists everything
+        let saved: serde_json::Value = serde_json::from_slice(&fs::read(&state).unwrap()).unwrap();
+        assert_eq!(saved["cursor"], cids[0].to_string());
+        assert!(saved["since"].is_null());
+    }
+}
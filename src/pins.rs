@@ -0,0 +1,193 @@
+use crate::cid::{Cid, Codec};
+use crate::drisl;
+use crate::file::write_atomic;
+use crate::store::Store;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Named pins, each pointing at a CID that garbage collection must keep.
+///
+/// Pinning a DRISL record also keeps every blob it links to, transitively.
+/// Raw blobs are pinned on their own, whatever they contain.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinSet {
+    pub pins: BTreeMap<String, Cid>,
+}
+
+impl PinSet {
+    /// Where a store keeps its pins
+    pub fn path(store: &Store) -> PathBuf {
+        store.meta_dir().join("pins.json")
+    }
+
+    /// Load the pins of a store. A store without pins has an empty pin set.
+    pub fn load(store: &Store) -> io::Result<Self> {
+        match fs::read(Self::path(store)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Where a store keeps the lock on its pins
+    fn lock_path(store: &Store) -> PathBuf {
+        store.meta_dir().join("pins.lock")
+    }
+
+    /// Lock the pins of a store, waiting for any other process or thread
+    /// that holds the lock. Hold it while changing the pins, and while
+    /// deciding what is pinned and deleting what isn't, so a pin added in
+    /// between can't lose its blobs. The lock is released when dropped.
+    pub fn lock(store: &Store) -> io::Result<PinsLock> {
+        fs::create_dir_all(store.meta_dir())?;
+        let file = fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(Self::lock_path(store))?;
+        file.lock()?;
+        Ok(PinsLock(file))
+    }
+
+    /// Save the pins of a store.
+    /// Writes to a temp file and renames, so readers never see a partial file.
+    pub fn save(&self, store: &Store) -> io::Result<()> {
+        fs::create_dir_all(store.meta_dir())?;
+        write_atomic(&Self::path(store), &serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Pin a CID under a name, replacing any pin with the same name.
+    /// Returns the CID the name used to point at.
+    pub fn add(&mut self, name: &str, cid: Cid) -> Option<Cid> {
+        self.pins.insert(name.to_string(), cid)
+    }
+
+    /// Remove a pin. Returns the CID it pointed at.
+    pub fn remove(&mut self, name: &str) -> Option<Cid> {
+        self.pins.remove(name)
+    }
+
+    /// Every CID kept by a pin, including the blobs linked from pinned
+    /// records. Blobs the store doesn't have are still included, so they
+    /// stay pinned once they are uploaded.
+    pub fn pinned(&self, store: &Store) -> io::Result<HashSet<Cid>> {
+        let mut pinned = HashSet::new();
+        let mut pending: Vec<Cid> = self.pins.values().copied().collect();
+        while let Some(cid) = pending.pop() {
+            if !pinned.insert(cid) || cid.codec() != Codec::Drisl {
+                continue;
+            }
+            match fs::metadata(store.path(&cid)) {
+                Ok(metadata) if metadata.is_file() && metadata.len() <= drisl::MAX_RECORD_SIZE => {
+                    pending.extend(links(&cid, &fs::read(store.path(&cid))?));
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(pinned)
+    }
+}
+
+/// A held lock on a store's pins. See `PinSet::lock`.
+#[derive(Debug)]
+pub struct PinsLock(fs::File);
+
+impl Drop for PinsLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// The CIDs a blob links to.
+///
+/// A DRISL record links to every CID in it. Raw blobs have no links, even
+/// if their bytes happen to spell out CIDs.
+pub fn links(cid: &Cid, bytes: &[u8]) -> Vec<Cid> {
+    if cid.codec() != Codec::Drisl {
+        return Vec::new();
+    }
+    drisl::decode(bytes)
+        .map(|record| record.links())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::test_store;
+
+    fn add(store: &Store, content: &str) -> Cid {
+        let cid = Cid::of(content);
+        fs::write(store.path(&cid), content).unwrap();
+        cid
+    }
+
+    #[test]
+    fn test_links() {
+        let a = Cid::of("a");
+        let b = Cid::of("b");
+        let raw = |bytes: &[u8]| links(&Cid::of(bytes), bytes);
+        // A raw blob listing CIDs is just content
+        assert_eq!(raw(format!("{}\n{}\n", a, b).as_bytes()), vec![]);
+        assert_eq!(raw(b"hello world"), vec![]);
+
+        let record = drisl::to_vec(&(a, "b", [b])).unwrap();
+        assert_eq!(links(&drisl::cid_of(&record), &record), vec![a, b]);
+        assert_eq!(raw(&record), vec![]);
+    }
+
+    fn add_record(store: &Store, links: &[Cid]) -> Cid {
+        let record = drisl::to_vec(links).unwrap();
+        let cid = drisl::cid_of(&record);
+        fs::write(store.path(&cid), record).unwrap();
+        cid
+    }
+
+    #[test]
+    fn test_pinned_follows_records() {
+        let store = test_store("pins", "records");
+        let leaf = add(&store, "leaf");
+        let nested = add_record(&store, &[leaf]);
+        let other = add(&store, "other");
+        let record = add_record(&store, &[nested, other]);
+        let unpinned = add(&store, "unpinned");
+        let listed = add(&store, "listed");
+        let list = add(&store, &format!("{}\n", listed));
+
+        let mut pins = PinSet::default();
+        pins.add("release", record);
+        pins.add("list", list);
+        pins.save(&store).unwrap();
+
+        let pinned = PinSet::load(&store).unwrap().pinned(&store).unwrap();
+        for cid in [record, nested, leaf, other, list] {
+            assert!(pinned.contains(&cid));
+        }
+        assert!(!pinned.contains(&unpinned));
+        // A raw blob that lists CIDs is pinned on its own
+        assert!(!pinned.contains(&listed));
+    }
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let store = test_store("pins", "lock");
+        let lock = PinSet::lock(&store).unwrap();
+
+        // Another open of the lock file, as another process would have
+        let other = fs::File::open(PinSet::lock_path(&store)).unwrap();
+        assert!(other.try_lock().is_err());
+        drop(lock);
+        assert!(other.try_lock().is_ok());
+    }
+
+    #[test]
+    fn test_load_without_pins() {
+        let store = test_store("pins", "empty");
+        assert_eq!(PinSet::load(&store).unwrap(), PinSet::default());
+    }
+}
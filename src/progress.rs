@@ -0,0 +1,144 @@
+use crate::request::{Progress, TransferEvent};
+use crate::url::Url;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two progress events for the same source in the JSON
+/// event stream. Keeps the stream readable for large transfers.
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Render transfers as progress bars on stderr, one per source. A bar goes
+/// away when its transfer ends.
+/// The bars are hidden when stderr is not a terminal.
+pub fn progress_bars() -> Progress {
+    bars(false)
+}
+
+/// Render uploads as progress bars on stderr, one per server. Each bar stays
+/// once its upload ends, showing whether it finished or failed, while the
+/// uploads to other servers go on.
+pub fn upload_bars() -> Progress {
+    bars(true)
+}
+
+fn bars(keep_ended: bool) -> Progress {
+    let multi = MultiProgress::new();
+    let bars: Mutex<HashMap<Url, ProgressBar>> = Mutex::new(HashMap::new());
+
+    Progress::new(move |event| {
+        let mut bars = bars.lock().expect("Progress bars lock poisoned");
+        match event {
+            TransferEvent::Started { url, total } => {
+                let bar = bars
+                    .entry(url.clone())
+                    .or_insert_with(|| multi.add(new_bar(url)));
+                match total {
+                    Some(total) => bar.set_length(*total),
+                    None => bar.unset_length(),
+                }
+                bar.set_position(0);
+            }
+            TransferEvent::Progress { url, bytes, .. } => {
+                if let Some(bar) = bars.get(url) {
+                    bar.set_position(*bytes);
+                }
+            }
+            TransferEvent::Retrying { url, attempt, .. } => {
+                let bar = bars
+                    .entry(url.clone())
+                    .or_insert_with(|| multi.add(new_bar(url)));
+                bar.set_message(format!("{} (retry {})", url, attempt));
+            }
+            TransferEvent::Failed { url, .. } => {
+                if let Some(bar) = bars.remove(url) {
+                    if keep_ended {
+                        bar.abandon_with_message(format!("{} (failed)", url));
+                    } else {
+                        bar.finish_and_clear();
+                    }
+                }
+            }
+            TransferEvent::Finished { url, .. } => {
+                if let Some(bar) = bars.remove(url) {
+                    if keep_ended {
+                        bar.finish();
+                    } else {
+                        bar.finish_and_clear();
+                    }
+                }
+            }
+            TransferEvent::Cancelled { url } => {
+                if let Some(bar) = bars.remove(url) {
+                    bar.finish_and_clear();
+                }
+            }
+        }
+    })
+}
+
+fn new_bar(url: &Url) -> ProgressBar {
+    let style = ProgressStyle::with_template(
+        "{msg} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+    )
+    .expect("Progress bar template should be valid")
+    .progress_chars("=> ");
+    ProgressBar::no_length()
+        .with_style(style)
+        .with_message(url.to_string())
+}
+
+/// Write transfer events to stderr as newline-delimited JSON, for tools that
+/// want to track transfers.
+pub fn json_events() -> Progress {
+    let last_progress: Mutex<HashMap<Url, Instant>> = Mutex::new(HashMap::new());
+
+    Progress::new(move |event| {
+        if let TransferEvent::Progress { url, bytes, total } = event {
+            let mut last_progress = last_progress.lock().expect("Progress lock poisoned");
+            let now = Instant::now();
+            let is_done = Some(*bytes) == *total;
+            let is_due = last_progress
+                .get(url)
+                .is_none_or(|last| now.duration_since(*last) >= JSON_PROGRESS_INTERVAL);
+            if !is_done && !is_due {
+                return;
+            }
+            last_progress.insert(url.clone(), now);
+        }
+
+        let _ = writeln!(std::io::stderr().lock(), "{}", to_json_line(event));
+    })
+}
+
+fn to_json_line(event: &TransferEvent) -> String {
+    serde_json::to_string(event).expect("Transfer events should serialize to JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_event_format() {
+        let url = Url::parse("https://example.com/file").unwrap();
+
+        assert_eq!(
+            to_json_line(&TransferEvent::Progress {
+                url: url.clone(),
+                bytes: 10,
+                total: Some(100)
+            }),
+            r#"{"event":"progress","url":"https://example.com/file","bytes":10,"total":100}"#
+        );
+        assert_eq!(
+            to_json_line(&TransferEvent::Started {
+                url: url.clone(),
+                total: None
+            }),
+            r#"{"event":"started","url":"https://example.com/file","total":null}"#
+        );
+    }
+}
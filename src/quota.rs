@@ -0,0 +1,239 @@
+use crate::cid::Cid;
+use crate::store::Store;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Limits on how much content a store accepts.
+/// No limit is set by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Largest blob the store accepts, in bytes
+    pub max_blob_size: Option<u64>,
+    /// Largest total size of all blobs in the store, in bytes
+    pub max_store_size: Option<u64>,
+    /// Largest total size of the blobs uploaded by a single client, in bytes
+    pub max_client_size: Option<u64>,
+}
+
+impl Quota {
+    /// Whether the quota caps the store or a client's total. Uploads then
+    /// have to declare their size, so one upload can't hold back the room
+    /// that is left while it streams in.
+    pub fn limits_usage(&self) -> bool {
+        self.max_store_size.is_some() || self.max_client_size.is_some()
+    }
+
+    /// The most bytes a new blob from `client` may have, given the current
+    /// usage and the uploads in progress, or None if there is no limit
+    pub fn remaining(&self, usage: &Usage, client: &str) -> Option<u64> {
+        let store_remaining = self
+            .max_store_size
+            .map(|max| max.saturating_sub(usage.size + usage.reserved_size()));
+        let client_remaining = self.max_client_size.map(|max| {
+            max.saturating_sub(usage.client_size(client) + usage.client_reserved_size(client))
+        });
+        [self.max_blob_size, store_remaining, client_remaining]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+/// How much content a store holds, in total and per client that uploaded it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Number of blobs
+    pub blobs: u64,
+    /// Total size of the blobs in bytes
+    pub size: u64,
+    /// Blobs by the client that uploaded them
+    owners: HashMap<Cid, (String, u64)>,
+    /// Bytes set aside for uploads in progress, by client
+    reserved: HashMap<String, u64>,
+}
+
+impl Usage {
+    /// Work out the usage of a store from its blobs and its log of owners.
+    pub fn scan(store: &Store) -> io::Result<Self> {
+        let recorded = read_owners(store)?;
+        let mut usage = Usage::default();
+        for blob in store.list(None, None, usize::MAX)? {
+            usage.blobs += 1;
+            usage.size += blob.size;
+            if let Some(client) = recorded.get(&blob.cid) {
+                usage.owners.insert(blob.cid, (client.clone(), blob.size));
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Total size of the blobs uploaded by a client
+    pub fn client_size(&self, client: &str) -> u64 {
+        self.owners
+            .values()
+            .filter(|(owner, _)| owner == client)
+            .map(|(_, size)| size)
+            .sum()
+    }
+
+    /// Bytes set aside for uploads in progress
+    pub fn reserved_size(&self) -> u64 {
+        self.reserved.values().sum()
+    }
+
+    /// Bytes set aside for a client's uploads in progress
+    pub fn client_reserved_size(&self, client: &str) -> u64 {
+        self.reserved.get(client).copied().unwrap_or(0)
+    }
+
+    /// Set aside bytes for an upload, so they count against the quota while
+    /// the upload is in progress
+    pub fn reserve(&mut self, client: &str, bytes: u64) {
+        *self.reserved.entry(client.to_string()).or_default() += bytes;
+    }
+
+    /// Give back bytes set aside with `reserve`
+    pub fn release(&mut self, client: &str, bytes: u64) {
+        if let Some(reserved) = self.reserved.get_mut(client) {
+            *reserved = reserved.saturating_sub(bytes);
+            if *reserved == 0 {
+                self.reserved.remove(client);
+            }
+        }
+    }
+
+    /// Replace the counts with those of a fresh scan, keeping what is set
+    /// aside for uploads in progress
+    pub fn recount(&mut self, scanned: Usage) {
+        let reserved = std::mem::take(&mut self.reserved);
+        *self = Usage {
+            reserved,
+            ..scanned
+        };
+    }
+
+    /// Count a blob that was just added to the store. A blob that is already
+    /// counted, e.g. because two uploads of it raced, isn't counted again.
+    pub fn add(&mut self, cid: Cid, size: u64, client: &str) {
+        if self.owners.contains_key(&cid) {
+            return;
+        }
+        self.blobs += 1;
+        self.size += size;
+        self.owners.insert(cid, (client.to_string(), size));
+    }
+
+    /// Stop counting a blob that was just removed from the store
+    pub fn remove(&mut self, cid: &Cid, size: u64) {
+        self.blobs = self.blobs.saturating_sub(1);
+        self.size = self.size.saturating_sub(size);
+        self.owners.remove(cid);
+    }
+}
+
+/// Where a store logs which client uploaded each blob
+fn owners_path(store: &Store) -> PathBuf {
+    store.meta_dir().join("owners.log")
+}
+
+/// Record that a client uploaded a blob.
+/// The log is append-only. If a blob is uploaded more than once (after
+/// being deleted), the last upload counts.
+pub fn record_owner(store: &Store, cid: &Cid, client: &str) -> io::Result<()> {
+    fs::create_dir_all(store.meta_dir())?;
+    let mut log = fs::File::options()
+        .create(true)
+        .append(true)
+        .open(owners_path(store))?;
+    writeln!(log, "{} {}", cid, client)
+}
+
+fn read_owners(store: &Store) -> io::Result<HashMap<Cid, String>> {
+    let log = match fs::read_to_string(owners_path(store)) {
+        Ok(log) => log,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err),
+    };
+    Ok(log
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter_map(|(cid, client)| Some((Cid::parse(cid).ok()?, client.to_string())))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::test_store;
+
+    #[test]
+    fn test_remaining_is_the_tightest_limit() {
+        let mut usage = Usage::default();
+        usage.add(Cid::of("a"), 60, "alice");
+        usage.add(Cid::of("b"), 30, "bob");
+
+        let quota = Quota {
+            max_blob_size: Some(50),
+            max_store_size: Some(100),
+            max_client_size: Some(70),
+        };
+        assert_eq!(quota.remaining(&usage, "alice"), Some(10));
+        assert_eq!(quota.remaining(&usage, "bob"), Some(10));
+
+        let quota = Quota {
+            max_store_size: None,
+            ..quota
+        };
+        assert_eq!(quota.remaining(&usage, "alice"), Some(10));
+        assert_eq!(quota.remaining(&usage, "bob"), Some(40));
+        assert_eq!(quota.remaining(&usage, "carol"), Some(50));
+
+        assert_eq!(Quota::default().remaining(&usage, "alice"), None);
+    }
+
+    #[test]
+    fn test_reservations_count_until_released() {
+        let mut usage = Usage::default();
+        let quota = Quota {
+            max_store_size: Some(100),
+            max_client_size: Some(70),
+            ..Quota::default()
+        };
+
+        usage.reserve("alice", 60);
+        assert_eq!(quota.remaining(&usage, "alice"), Some(10));
+        assert_eq!(quota.remaining(&usage, "bob"), Some(40));
+
+        // A fresh scan doesn't forget uploads in progress
+        usage.recount(Usage::default());
+        assert_eq!(quota.remaining(&usage, "alice"), Some(10));
+
+        usage.release("alice", 60);
+        usage.add(Cid::of("a"), 60, "alice");
+        // Counting the same blob twice changes nothing
+        usage.add(Cid::of("a"), 60, "alice");
+        assert_eq!(usage.blobs, 1);
+        assert_eq!(usage.size, 60);
+        assert_eq!(quota.remaining(&usage, "alice"), Some(10));
+        assert_eq!(quota.remaining(&usage, "bob"), Some(40));
+    }
+
+    #[test]
+    fn test_scan_counts_owners_of_existing_blobs() {
+        let store = test_store("quota", "scan");
+
+        let kept = Cid::of("kept");
+        let deleted = Cid::of("deleted");
+        fs::write(store.path(&kept), "kept").unwrap();
+        record_owner(&store, &kept, "alice").unwrap();
+        record_owner(&store, &deleted, "alice").unwrap();
+
+        let usage = Usage::scan(&store).unwrap();
+        assert_eq!(usage.blobs, 1);
+        assert_eq!(usage.size, 4);
+        assert_eq!(usage.client_size("alice"), 4);
+    }
+}
@@ -0,0 +1,497 @@
+use crate::server::client_id;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+
+/// Past this many clients, clients whose buckets have refilled are
+/// forgotten, since they are no different from new clients
+const MAX_CLIENTS: usize = 10_000;
+
+/// How often to look for clients to forget once there are too many
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// New clients share the buckets of this client while there is no room to
+/// track them on their own
+const OVERFLOW_CLIENT: &str = "overflow";
+
+/// Limits on the requests of each client, for one kind of request.
+/// No limit is set by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Requests per second, with bursts of up to a second's worth
+    pub requests_per_sec: Option<f64>,
+    /// Bytes per second sent or received, with bursts of up to a second's
+    /// worth. Transfers are slowed down to stay within the limit.
+    pub bytes_per_sec: Option<u64>,
+}
+
+/// Limits on how much of the server each client can use.
+///
+/// Clients that present the server's auth token are limited together, and
+/// every other client is limited by IP address. Routes are limited by what
+/// they do rather than one by one: reads and writes each have their own
+/// limits, so uploads can be limited more strictly than downloads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimits {
+    /// Limits for reads: GET, HEAD and OPTIONS, and existence checks
+    /// (`POST /api/v1/has`), which change nothing
+    #[serde(default)]
+    pub read: RateLimit,
+    /// Limits for uploads and other changes: PUT, POST and DELETE
+    #[serde(default)]
+    pub write: RateLimit,
+    /// Most requests in progress at once across all clients, including
+    /// responses that are still being sent
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+}
+
+impl RateLimits {
+    pub fn is_enabled(&self) -> bool {
+        *self != RateLimits::default()
+    }
+}
+
+/// Whether a request reads or changes content. Each has its own limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Class {
+    Read,
+    Write,
+}
+
+/// Routes that are posted to, but only read
+const READ_ONLY_POSTS: [&str; 1] = ["/api/v1/has"];
+
+impl Class {
+    fn of(method: &Method, path: &str) -> Self {
+        let reads = method == Method::GET || method == Method::HEAD || method == Method::OPTIONS;
+        if reads || (method == Method::POST && READ_ONLY_POSTS.contains(&path)) {
+            Class::Read
+        } else {
+            Class::Write
+        }
+    }
+}
+
+/// A token bucket that refills at `rate` tokens per second, up to `burst`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub(crate) fn full(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+
+    /// Take a token if there is one, or else say how long until there is
+    fn take(&mut self, rate: f64, burst: f64, now: Instant) -> Result<(), Duration> {
+        self.refill(rate, burst, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    /// Take tokens even if that leaves the bucket in debt, and say how long
+    /// until it is out of debt
+    pub(crate) fn charge(&mut self, amount: f64, rate: f64, burst: f64, now: Instant) -> Duration {
+        self.refill(rate, burst, now);
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+    fn is_full(&self, rate: f64, burst: f64, now: Instant) -> bool {
+        let mut bucket = *self;
+        bucket.refill(rate, burst, now);
+        bucket.tokens >= burst
+    }
+}
+
+/// Most tokens a bucket holds: a second's worth, and at least one
+pub(crate) fn burst(rate: f64) -> f64 {
+    rate.max(1.0)
+}
+
+#[derive(Debug, Default)]
+struct ClientBuckets {
+    requests: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+/// The buckets of every client, and when idle clients were last forgotten
+#[derive(Debug, Default)]
+struct Buckets {
+    clients: HashMap<(Class, String), ClientBuckets>,
+    last_sweep: Option<Instant>,
+}
+
+/// Enforces `RateLimits` on the requests of a server.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    /// Clients with this token are limited together rather than by address
+    auth_token: Option<String>,
+    buckets: Mutex<Buckets>,
+    in_progress: AtomicUsize,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, auth_token: Option<String>) -> Self {
+        Self {
+            limits,
+            auth_token,
+            buckets: Mutex::default(),
+            in_progress: AtomicUsize::new(0),
+        }
+    }
+
+    fn limit(&self, class: Class) -> RateLimit {
+        match class {
+            Class::Read => self.limits.read,
+            Class::Write => self.limits.write,
+        }
+    }
+
+    /// Who a request counts against. See `server::client_id`.
+    fn client(&self, headers: &HeaderMap, addr: Option<SocketAddr>) -> String {
+        client_id(self.auth_token.as_deref(), headers, addr)
+    }
+
+    /// Count a request against a client's request rate. Returns how long
+    /// the client should wait if it is over the limit.
+    fn check_request(&self, class: Class, client: &str, now: Instant) -> Result<(), Duration> {
+        let Some(rate) = self.limit(class).requests_per_sec else {
+            return Ok(());
+        };
+        let mut buckets = self.buckets.lock().expect("Rate limit lock poisoned");
+        self.client_buckets(&mut buckets, class, client, now)
+            .requests
+            .get_or_insert_with(|| Bucket::full(burst(rate), now))
+            .take(rate, burst(rate), now)
+    }
+
+    /// Count bytes sent to or received from a client. Returns how long to
+    /// pause the transfer to stay within the limit.
+    fn charge_bytes(&self, class: Class, client: &str, bytes: usize, now: Instant) -> Duration {
+        let Some(rate) = self.limit(class).bytes_per_sec else {
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+        let mut buckets = self.buckets.lock().expect("Rate limit lock poisoned");
+        self.client_buckets(&mut buckets, class, client, now)
+            .bytes
+            .get_or_insert_with(|| Bucket::full(burst(rate), now))
+            .charge(bytes as f64, rate, burst(rate), now)
+    }
+
+    /// The buckets of a client. Clients that aren't tracked yet while there
+    /// are too many share the buckets of `OVERFLOW_CLIENT`, so the number of
+    /// clients stays bounded.
+    fn client_buckets<'a>(
+        &self,
+        buckets: &'a mut Buckets,
+        class: Class,
+        client: &str,
+        now: Instant,
+    ) -> &'a mut ClientBuckets {
+        let key = (class, client.to_string());
+        if buckets.clients.len() >= MAX_CLIENTS && !buckets.clients.contains_key(&key) {
+            self.forget_idle_clients(buckets, now);
+            if buckets.clients.len() >= MAX_CLIENTS {
+                return buckets
+                    .clients
+                    .entry((class, OVERFLOW_CLIENT.to_string()))
+                    .or_default();
+            }
+        }
+        buckets.clients.entry(key).or_default()
+    }
+
+    /// Forget the clients whose buckets have refilled, at most once every
+    /// `SWEEP_INTERVAL`
+    fn forget_idle_clients(&self, buckets: &mut Buckets, now: Instant) {
+        if buckets
+            .last_sweep
+            .is_some_and(|last| now.saturating_duration_since(last) < SWEEP_INTERVAL)
+        {
+            return;
+        }
+        buckets.last_sweep = Some(now);
+        buckets.clients.retain(|(class, _), client| {
+            let limit = self.limit(*class);
+            let requests_full = match (client.requests, limit.requests_per_sec) {
+                (Some(bucket), Some(rate)) => bucket.is_full(rate, burst(rate), now),
+                _ => true,
+            };
+            let bytes_full = match (client.bytes, limit.bytes_per_sec) {
+                (Some(bucket), Some(rate)) => bucket.is_full(rate as f64, burst(rate as f64), now),
+                _ => true,
+            };
+            !(requests_full && bytes_full)
+        });
+    }
+
+    /// Count a request as in progress, unless there are already too many
+    fn start(self: &Arc<Self>) -> Option<InProgress> {
+        let in_progress = self.in_progress.fetch_add(1, Ordering::SeqCst);
+        let guard = InProgress(self.clone());
+        match self.limits.max_concurrent_requests {
+            Some(max) if in_progress >= max => None,
+            _ => Some(guard),
+        }
+    }
+}
+
+/// A request in progress. It stops counting when dropped.
+#[derive(Debug)]
+struct InProgress(Arc<RateLimiter>);
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.0.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware that enforces rate limits, answering requests over the limit
+/// with 429 Too Many Requests, and requests beyond the concurrency limit
+/// with 503 Service Unavailable, both with a `Retry-After` header.
+pub async fn limit_rate(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(in_progress) = limiter.start() else {
+        return retry_after(StatusCode::SERVICE_UNAVAILABLE, Duration::from_secs(1));
+    };
+
+    let class = Class::of(request.method(), request.uri().path());
+    let addr = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let client = limiter.client(request.headers(), addr);
+    if let Err(wait) = limiter.check_request(class, &client, Instant::now()) {
+        return retry_after(StatusCode::TOO_MANY_REQUESTS, wait);
+    }
+
+    let request =
+        request.map(|body| Body::new(LimitedBody::new(body, &limiter, class, &client, None)));
+    let response = next.run(request).await;
+    response.map(|body| {
+        Body::new(LimitedBody::new(
+            body,
+            &limiter,
+            class,
+            &client,
+            Some(in_progress),
+        ))
+    })
+}
+
+fn retry_after(status: StatusCode, wait: Duration) -> Response {
+    let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        status,
+        [(header::RETRY_AFTER, seconds.to_string())],
+        "Too many requests",
+    )
+        .into_response()
+}
+
+/// A request or response body that is slowed down to stay within the
+/// client's bytes per second, and that keeps its request counted as in
+/// progress until it has been sent.
+struct LimitedBody {
+    inner: Body,
+    limiter: Arc<RateLimiter>,
+    class: Class,
+    client: String,
+    /// A frame held back until the client has earned it
+    held: Option<(Frame<Bytes>, Pin<Box<tokio::time::Sleep>>)>,
+    _in_progress: Option<InProgress>,
+}
+
+impl LimitedBody {
+    fn new(
+        inner: Body,
+        limiter: &Arc<RateLimiter>,
+        class: Class,
+        client: &str,
+        in_progress: Option<InProgress>,
+    ) -> Self {
+        Self {
+            inner,
+            limiter: limiter.clone(),
+            class,
+            client: client.to_string(),
+            held: None,
+            _in_progress: in_progress,
+        }
+    }
+}
+
+impl http_body::Body for LimitedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        if let Some((_, pause)) = &mut this.held {
+            ready!(pause.as_mut().poll(cx));
+            let (frame, _) = this.held.take().expect("held frame");
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        let Some(Ok(frame)) = frame else {
+            return Poll::Ready(frame);
+        };
+        let size = frame.data_ref().map_or(0, Bytes::len);
+        let wait = this
+            .limiter
+            .charge_bytes(this.class, &this.client, size, Instant::now());
+        if wait.is_zero() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        // Hold the frame back until the pause is over, registering for a
+        // wake up then
+        let mut pause = Box::pin(tokio::time::sleep(wait));
+        if pause.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        this.held = Some((frame, pause));
+        Poll::Pending
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.held.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_take() {
+        let start = Instant::now();
+        let mut bucket = Bucket::full(2.0, start);
+        assert!(bucket.take(2.0, 2.0, start).is_ok());
+        assert!(bucket.take(2.0, 2.0, start).is_ok());
+        assert_eq!(
+            bucket.take(2.0, 2.0, start),
+            Err(Duration::from_millis(500))
+        );
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(2.0, 2.0, later).is_ok());
+        assert!(bucket.is_full(2.0, 2.0, later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_bucket_charge() {
+        let start = Instant::now();
+        let mut bucket = Bucket::full(100.0, start);
+        assert_eq!(bucket.charge(60.0, 100.0, 100.0, start), Duration::ZERO);
+        assert_eq!(
+            bucket.charge(90.0, 100.0, 100.0, start),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn test_classes() {
+        assert_eq!(Class::of(&Method::GET, "/api/v1/blobs"), Class::Read);
+        assert_eq!(Class::of(&Method::HEAD, "/abc"), Class::Read);
+        assert_eq!(Class::of(&Method::POST, "/api/v1/has"), Class::Read);
+        assert_eq!(Class::of(&Method::PUT, "/abc"), Class::Write);
+        assert_eq!(Class::of(&Method::DELETE, "/api/v1/pins/a"), Class::Write);
+    }
+
+    #[test]
+    fn test_clients() {
+        let limiter = RateLimiter::new(RateLimits::default(), Some("secret".to_string()));
+        let addr = Some("192.0.2.1:1234".parse().unwrap());
+        let mut headers = HeaderMap::new();
+        assert_eq!(limiter.client(&headers, addr), "192.0.2.1");
+        headers.insert(header::AUTHORIZATION, "Bearer guess".parse().unwrap());
+        assert_eq!(limiter.client(&headers, addr), "192.0.2.1");
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert_eq!(limiter.client(&headers, addr), "token");
+    }
+
+    #[test]
+    fn test_forget_idle_clients_with_only_byte_limits() {
+        let limits = RateLimits {
+            read: RateLimit {
+                requests_per_sec: None,
+                bytes_per_sec: Some(1_000_000),
+            },
+            ..RateLimits::default()
+        };
+        let limiter = RateLimiter::new(limits, None);
+        let start = Instant::now();
+        for i in 0..MAX_CLIENTS + 10 {
+            limiter.charge_bytes(Class::Read, &i.to_string(), 1, start);
+        }
+        // No one is idle yet, so the clients past the limit share a bucket
+        let clients = |limiter: &RateLimiter| limiter.buckets.lock().unwrap().clients.len();
+        assert_eq!(clients(&limiter), MAX_CLIENTS + 1);
+
+        // Once their buckets have refilled, they are forgotten at the next
+        // sweep
+        limiter.charge_bytes(Class::Read, "last", 1, start + SWEEP_INTERVAL);
+        assert_eq!(clients(&limiter), 1);
+    }
+
+    #[test]
+    fn test_max_concurrent_requests() {
+        let limits = RateLimits {
+            max_concurrent_requests: Some(1),
+            ..RateLimits::default()
+        };
+        let limiter = Arc::new(RateLimiter::new(limits, None));
+        let first = limiter.start();
+        assert!(first.is_some());
+        assert!(limiter.start().is_none());
+        drop(first);
+        assert!(limiter.start().is_some());
+    }
+}
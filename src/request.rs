@@ -1,11 +1,226 @@
-use crate::cid::Cid;
+use crate::api::{BLOBS_PATH, BlobList, HAS_PATH, HasResponse, MAX_HAS_CIDS};
+use crate::cid::{Cid, CidHasher};
+use crate::drisl::{self, DrislError};
+use crate::health::HealthCache;
+use crate::ratelimit::{Bucket, burst};
 use crate::url::Url;
+use futures_util::StreamExt;
+use rand::Rng;
 use reqwest;
-pub use reqwest::{Client, Response};
+pub use reqwest::{Client, Response, StatusCode};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinSet;
+use tokio_util::io::ReaderStream;
 
-pub fn build_client(timeout: std::time::Duration) -> Result<Client, reqwest::Error> {
-    let client = reqwest::ClientBuilder::new().timeout(timeout).build()?;
-    Ok(client)
+/// How long a client waits on a server before giving up
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// For a connection to be established
+    pub connect: Option<Duration>,
+    /// For the response to start, and then between reads of the body, so a
+    /// stalled transfer fails however big the content is. This includes
+    /// sending the request body, so it shouldn't be set for uploads.
+    pub read: Option<Duration>,
+    /// For each request as a whole, including reading the body
+    pub total: Option<Duration>,
+}
+
+/// Build a client with the given timeouts
+pub fn build_client(timeouts: Timeouts) -> Result<Client, reqwest::Error> {
+    let mut builder = reqwest::ClientBuilder::new();
+    if let Some(timeout) = timeouts.connect {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = timeouts.read {
+        builder = builder.read_timeout(timeout);
+    }
+    if let Some(timeout) = timeouts.total {
+        builder = builder.timeout(timeout);
+    }
+    builder.build()
+}
+
+/// Configuration for fetching a CID from multiple sources
+#[derive(Debug, Clone)]
+pub struct FetchConfig {
+    /// Maximum number of requests in flight at the same time
+    pub concurrency: usize,
+    /// How long to wait for a pending request before also trying the next source
+    pub stagger: Duration,
+    /// How to retry transient failures for each source
+    pub retry: RetryPolicy,
+    /// Observer for transfer progress
+    pub progress: Progress,
+    /// Limit on the bandwidth of all requests together
+    pub throttle: Throttle,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 3,
+            stagger: Duration::from_millis(250),
+            retry: RetryPolicy::default(),
+            progress: Progress::default(),
+            throttle: Throttle::default(),
+        }
+    }
+}
+
+/// Something that happened during a transfer to or from a source
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TransferEvent {
+    /// A source responded. `total` is the Content-Length, if known.
+    Started { url: Url, total: Option<u64> },
+    /// Bytes transferred so far
+    Progress {
+        url: Url,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    /// An attempt failed with a transient error, and we'll try again
+    Retrying {
+        url: Url,
+        attempt: u32,
+        delay_ms: u64,
+        error: String,
+    },
+    /// The source failed for good
+    Failed { url: Url, error: String },
+    /// The transfer was stopped because another source won the race
+    Cancelled { url: Url },
+    /// The transfer completed and passed the integrity check
+    Finished { url: Url, bytes: u64 },
+}
+
+/// Observer for transfer events.
+/// Cheap to clone, and does nothing by default. The callback is called for
+/// every chunk of data, so it should return quickly.
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<ProgressCallback>>);
+
+type ProgressCallback = dyn Fn(&TransferEvent) + Send + Sync;
+
+impl Progress {
+    pub fn new(callback: impl Fn(&TransferEvent) + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(callback)))
+    }
+
+    pub fn emit(&self, event: TransferEvent) {
+        if let Some(callback) = &self.0 {
+            callback(&event);
+        }
+    }
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Progress").field(&self.0.is_some()).finish()
+    }
+}
+
+/// Limit on the bandwidth of transfers, as a token bucket of bytes.
+/// Cheap to clone, and clones share the same bucket, so any number of
+/// concurrent downloads and uploads stay within the limit together.
+/// Unlimited by default.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle(Option<Arc<ThrottleBucket>>);
+
+#[derive(Debug)]
+struct ThrottleBucket {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    /// Limit transfers to `bytes_per_sec`, with bursts of up to a second's
+    /// worth
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self(Some(Arc::new(ThrottleBucket {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket::full(burst(bytes_per_sec), Instant::now())),
+        })))
+    }
+
+    /// Count bytes that were transferred, and wait until the limit allows
+    /// more. Downloads are slowed down by reading from the connection less
+    /// often, and uploads by sending the next chunk later.
+    pub async fn consume(&self, bytes: usize) {
+        let Some(throttle) = &self.0 else {
+            return;
+        };
+        let rate = throttle.bytes_per_sec;
+        let wait = throttle
+            .bucket
+            .lock()
+            .expect("Throttle lock poisoned")
+            .charge(bytes as f64, rate, burst(rate), Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Policy for retrying transient failures (timeouts, connection errors,
+/// 5xx and 429 responses) with exponential backoff.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts per source, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry. Doubles with every retry.
+    pub base_delay: Duration,
+    /// Upper bound for any delay, including delays requested by `Retry-After`
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before the given retry (1 for the first retry).
+    /// Uses "full jitter": a random delay between zero and the exponential
+    /// backoff, so that many clients don't retry in lockstep.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31);
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        ceiling.mul_f64(rand::rng().random::<f64>())
+    }
+
+    /// Delay before retrying after an error.
+    /// A `Retry-After` from the server wins over our own backoff, but is
+    /// still capped by `max_delay`.
+    fn delay_for(&self, retry: u32, err: &RequestError) -> Duration {
+        match err {
+            RequestError::HttpStatus(_, Some(retry_after)) => (*retry_after).min(self.max_delay),
+            _ => self.backoff(retry),
+        }
+    }
 }
 
 /// HEAD CID, to check if a CID exists at a URL
@@ -17,6 +232,212 @@ pub async fn head_cid(client: &Client, url: &Url, cid: &Cid) -> Result<Response,
     Ok(response)
 }
 
+/// What happened when pushing content to a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushOutcome {
+    /// The server already had the content, so nothing was uploaded
+    AlreadyPresent,
+    /// The content was uploaded
+    Uploaded,
+}
+
+/// Upload a file to a magnetize server with `PUT /{CID}`.
+/// The file is streamed from disk, and the server verifies it against the CID.
+pub async fn put_cid(
+    client: &Client,
+    server: &Url,
+    cid: &Cid,
+    path: &Path,
+    token: Option<&str>,
+    progress: &Progress,
+    throttle: &Throttle,
+) -> Result<(), RequestError> {
+    let url = server.join(&cid.to_string())?;
+    let file = tokio::fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+
+    progress.emit(TransferEvent::Started {
+        url: url.clone(),
+        total: Some(size),
+    });
+
+    let mut sent: u64 = 0;
+    let chunk_progress = progress.clone();
+    let chunk_url = url.clone();
+    let throttle = throttle.clone();
+    let stream = ReaderStream::new(file).then(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            sent += chunk.len() as u64;
+            chunk_progress.emit(TransferEvent::Progress {
+                url: chunk_url.clone(),
+                bytes: sent,
+                total: Some(size),
+            });
+        }
+        let throttle = throttle.clone();
+        async move {
+            if let Ok(chunk) = &chunk {
+                throttle.consume(chunk.len()).await;
+            }
+            chunk
+        }
+    });
+
+    let mut request = client
+        .put(url.clone())
+        .header(reqwest::header::CONTENT_LENGTH, size)
+        .body(reqwest::Body::wrap_stream(stream));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(RequestError::HttpStatus(status, retry_after(&response)));
+    }
+
+    progress.emit(TransferEvent::Finished { url, bytes: size });
+    Ok(())
+}
+
+/// Push a file to a magnetize server.
+///
+/// Skips the upload if a HEAD request shows the server already has the CID.
+/// After uploading, checks with another HEAD request that the server really
+/// has the content, with the expected size.
+pub async fn push_cid(
+    client: &Client,
+    server: &Url,
+    cid: &Cid,
+    path: &Path,
+    token: Option<&str>,
+    progress: &Progress,
+    throttle: &Throttle,
+) -> Result<PushOutcome, RequestError> {
+    let size = tokio::fs::metadata(path).await?.len();
+
+    let existing = head_cid(client, server, cid).await?;
+    if existing.status() == StatusCode::OK && header_content_length(&existing) == Some(size) {
+        return Ok(PushOutcome::AlreadyPresent);
+    }
+
+    put_cid(client, server, cid, path, token, progress, throttle).await?;
+
+    let uploaded = head_cid(client, server, cid).await?;
+    let status = uploaded.status();
+    if status != StatusCode::OK {
+        return Err(RequestError::HttpStatus(status, None));
+    }
+    if header_content_length(&uploaded) != Some(size) {
+        return Err(RequestError::IntegrityError(format!(
+            "Server reports a size of {:?} bytes after upload. Expected: {}",
+            header_content_length(&uploaded),
+            size
+        )));
+    }
+
+    Ok(PushOutcome::Uploaded)
+}
+
+/// Encode a DRISL record and upload it to a magnetize server with
+/// `PUT /{CID}`. Returns the CID of the record.
+pub async fn put_record<T: Serialize + ?Sized>(
+    client: &Client,
+    server: &Url,
+    record: &T,
+    token: Option<&str>,
+) -> Result<Cid, RequestError> {
+    let bytes = drisl::to_vec(record)?;
+    let cid = drisl::cid_of(&bytes);
+    let mut request = client.put(server.join(&cid.to_string())?).body(bytes);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(RequestError::HttpStatus(status, retry_after(&response)));
+    }
+    Ok(cid)
+}
+
+/// Fetch a DRISL record from a URL, check it against its CID, and decode it.
+/// Stops reading once the response is larger than a record can be.
+pub async fn get_record<T: DeserializeOwned>(
+    client: &Client,
+    url: &Url,
+    cid: &Cid,
+) -> Result<T, RequestError> {
+    let (bytes, _) = get_and_check_cid_timed(
+        client,
+        url,
+        cid,
+        &Progress::default(),
+        &Throttle::default(),
+        drisl::MAX_RECORD_SIZE,
+    )
+    .await?;
+    Ok(drisl::from_slice(&bytes)?)
+}
+
+/// Read the Content-Length header.
+/// Unlike `Response::content_length`, this works for HEAD responses, which
+/// have no body.
+pub fn header_content_length(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Push a file to several servers in parallel.
+/// Returns the outcome for each server, in the order the servers were given.
+pub async fn push_cid_to_all(
+    client: &Client,
+    servers: &[Url],
+    cid: &Cid,
+    path: &Path,
+    token: Option<&str>,
+    progress: &Progress,
+    throttle: &Throttle,
+) -> Vec<(Url, Result<PushOutcome, RequestError>)> {
+    let mut tasks = JoinSet::new();
+    for (i, server) in servers.iter().enumerate() {
+        let client = client.clone();
+        let server = server.clone();
+        let cid = *cid;
+        let path = path.to_path_buf();
+        let token = token.map(|token| token.to_string());
+        let progress = progress.clone();
+        let throttle = throttle.clone();
+        tasks.spawn(async move {
+            let result = push_cid(
+                &client,
+                &server,
+                &cid,
+                &path,
+                token.as_deref(),
+                &progress,
+                &throttle,
+            )
+            .await;
+            (i, server, result)
+        });
+    }
+
+    let mut results: Vec<_> = tasks.join_all().await;
+    results.sort_by_key(|(i, _, _)| *i);
+    results
+        .into_iter()
+        .map(|(_, server, result)| (server, result))
+        .collect()
+}
+
 /// Fetch a URL and do an integrity check on the body against a CID.
 /// Returns the bytes if resource is found and integrity check passes.
 pub async fn get_and_check_cid(
@@ -24,11 +445,70 @@ pub async fn get_and_check_cid(
     url: &Url,
     cid: &Cid,
 ) -> Result<Vec<u8>, RequestError> {
-    let response = client.get(url.as_str()).send().await?;
-    let body = response.bytes().await?;
+    get_and_check_cid_with_progress(client, url, cid, &Progress::default(), &Throttle::default())
+        .await
+}
+
+/// Like `get_and_check_cid`, reporting progress while the body is read, and
+/// reading no faster than `throttle` allows.
+pub async fn get_and_check_cid_with_progress(
+    client: &Client,
+    url: &Url,
+    cid: &Cid,
+    progress: &Progress,
+    throttle: &Throttle,
+) -> Result<Vec<u8>, RequestError> {
+    let (body, _) = get_and_check_cid_timed(client, url, cid, progress, throttle, u64::MAX).await?;
+    Ok(body)
+}
+
+/// Like `get_and_check_cid_with_progress`, also returning the time it took
+/// the response to start. Unlike the time for the whole transfer, that
+/// doesn't depend on the size of the content, so it can be compared across
+/// fetches. Fails with `RequestError::TooLarge` as soon as the body is
+/// longer than `max_size`.
+async fn get_and_check_cid_timed(
+    client: &Client,
+    url: &Url,
+    cid: &Cid,
+    progress: &Progress,
+    throttle: &Throttle,
+    max_size: u64,
+) -> Result<(Vec<u8>, Duration), RequestError> {
+    let start = Instant::now();
+    let mut response = client.get(url.as_str()).send().await?;
+    let time_to_first_byte = start.elapsed();
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(RequestError::HttpStatus(status, retry_after(&response)));
+    }
+
+    let total = response.content_length();
+    if total.is_some_and(|total| total > max_size) {
+        return Err(RequestError::TooLarge(max_size));
+    }
+    progress.emit(TransferEvent::Started {
+        url: url.clone(),
+        total,
+    });
+
+    let mut body = Vec::with_capacity(total.unwrap_or(0).min(64 * 1024 * 1024) as usize);
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > max_size {
+            return Err(RequestError::TooLarge(max_size));
+        }
+        throttle.consume(chunk.len()).await;
+        body.extend_from_slice(&chunk);
+        progress.emit(TransferEvent::Progress {
+            url: url.clone(),
+            bytes: body.len() as u64,
+            total,
+        });
+    }
 
     // Generate CID from response
-    let body_cid = Cid::of(&body);
+    let body_cid = Cid::of(&body).with_codec(cid.codec());
 
     // Do integrity check
     if !&body_cid.eq(cid) {
@@ -40,23 +520,339 @@ pub async fn get_and_check_cid(
         )));
     }
 
+    progress.emit(TransferEvent::Finished {
+        url: url.clone(),
+        bytes: body.len() as u64,
+    });
+
     // Return the bytes
-    Ok(body.to_vec())
+    Ok((body, time_to_first_byte))
+}
+
+/// Fetch a URL and do an integrity check on the body against a CID, retrying
+/// transient failures according to the retry policy.
+pub async fn get_and_check_cid_with_retry(
+    client: &Client,
+    url: &Url,
+    cid: &Cid,
+    policy: &RetryPolicy,
+    progress: &Progress,
+    throttle: &Throttle,
+) -> Result<Vec<u8>, RequestError> {
+    with_retry(url, policy, progress, || {
+        get_and_check_cid_with_progress(client, url, cid, progress, throttle)
+    })
+    .await
+}
+
+/// Run a transfer to or from `url`, retrying transient failures according to
+/// the retry policy. Retries and the final failure are reported to `progress`.
+pub async fn with_retry<T, F, Fut>(
+    url: &Url,
+    policy: &RetryPolicy,
+    progress: &Progress,
+    mut transfer: F,
+) -> Result<T, RequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match transfer().await {
+            Err(err) if err.is_transient() && attempt < policy.max_attempts => {
+                let delay = policy.delay_for(attempt, &err);
+                tracing::debug!(%url, attempt, ?delay, "retrying after error: {}", err);
+                progress.emit(TransferEvent::Retrying {
+                    url: url.clone(),
+                    attempt,
+                    delay_ms: delay.as_millis() as u64,
+                    error: err.to_string(),
+                });
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                progress.emit(TransferEvent::Failed {
+                    url: url.clone(),
+                    error: err.to_string(),
+                });
+                return Err(err);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Download a CID from a URL into a file, doing an integrity check while the
+/// body is written. Unlike `get_and_check_cid`, the content is never held in
+/// memory, so this works for blobs of any size.
+///
+/// The file is created or truncated. If the content doesn't match the CID the
+/// file is left in place, and it is up to the caller to remove it.
+///
+/// Returns the size of the content in bytes.
+pub async fn download_cid(
+    client: &Client,
+    url: &Url,
+    cid: &Cid,
+    path: &Path,
+    progress: &Progress,
+    throttle: &Throttle,
+) -> Result<u64, RequestError> {
+    let mut response = client.get(url.as_str()).send().await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(RequestError::HttpStatus(status, retry_after(&response)));
+    }
+
+    let total = response.content_length();
+    progress.emit(TransferEvent::Started {
+        url: url.clone(),
+        total,
+    });
+
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut hasher = CidHasher::new();
+    let mut size: u64 = 0;
+    while let Some(chunk) = response.chunk().await? {
+        throttle.consume(chunk.len()).await;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+        progress.emit(TransferEvent::Progress {
+            url: url.clone(),
+            bytes: size,
+            total,
+        });
+    }
+    file.sync_all().await?;
+
+    let body_cid = hasher.finalize().with_codec(cid.codec());
+    if body_cid != *cid {
+        return Err(RequestError::IntegrityError(format!(
+            "Response doesn't match CID. Expected: {} Got: {}",
+            cid, body_cid
+        )));
+    }
+
+    progress.emit(TransferEvent::Finished {
+        url: url.clone(),
+        bytes: size,
+    });
+
+    Ok(size)
+}
+
+/// Check which of a list of CIDs a magnetize server has, with
+/// `POST /api/v1/has`. Large lists are split into several requests.
+pub async fn has_cids(
+    client: &Client,
+    server: &Url,
+    cids: &[Cid],
+) -> Result<HasResponse, RequestError> {
+    let url = server.join(HAS_PATH)?;
+    let mut has = HasResponse::default();
+    for batch in cids.chunks(MAX_HAS_CIDS) {
+        let response = client.post(url.clone()).json(batch).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(RequestError::HttpStatus(status, retry_after(&response)));
+        }
+        let batch_has: HasResponse = response.json().await?;
+        has.present.extend(batch_has.present);
+        has.missing.extend(batch_has.missing);
+    }
+    Ok(has)
+}
+
+/// Get a page of the blobs a magnetize server holds, with
+/// `GET /api/v1/blobs`. Pass the cursor from the previous page to get the
+/// next one, and `since` (a Unix timestamp) to only list blobs added since
+/// then. The server must have listing enabled.
+pub async fn list_blobs(
+    client: &Client,
+    server: &Url,
+    cursor: Option<&str>,
+    since: Option<u64>,
+    limit: Option<usize>,
+) -> Result<BlobList, RequestError> {
+    let url = server.join(BLOBS_PATH)?;
+    let mut request = client.get(url);
+    if let Some(cursor) = cursor {
+        request = request.query(&[("cursor", cursor)]);
+    }
+    if let Some(since) = since {
+        request = request.query(&[("since", since)]);
+    }
+    if let Some(limit) = limit {
+        request = request.query(&[("limit", limit)]);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(RequestError::HttpStatus(status, retry_after(&response)));
+    }
+    Ok(response.json().await?)
+}
+
+/// Parse the `Retry-After` header, which is either a number of seconds or an
+/// HTTP date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+    parse_retry_after(value, SystemTime::now())
+}
+
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value.trim()).ok()?;
+    // A date in the past means "retry now"
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// Race requests for a CID against multiple sources, happy-eyeballs style.
+///
+/// Sources are tried in order. A request to the next source is started
+/// whenever a request fails, or when `config.stagger` elapses without a
+/// response, up to `config.concurrency` requests in flight. The first response
+/// that passes the integrity check wins and the other requests are cancelled.
+///
+/// Each source is retried according to `config.retry` before it counts as
+/// failed. The outcome of every finished request is recorded in `health`.
+///
+/// Returns the winning URL and the bytes, or the error for every source if
+/// none of them succeeded.
+pub async fn race_get_and_check_cid(
+    client: &Client,
+    urls: &[Url],
+    cid: &Cid,
+    config: &FetchConfig,
+    health: &mut HealthCache,
+) -> Result<(Url, Vec<u8>), RequestError> {
+    let mut pending: VecDeque<Url> = urls.iter().cloned().collect();
+    let mut in_flight = JoinSet::new();
+    let mut in_flight_urls = Vec::new();
+    let mut errors = Vec::new();
+    let concurrency = config.concurrency.max(1);
+
+    loop {
+        // We get here at the start, and whenever a request failed or the
+        // stagger delay elapsed. Either way, it is time to try another source.
+        if in_flight.len() < concurrency
+            && let Some(url) = pending.pop_front()
+        {
+            let client = client.clone();
+            let cid = *cid;
+            let retry = config.retry.clone();
+            let progress = config.progress.clone();
+            let throttle = config.throttle.clone();
+            in_flight_urls.push(url.clone());
+            in_flight.spawn(async move {
+                let result = with_retry(&url, &retry, &progress, || {
+                    get_and_check_cid_timed(&client, &url, &cid, &progress, &throttle, u64::MAX)
+                })
+                .await;
+                (url, result)
+            });
+        }
+
+        if in_flight.is_empty() {
+            return Err(RequestError::SourcesExhausted(errors));
+        }
+
+        let can_start_more = !pending.is_empty() && in_flight.len() < concurrency;
+
+        tokio::select! {
+            Some(joined) = in_flight.join_next() => {
+                let (url, result) = joined.expect("Fetch task panicked");
+                in_flight_urls.retain(|in_flight_url| *in_flight_url != url);
+                match result {
+                    Ok((body, time_to_first_byte)) => {
+                        health.record_success(&url, time_to_first_byte);
+                        // Cancel the remaining requests, and only then report
+                        // them, so they can't report anything after that
+                        in_flight.shutdown().await;
+                        for url in in_flight_urls {
+                            config.progress.emit(TransferEvent::Cancelled { url });
+                        }
+                        return Ok((url, body));
+                    }
+                    Err(err) => {
+                        match err {
+                            RequestError::IntegrityError(_) => health.record_integrity_failure(&url),
+                            _ => health.record_failure(&url),
+                        }
+                        errors.push((url, err));
+                    }
+                }
+            }
+            _ = tokio::time::sleep(config.stagger), if can_start_more => {}
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum RequestError {
+    IoError(std::io::Error),
     RequestError(reqwest::Error),
     UrlParseError(url::ParseError),
     IntegrityError(String),
+    /// A record that isn't valid DRISL, or doesn't fit the expected type
+    RecordError(DrislError),
+    /// The response is larger than the given limit in bytes
+    TooLarge(u64),
+    /// Server responded with a non-success status, and maybe a `Retry-After`
+    HttpStatus(StatusCode, Option<Duration>),
+    /// Every source was tried, and each failed with the given error
+    SourcesExhausted(Vec<(Url, RequestError)>),
 }
 
 impl std::fmt::Display for RequestError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            RequestError::IoError(err) => write!(f, "IO Error: {}", err),
             RequestError::RequestError(err) => write!(f, "Request Error: {}", err),
             RequestError::UrlParseError(err) => write!(f, "URL Parse Error: {}", err),
             RequestError::IntegrityError(err) => write!(f, "Integrity Error: {}", err),
+            RequestError::RecordError(err) => write!(f, "Record Error: {}", err),
+            RequestError::TooLarge(max_size) => {
+                write!(f, "Response is larger than the limit of {} bytes", max_size)
+            }
+            RequestError::HttpStatus(status, _) => write!(f, "HTTP Error: {}", status),
+            RequestError::SourcesExhausted(errors) => {
+                write!(f, "All sources failed")?;
+                for (url, err) in errors {
+                    write!(f, "\n\t{}: {}", url, err)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl RequestError {
+    /// Is this error likely to go away if we try again?
+    /// Integrity errors are never transient. A mirror that serves the wrong
+    /// bytes is not to be trusted.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RequestError::RequestError(err) => {
+                err.is_timeout() || err.is_connect() || err.is_body()
+            }
+            RequestError::HttpStatus(status, _) => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            _ => false,
         }
     }
 }
@@ -69,8 +865,311 @@ impl From<reqwest::Error> for RequestError {
     }
 }
 
+impl From<std::io::Error> for RequestError {
+    fn from(err: std::io::Error) -> Self {
+        RequestError::IoError(err)
+    }
+}
+
+impl From<DrislError> for RequestError {
+    fn from(err: DrislError) -> Self {
+        RequestError::RecordError(err)
+    }
+}
+
 impl From<url::ParseError> for RequestError {
     fn from(err: url::ParseError) -> Self {
         RequestError::UrlParseError(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, routing::get};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const BODY: &str = "hello world";
+
+    /// Serve a few test routes on a random local port, returning the base URL
+    async fn test_server() -> Url {
+        // Fails with 503 on the first request, then succeeds
+        let flaky_hits = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/good", get(|| async { BODY }))
+            .route("/wrong", get(|| async { "not hello world" }))
+            .route("/missing", get(|| async { StatusCode::NOT_FOUND }))
+            .route(
+                "/large",
+                // Streamed, without a Content-Length
+                get(|| async {
+                    let chunk: Result<_, std::io::Error> =
+                        Ok(vec![0u8; drisl::MAX_RECORD_SIZE as usize + 1]);
+                    axum::body::Body::from_stream(futures_util::stream::iter([chunk]))
+                }),
+            )
+            .route(
+                "/flaky",
+                get(move || async move {
+                    match flaky_hits.fetch_add(1, Ordering::SeqCst) {
+                        0 => Err((StatusCode::SERVICE_UNAVAILABLE, [("retry-after", "0")])),
+                        _ => Ok(BODY),
+                    }
+                }),
+            )
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    BODY
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Url::parse(&format!("http://{}/", addr)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_record_stops_at_the_record_size() {
+        let base = test_server().await;
+        let url = base.join("large").unwrap();
+        let cid = drisl::cid_of(vec![0u8; drisl::MAX_RECORD_SIZE as usize + 1]);
+        let result = get_record::<Vec<Cid>>(&Client::new(), &url, &cid).await;
+        assert!(matches!(
+            result,
+            Err(RequestError::TooLarge(max_size)) if max_size == drisl::MAX_RECORD_SIZE
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_race_skips_failing_sources() {
+        let base = test_server().await;
+        let urls = vec![
+            base.join("missing").unwrap(),
+            base.join("wrong").unwrap(),
+            base.join("good").unwrap(),
+        ];
+        let client = Client::new();
+        let config = FetchConfig {
+            concurrency: 1,
+            stagger: Duration::from_secs(10),
+            retry: RetryPolicy::none(),
+            ..FetchConfig::default()
+        };
+        let mut health = HealthCache::default();
+
+        let (url, body) =
+            race_get_and_check_cid(&client, &urls, &Cid::of(BODY), &config, &mut health)
+                .await
+                .unwrap();
+
+        assert_eq!(url, base.join("good").unwrap());
+        assert_eq!(body, BODY.as_bytes());
+
+        // Every finished request was recorded. All share the same origin.
+        let mirror = health.get(&url).unwrap();
+        assert_eq!(mirror.successes, 1);
+        assert_eq!(mirror.failures, 2);
+        assert!(mirror.last_integrity_failure.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_race_does_not_wait_for_slow_source() {
+        let base = test_server().await;
+        let urls = vec![base.join("slow").unwrap(), base.join("good").unwrap()];
+        let client = Client::new();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let config = FetchConfig {
+            concurrency: 2,
+            stagger: Duration::from_millis(50),
+            retry: RetryPolicy::none(),
+            progress: {
+                let events = events.clone();
+                Progress::new(move |event| events.lock().unwrap().push(event.clone()))
+            },
+            ..FetchConfig::default()
+        };
+
+        let start = Instant::now();
+        let (url, _) = race_get_and_check_cid(
+            &client,
+            &urls,
+            &Cid::of(BODY),
+            &config,
+            &mut HealthCache::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(url, base.join("good").unwrap());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // The slow source was cancelled, and said so last
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events.last(),
+            Some(&TransferEvent::Cancelled {
+                url: base.join("slow").unwrap()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_race_times_out_and_reports_all_errors() {
+        let base = test_server().await;
+        let urls = vec![base.join("slow").unwrap(), base.join("wrong").unwrap()];
+        let client = build_client(Timeouts {
+            connect: Some(Duration::from_secs(1)),
+            read: Some(Duration::from_millis(200)),
+            total: None,
+        })
+        .unwrap();
+
+        let config = FetchConfig {
+            retry: RetryPolicy::none(),
+            ..FetchConfig::default()
+        };
+
+        let result = race_get_and_check_cid(
+            &client,
+            &urls,
+            &Cid::of(BODY),
+            &config,
+            &mut HealthCache::default(),
+        )
+        .await;
+
+        match result {
+            Err(RequestError::SourcesExhausted(errors)) => assert_eq!(errors.len(), 2),
+            _ => panic!("Expected SourcesExhausted error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_failure() {
+        let base = test_server().await;
+        let url = base.join("flaky").unwrap();
+        let client = Client::new();
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+        };
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let progress = {
+            let events = events.clone();
+            Progress::new(move |event| events.lock().unwrap().push(event.clone()))
+        };
+
+        let body = get_and_check_cid_with_retry(
+            &client,
+            &url,
+            &Cid::of(BODY),
+            &policy,
+            &progress,
+            &Throttle::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(body, BODY.as_bytes());
+
+        let events = events.lock().unwrap();
+        assert!(matches!(
+            events.first(),
+            Some(TransferEvent::Retrying { attempt: 1, .. })
+        ));
+        assert_eq!(
+            events.get(1),
+            Some(&TransferEvent::Started {
+                url: url.clone(),
+                total: Some(BODY.len() as u64)
+            })
+        );
+        assert_eq!(
+            events.last(),
+            Some(&TransferEvent::Finished {
+                url: url.clone(),
+                bytes: BODY.len() as u64
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_for_integrity_error() {
+        let base = test_server().await;
+        let url = base.join("wrong").unwrap();
+        let client = Client::new();
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(10),
+        };
+
+        let start = Instant::now();
+        let result = get_and_check_cid_with_retry(
+            &client,
+            &url,
+            &Cid::of(BODY),
+            &policy,
+            &Progress::default(),
+            &Throttle::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(RequestError::IntegrityError(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_throttle_is_shared() {
+        let throttle = Throttle::new(100_000);
+        let start = Instant::now();
+        // The first second's worth is a burst
+        throttle.consume(100_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // Clones draw from the same bucket
+        let other = throttle.clone();
+        tokio::join!(throttle.consume(25_000), other.consume(25_000));
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        let unlimited = Throttle::default();
+        let start = Instant::now();
+        unlimited.consume(usize::MAX).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        for retry in 1..10 {
+            let ceiling = Duration::from_millis(100 * (1 << (retry - 1))).min(policy.max_delay);
+            assert!(policy.backoff(retry) <= ceiling);
+        }
+        assert!(policy.backoff(1000) <= policy.max_delay);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after(&httpdate::fmt_http_date(now + Duration::from_secs(30)), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after(&httpdate::fmt_http_date(now - Duration::from_secs(30)), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
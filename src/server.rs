@@ -1,14 +1,42 @@
-use crate::cid::Cid;
+use crate::api::{
+    BlobList, DEFAULT_PAGE_LIMIT, HasResponse, MAX_HAS_CIDS, MAX_PAGE_LIMIT, UsageReport,
+};
+use crate::car;
+use crate::cid::{Cid, CidHasher, Codec};
+use crate::drisl;
+use crate::fsck::{fsck, repair};
+use crate::metrics::{IntegrityCheck, Metrics};
+use crate::pins::PinSet;
+use crate::quota::{Quota, Usage, record_owner};
+use crate::ratelimit::{RateLimiter, RateLimits, limit_rate};
+use crate::request::{Timeouts, build_client};
+use crate::store::{Store, StoreError};
+use crate::tls::{self, TlsConfig};
+use crate::url::Url;
+use crate::util::constant_time_eq;
 use axum::{
-    Router,
-    extract::{Path, Query, State},
-    http::StatusCode,
+    Json, Router,
+    body::Body,
+    extract::{ConnectInfo, MatchedPath, Path, Query, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, head},
+    routing::{delete, get, head, post, put},
 };
+use axum_server::Handle;
+use bytes::Bytes;
+use futures_util::{StreamExt, stream};
 use serde::{Deserialize, Serialize};
 use std::fs;
+use std::io;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_util::io::ReaderStream;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::set_header::SetResponseHeaderLayer;
 use tower_http::trace::{self, TraceLayer};
 use tracing::Level;
 
@@ -18,11 +46,65 @@ pub struct ServerConfig {
     pub addr: String,
     /// The directory where content-addressed files will be stored
     pub dir: PathBuf,
+    /// Bearer token clients must present to upload content.
+    /// Uploads are disabled if there is no token.
+    pub auth_token: Option<String>,
+    /// Serve a listing of all blobs at `GET /api/v1/blobs`
+    #[serde(default)]
+    pub enable_listing: bool,
+    /// Limits on uploads
+    #[serde(default)]
+    pub quota: Quota,
+    /// Hash blobs as they are served, and abort the response instead of
+    /// finishing it if a blob doesn't match its CID
+    #[serde(default)]
+    pub verify_on_read: bool,
+    /// Serve Prometheus metrics at `GET /metrics`
+    #[serde(default)]
+    pub enable_metrics: bool,
+    /// Check the integrity of the store this often, in the background
+    #[serde(default)]
+    pub scrub_interval: Option<Duration>,
+    /// Servers to fetch blobs from when the scrubber finds them corrupted
+    #[serde(default)]
+    pub repair_peers: Vec<Url>,
+    /// After SIGTERM or SIGINT, how long to wait for requests in progress
+    /// before closing their connections
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: Duration,
+    /// Serve HTTPS instead of plain HTTP
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Origins of web apps allowed to fetch content cross-origin, or `*`
+    /// for any. No CORS headers are sent without any.
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    /// Limits on how much of the server each client can use
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
 }
 
 #[derive(Clone)]
 struct ServerState {
-    pub dir: PathBuf,
+    pub store: Store,
+    pub auth_token: Option<String>,
+    pub enable_listing: bool,
+    pub quota: Quota,
+    /// What the store holds, kept up to date as blobs are added and deleted
+    pub usage: Arc<Mutex<Usage>>,
+    pub verify_on_read: bool,
+    /// Collected only when metrics are enabled
+    pub metrics: Option<Arc<Metrics>>,
+    /// Set once the server starts shutting down, so it reports that it is
+    /// no longer ready
+    pub shutting_down: Arc<AtomicBool>,
+    pub cors_origins: Vec<String>,
+    /// Only set when there are rate limits
+    pub rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 /// Multithread server (number of threads = number of CPUs)
@@ -39,33 +121,311 @@ pub async fn serve(config: ServerConfig) {
 
     let addr = config.addr.clone();
 
-    let state = ServerState { dir: config.dir };
+    let store = Store::new(config.dir);
+    let usage = Usage::scan(&store).expect("Unable to read file storage directory");
+
+    let rate_limiter = config.rate_limits.is_enabled().then(|| {
+        Arc::new(RateLimiter::new(
+            config.rate_limits,
+            config.auth_token.clone(),
+        ))
+    });
+
+    let state = ServerState {
+        store,
+        auth_token: config.auth_token,
+        enable_listing: config.enable_listing,
+        quota: config.quota,
+        usage: Arc::new(Mutex::new(usage)),
+        verify_on_read: config.verify_on_read,
+        metrics: config.enable_metrics.then(|| Arc::new(Metrics::new())),
+        shutting_down: Arc::default(),
+        cors_origins: config.cors_origins,
+        rate_limiter,
+    };
+    let store = state.store.clone();
+    let shutting_down = state.shutting_down.clone();
+
+    if let Some(interval) = config.scrub_interval {
+        tokio::spawn(scrub(state.clone(), interval, config.repair_peers));
+    }
+
+    let app = router(state).layer(
+        TraceLayer::new_for_http()
+            .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+            .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+    );
+
+    // Run the server
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .and_then(|listener| listener.into_std())
+        .expect("Unable to bind server to address");
+
+    let handle = Handle::new();
+    tokio::spawn(watch_signals(
+        shutting_down,
+        handle.clone(),
+        config.drain_timeout,
+    ));
+
+    // Clients are identified by address for per-client quotas
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let result = match config.tls {
+        Some(tls_config) => {
+            let rustls = tls::load(&tls_config)
+                .await
+                .expect("Unable to load TLS certificate");
+            tokio::spawn(tls::watch(tls_config.clone(), rustls.clone()));
+            if let Some(redirect_addr) = &tls_config.redirect_addr {
+                let https_port = listener
+                    .local_addr()
+                    .expect("Unable to get server address")
+                    .port();
+                spawn_redirect(redirect_addr, https_port, handle.clone()).await;
+            }
+            tracing::info!(addr = &addr, "server listening for HTTPS");
+            axum_server::from_tcp_rustls(listener, rustls)
+                .handle(handle)
+                .serve(app)
+                .await
+        }
+        None => {
+            tracing::info!(addr = &addr, "server listening");
+            axum_server::from_tcp(listener)
+                .handle(handle)
+                .serve(app)
+                .await
+        }
+    };
+    result.expect("Unable to start server");
 
-    // Build our application with routes
-    let app = Router::new()
+    // Uploads that were cut off remove their own temp files. Clean up after
+    // any that were interrupted long ago, e.g. by a crash.
+    match store.remove_stale_temp_files() {
+        Ok(removed) => tracing::info!(removed = removed.len(), "removed partial uploads"),
+        Err(err) => tracing::error!("unable to remove partial uploads: {}", err),
+    }
+    tracing::info!("server stopped");
+}
+
+/// Listen for plain HTTP, and redirect every request to HTTPS
+async fn spawn_redirect(addr: &str, https_port: u16, handle: Handle) {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .and_then(|listener| listener.into_std())
+        .expect("Unable to bind redirect to address");
+    tracing::info!(addr, "redirecting HTTP to HTTPS");
+    let server = axum_server::from_tcp(listener)
+        .handle(handle)
+        .serve(tls::redirect_router(https_port).into_make_service());
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            tracing::error!("redirect server failed: {}", err);
+        }
+    });
+}
+
+/// Wait for SIGTERM or SIGINT, then start shutting down: stop reporting
+/// ready, stop accepting connections, and give requests in progress up to
+/// `drain_timeout` to finish
+async fn watch_signals(shutting_down: Arc<AtomicBool>, handle: Handle, drain_timeout: Duration) {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Unable to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutting down, waiting for requests in progress");
+    shutting_down.store(true, Ordering::SeqCst);
+    handle.graceful_shutdown(Some(drain_timeout));
+}
+
+/// Periodically check the integrity of the store, quarantining corrupted
+/// blobs and repairing them from peers
+async fn scrub(state: ServerState, interval: Duration, peers: Vec<Url>) {
+    // A stalled peer mustn't hold up the repairs of the blobs after it
+    let client = build_client(Timeouts {
+        connect: Some(Duration::from_secs(10)),
+        read: Some(Duration::from_secs(30)),
+        total: None,
+    })
+    .expect("Unable to build HTTP client");
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let store = state.store.clone();
+        let report = match tokio::task::spawn_blocking(move || fsck(&store, false)).await {
+            Ok(Ok(report)) => report,
+            Ok(Err(err)) => {
+                tracing::error!("unable to scrub store: {}", err);
+                continue;
+            }
+            Err(err) => {
+                tracing::error!("scrubbing store panicked: {}", err);
+                continue;
+            }
+        };
+        tracing::info!(
+            checked = report.checked,
+            corrupted = report.corrupted.len(),
+            removed = report.removed.len(),
+            errors = report.errors.len(),
+            "scrubbed store"
+        );
+        if let Some(metrics) = &state.metrics {
+            metrics.add_integrity_failures(IntegrityCheck::Scrub, report.corrupted.len() as u64);
+        }
+
+        for cid in &report.corrupted {
+            if let Some(peer) = repair(&client, &state.store, cid, &peers).await {
+                tracing::info!(%cid, %peer, "repaired blob");
+            }
+        }
+
+        if !report.is_clean()
+            && let Err(err) = refresh_usage(&state).await
+        {
+            tracing::error!("unable to refresh usage: {}", err);
+        }
+    }
+}
+
+/// Build our application with routes
+fn router(state: ServerState) -> Router {
+    let mut router = Router::new();
+    if state.enable_listing {
+        router = router
+            .route("/api/v1/blobs", get(get_blobs))
+            .route("/.well-known/rasl/", get(get_blobs));
+    }
+    router = router
         .route("/", get(get_index))
+        .route("/api/v1/has", post(post_has))
+        .route("/api/v1/usage", get(get_usage))
+        .route("/api/v1/pins", get(get_pins))
+        .route("/api/v1/pins/{name}", put(put_pin))
+        .route("/api/v1/pins/{name}", delete(delete_pin));
+
+    let mut blobs = Router::new()
         .route("/{cid}", get(get_cid))
         .route("/{cid}", head(head_cid))
+        .route("/{cid}", put(put_cid))
+        .route("/{cid}", delete(delete_cid))
         // See <https://dasl.ing/rasl.html>
         .route("/.well-known/rasl/{cid}", get(get_cid))
         .route("/.well-known/rasl/{cid}", head(head_cid))
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
-        )
-        .with_state(state);
+        .route("/ipfs/{cid}", get(get_ipfs))
+        .route("/ipfs/{cid}", head(head_ipfs));
+    if let Some(cors) = cors_layer(&state.cors_origins) {
+        blobs = blobs.layer(cors).layer(SetResponseHeaderLayer::overriding(
+            HeaderName::from_static("cross-origin-resource-policy"),
+            HeaderValue::from_static("cross-origin"),
+        ));
+    }
+    router = router.merge(blobs);
 
-    // Run the server
-    let listener = tokio::net::TcpListener::bind(&addr)
-        .await
-        .expect("Unable to bind server to address");
+    // Layers only apply to the routes added before them, so probes and
+    // metrics, added after, are never rate limited
+    if let Some(limiter) = &state.rate_limiter {
+        router = router.layer(middleware::from_fn_with_state(limiter.clone(), limit_rate));
+    }
+    router = router
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(get_readyz));
 
-    tracing::info!(addr = &addr, "server listening");
+    // Added last so that it applies to every route
+    if let Some(metrics) = &state.metrics {
+        router = router
+            .route("/metrics", get(get_metrics))
+            .layer(middleware::from_fn_with_state(
+                metrics.clone(),
+                track_metrics,
+            ));
+    }
+    router.with_state(state)
+}
 
-    axum::serve(listener, app)
-        .await
-        .expect("Unable to start server");
+/// CORS for the blob routes, so web apps on the allowed origins can fetch
+/// content and check its `Content-Digest` themselves. None if no origins
+/// are allowed.
+fn cors_layer(origins: &[String]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().filter_map(|origin| {
+            let value = HeaderValue::from_str(origin);
+            if value.is_err() {
+                tracing::warn!(origin, "ignoring invalid CORS origin");
+            }
+            value.ok()
+        }))
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::HEAD])
+            .expose_headers([
+                HeaderName::from_static("content-digest"),
+                header::CONTENT_LENGTH,
+            ])
+            .max_age(Duration::from_secs(24 * 60 * 60)),
+    )
+}
+
+/// Count every request by method, route and status, with its latency
+async fn track_metrics(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let response = next.run(request).await;
+    metrics.record_request(
+        method.as_str(),
+        &route,
+        response.status().as_u16(),
+        start.elapsed(),
+    );
+    response
+}
+
+// Handler for GET /metrics
+async fn get_metrics(State(state): State<ServerState>) -> Response {
+    let Some(metrics) = &state.metrics else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let (blobs, size) = {
+        let usage = state.usage.lock().expect("Usage lock poisoned");
+        (usage.blobs, usage.size)
+    };
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(blobs, size),
+    )
+        .into_response()
 }
 
 // Handler for GET /
@@ -73,6 +433,177 @@ async fn get_index() -> Response {
     (StatusCode::OK, "GET /{CID}").into_response()
 }
 
+// Handler for GET /healthz
+// The server is alive if it can answer at all
+async fn get_healthz() -> Response {
+    (StatusCode::OK, "ok").into_response()
+}
+
+// Handler for GET /readyz
+// The server is ready for traffic if it isn't shutting down and its store
+// can be read and written
+async fn get_readyz(State(state): State<ServerState>) -> Response {
+    if state.shutting_down.load(Ordering::SeqCst) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down").into_response();
+    }
+    let store = state.store.clone();
+    match tokio::task::spawn_blocking(move || store.probe()).await {
+        Ok(Ok(())) => (StatusCode::OK, "ok").into_response(),
+        Ok(Err(err)) => {
+            tracing::error!("store is not ready: {}", err);
+            (StatusCode::SERVICE_UNAVAILABLE, "store unavailable").into_response()
+        }
+        Err(err) => {
+            tracing::error!("checking store panicked: {}", err);
+            (StatusCode::SERVICE_UNAVAILABLE, "store unavailable").into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    /// Only list blobs added at or after this Unix timestamp
+    since: Option<u64>,
+}
+
+// Handler for GET /api/v1/blobs and GET /.well-known/rasl/
+// Lists blobs ordered by CID, a page at a time. Each page ends with a cursor
+// for the next one.
+// Replicas can poll for new blobs with `since=` or `If-Modified-Since`. With
+// `If-Modified-Since`, we respond 304 Not Modified if nothing was added.
+async fn get_blobs(
+    State(state): State<ServerState>,
+    query: Query<ListParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Query(ListParams {
+        cursor,
+        limit,
+        since,
+    }) = query;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+    let listed_at = unix_now();
+
+    let if_modified_since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .map(unix_secs);
+    let not_modified_allowed = since.is_none() && cursor.is_none() && if_modified_since.is_some();
+    let since = since.or(if_modified_since);
+
+    let store = state.store.clone();
+    let listed =
+        tokio::task::spawn_blocking(move || store.list(cursor.as_deref(), since, limit)).await;
+
+    match listed {
+        Ok(Ok(blobs)) if blobs.is_empty() && not_modified_allowed => {
+            StatusCode::NOT_MODIFIED.into_response()
+        }
+        Ok(Ok(blobs)) => {
+            let cursor = match blobs.last() {
+                Some(last) if blobs.len() == limit => Some(last.cid.to_string()),
+                _ => None,
+            };
+            Json(BlobList {
+                blobs,
+                cursor,
+                listed_at,
+            })
+            .into_response()
+        }
+        Ok(Err(err)) => {
+            tracing::error!("unable to list blobs: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Unable to list content").into_response()
+        }
+        Err(err) => {
+            tracing::error!("listing blobs panicked: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Unable to list content").into_response()
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Handler for POST /api/v1/has
+// Checks which of a list of CIDs the store has, so clients can plan a sync
+// without a HEAD request per CID. Takes a JSON array of CIDs, or one CID per
+// line for any other content type.
+async fn post_has(State(state): State<ServerState>, headers: HeaderMap, body: String) -> Response {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    let cids: Vec<String> = if is_json {
+        match serde_json::from_str(&body) {
+            Ok(cids) => cids,
+            Err(_) => {
+                return (StatusCode::BAD_REQUEST, "Expected a JSON array of CIDs").into_response();
+            }
+        }
+    } else {
+        body.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+
+    if cids.len() > MAX_HAS_CIDS {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("At most {} CIDs can be checked at once", MAX_HAS_CIDS),
+        )
+            .into_response();
+    }
+
+    let mut parsed = Vec::with_capacity(cids.len());
+    for cid in &cids {
+        match Cid::parse(cid) {
+            Ok(cid) => parsed.push(cid),
+            Err(_) => {
+                return (StatusCode::BAD_REQUEST, format!("Invalid CID: {}", cid)).into_response();
+            }
+        }
+    }
+
+    let store = state.store.clone();
+    let checked = tokio::task::spawn_blocking(move || {
+        let mut response = HasResponse::default();
+        for cid in parsed {
+            match store.info(&cid)? {
+                Some(blob) => response.present.push(blob),
+                None => response.missing.push(cid),
+            }
+        }
+        Ok::<_, std::io::Error>(response)
+    })
+    .await;
+
+    match checked {
+        Ok(Ok(response)) => Json(response).into_response(),
+        Ok(Err(err)) => {
+            tracing::error!("unable to check blobs: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Unable to check content").into_response()
+        }
+        Err(err) => {
+            tracing::error!("checking blobs panicked: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Unable to check content").into_response()
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct CidParams {
     dn: Option<String>,
@@ -89,36 +620,146 @@ async fn get_cid(
         return (StatusCode::BAD_REQUEST, "Invalid CID").into_response();
     };
 
-    let file_path = state.dir.join(&cid.to_string());
+    let content_disposition = match query.dn {
+        Some(ref dn) => format!("attachment; filename=\"{}\"", dn),
+        None => "attachment".to_string(),
+    };
 
-    // Read and return file contents if it exists
+    let (body, size) = match blob_body(&state, cid).await {
+        Ok(blob) => blob,
+        Err(response) => return response,
+    };
     // Include content-digest header.
     // See <https://www.ietf.org/archive/id/draft-ietf-httpbis-digest-headers-08.html>
+    (
+        StatusCode::OK,
+        [
+            ("content-digest", format!("cid=:{}:", cid).as_str()),
+            ("content-type", content_type(&cid)),
+            ("content-disposition", &content_disposition),
+            ("content-length", size.to_string().as_str()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Media type of a blob, from the codec of its CID
+fn content_type(cid: &Cid) -> &'static str {
+    match cid.codec() {
+        Codec::Raw => "application/octet-stream",
+        Codec::Drisl => drisl::DRISL_MEDIA_TYPE,
+    }
+}
+
+/// The content of a blob as a response body, with its size, or the error
+/// response if it can't be served. With verify-on-read, the content is
+/// streamed and hashed on the way, and otherwise read in one go.
+async fn blob_body(state: &ServerState, cid: Cid) -> Result<(Body, u64), Response> {
+    let file_path = state.store.path(&cid);
+
+    if state.verify_on_read {
+        let (file, size) = match open_blob(&file_path).await {
+            Ok(Some(opened)) => opened,
+            Ok(None) => return Err((StatusCode::NOT_FOUND, "File not found").into_response()),
+            Err(err) => {
+                tracing::error!(%cid, "unable to open blob: {}", err);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        };
+        if let Some(metrics) = &state.metrics {
+            metrics.add_bytes_served(size);
+        }
+        return Ok((verified_body(state.clone(), cid, size, file), size));
+    }
+
     match fs::read(&file_path) {
         Ok(contents) => {
-            let content_disposition = match query.dn {
-                Some(ref dn) => format!("attachment; filename=\"{}\"", dn),
-                None => format!("attachment"),
-            };
-
-            return (
-                StatusCode::OK,
-                [
-                    (
-                        "content-digest",
-                        format!("cid=:{}:", cid.to_string()).as_str(),
-                    ),
-                    ("content-type", "application/octet-stream"),
-                    ("content-disposition", &content_disposition),
-                    ("content-length", contents.len().to_string().as_str()),
-                ],
-                contents,
-            )
-                .into_response();
+            let size = contents.len() as u64;
+            if let Some(metrics) = &state.metrics {
+                metrics.add_bytes_served(size);
+            }
+            Ok((Body::from(contents), size))
         }
-        Err(_) => {
-            return (StatusCode::NOT_FOUND, "File not found").into_response();
+        Err(_) => Err((StatusCode::NOT_FOUND, "File not found").into_response()),
+    }
+}
+
+/// Open a blob for streaming, with its size. None if there is no such blob.
+async fn open_blob(path: &std::path::Path) -> io::Result<Option<(tokio::fs::File, u64)>> {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    Ok(Some((file, metadata.len())))
+}
+
+/// Stream a blob, hashing it on the way.
+///
+/// The last chunk is held back until the whole blob has been hashed. If the
+/// blob doesn't match its CID, the body ends with an error instead, which
+/// aborts the connection, so the client sees a truncated transfer rather
+/// than a complete wrong file. The blob is then quarantined.
+fn verified_body(state: ServerState, cid: Cid, size: u64, file: tokio::fs::File) -> Body {
+    let chunks = ReaderStream::new(file);
+    let body = stream::unfold(
+        Some((chunks, CidHasher::new(), None::<Bytes>)),
+        move |reading| {
+            let state = state.clone();
+            async move {
+                let (mut chunks, mut hasher, mut held) = reading?;
+                loop {
+                    match chunks.next().await {
+                        Some(Ok(chunk)) => {
+                            hasher.update(&chunk);
+                            if let Some(previous) = held.replace(chunk) {
+                                return Some((Ok(previous), Some((chunks, hasher, held))));
+                            }
+                        }
+                        Some(Err(err)) => return Some((Err(err), None)),
+                        None => {
+                            let actual = hasher.finalize().with_codec(cid.codec());
+                            if actual == cid {
+                                return held.map(|last| (Ok(last), None));
+                            }
+                            quarantine_corrupted(&state, &cid, &actual, size);
+                            let err = io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("blob {} doesn't match its CID", cid),
+                            );
+                            return Some((Err(err), None));
+                        }
+                    }
+                }
+            }
+        },
+    );
+    Body::from_stream(body)
+}
+
+/// Take a blob that was found not to match its CID out of service
+fn quarantine_corrupted(state: &ServerState, cid: &Cid, actual: &Cid, size: u64) {
+    tracing::error!(%cid, %actual, "served blob doesn't match its CID");
+    if let Some(metrics) = &state.metrics {
+        metrics.add_integrity_failures(IntegrityCheck::Read, 1);
+    }
+    match state.store.quarantine(cid) {
+        Ok(path) => {
+            tracing::warn!(%cid, path = %path.display(), "quarantined blob");
+            state
+                .usage
+                .lock()
+                .expect("Usage lock poisoned")
+                .remove(cid, size);
         }
+        // Another request may have quarantined it first
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => tracing::error!(%cid, "unable to quarantine blob: {}", err),
     }
 }
 
@@ -129,11 +770,1354 @@ async fn head_cid(State(state): State<ServerState>, Path(cid): Path<String>) ->
         return (StatusCode::BAD_REQUEST, "Invalid CID").into_response();
     };
 
-    let file_path = state.dir.join(&cid.to_string());
+    // Include the same content headers as GET, so clients can check the
+    // size and digest of a blob without downloading it.
+    match fs::metadata(state.store.path(&cid)) {
+        Ok(metadata) if metadata.is_file() => (
+            StatusCode::OK,
+            [
+                ("content-digest", format!("cid=:{}:", cid)),
+                ("content-type", content_type(&cid).to_string()),
+                ("content-length", metadata.len().to_string()),
+            ],
+        )
+            .into_response(),
+        _ => (StatusCode::NOT_FOUND, "File not found").into_response(),
+    }
+}
+
+/// Media type of a raw block in the IPFS gateway specs
+const IPLD_RAW: &str = "application/vnd.ipld.raw";
+
+/// Media type of a CAR file, without parameters
+const IPLD_CAR: &str = "application/vnd.ipld.car";
+
+#[derive(Deserialize)]
+struct IpfsParams {
+    format: Option<String>,
+}
+
+/// The verifiable response types of the trustless gateway spec that we serve.
+/// Blobs are raw blocks, so a blob's DAG is just the blob, and its CAR holds
+/// that one block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GatewayFormat {
+    Raw,
+    Car,
+}
+
+impl GatewayFormat {
+    fn from_param(format: &str) -> Option<Self> {
+        match format {
+            "raw" => Some(GatewayFormat::Raw),
+            "car" => Some(GatewayFormat::Car),
+            _ => None,
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.split(';').next().unwrap_or_default().trim() {
+            IPLD_RAW => Some(GatewayFormat::Raw),
+            IPLD_CAR => Some(GatewayFormat::Car),
+            _ => None,
+        }
+    }
+}
+
+// Handler for GET /ipfs/CID
+// Serves a blob as a raw block or a CAR, as an IPFS trustless gateway does,
+// so IPFS tooling can use the server as a source.
+// See <https://specs.ipfs.tech/http-gateways/trustless-gateway/>
+async fn get_ipfs(
+    State(state): State<ServerState>,
+    Path(cid): Path<String>,
+    Query(params): Query<IpfsParams>,
+    headers: HeaderMap,
+) -> Response {
+    let (cid, format) = match gateway_request(&cid, &params, &headers) {
+        Ok(request) => request,
+        Err(err) => return err.into_response(),
+    };
+    let blob = blob_body(&state, cid).await;
+    if let Some(metrics) = &state.metrics {
+        metrics.add_gateway_lookup(blob.is_ok());
+    }
+    match blob {
+        Ok((body, size)) => gateway_response(&cid, format, size, Some(body)),
+        Err(response) => response,
+    }
+}
+
+// Handler for HEAD /ipfs/CID
+async fn head_ipfs(
+    State(state): State<ServerState>,
+    Path(cid): Path<String>,
+    Query(params): Query<IpfsParams>,
+    headers: HeaderMap,
+) -> Response {
+    let (cid, format) = match gateway_request(&cid, &params, &headers) {
+        Ok(request) => request,
+        Err(err) => return err.into_response(),
+    };
+    let size = match fs::metadata(state.store.path(&cid)) {
+        Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+        _ => None,
+    };
+    if let Some(metrics) = &state.metrics {
+        metrics.add_gateway_lookup(size.is_some());
+    }
+    match size {
+        Some(size) => gateway_response(&cid, format, size, None),
+        None => (StatusCode::NOT_FOUND, "File not found").into_response(),
+    }
+}
+
+/// Parse the CID of a gateway request, and the response type it asks for.
+/// The format parameter wins over the Accept header, and otherwise the first
+/// type in the Accept header that we serve is used.
+fn gateway_request(
+    cid: &str,
+    params: &IpfsParams,
+    headers: &HeaderMap,
+) -> Result<(Cid, GatewayFormat), (StatusCode, &'static str)> {
+    let Ok(cid) = Cid::parse(cid) else {
+        return Err((StatusCode::BAD_REQUEST, "Invalid CID"));
+    };
+    let format = match params.format.as_deref() {
+        Some(format) => GatewayFormat::from_param(format),
+        None => headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(GatewayFormat::from_media_type),
+    };
+    match format {
+        Some(format) => Ok((cid, format)),
+        None => Err((
+            StatusCode::NOT_ACCEPTABLE,
+            "Only raw blocks and CARs are served. Use ?format=raw or ?format=car",
+        )),
+    }
+}
+
+/// A gateway response for a blob of `size` bytes. The body is None for HEAD.
+fn gateway_response(cid: &Cid, format: GatewayFormat, size: u64, body: Option<Body>) -> Response {
+    let (content_type, extension, content_length, body) = match format {
+        GatewayFormat::Raw => (IPLD_RAW, "raw", size, body),
+        GatewayFormat::Car => {
+            let mut prefix = car::header(&[*cid]);
+            prefix.extend_from_slice(&car::block_prefix(cid, size));
+            let content_length = prefix.len() as u64 + size;
+            let body = body.map(|body| {
+                let prefix = stream::once(async { Ok(Bytes::from(prefix)) });
+                Body::from_stream(prefix.chain(body.into_data_stream()))
+            });
+            (car::CAR_MEDIA_TYPE, "car", content_length, body)
+        }
+    };
+    let headers = [
+        ("content-type", content_type.to_string()),
+        ("content-length", content_length.to_string()),
+        (
+            "content-disposition",
+            format!("attachment; filename=\"{}.{}\"", cid, extension),
+        ),
+        ("x-content-type-options", "nosniff".to_string()),
+        // A block can never change
+        (
+            "cache-control",
+            "public, max-age=29030400, immutable".to_string(),
+        ),
+        ("etag", format!("\"{}.{}\"", cid, extension)),
+        ("vary", "Accept".to_string()),
+    ];
+    let mut response = match body {
+        Some(body) => (headers, body).into_response(),
+        None => headers.into_response(),
+    };
+    // The digest of a raw block is its CID. A CAR has no such digest.
+    if format == GatewayFormat::Raw
+        && let Ok(digest) = HeaderValue::from_str(&format!("cid=:{}:", cid))
+    {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("content-digest"), digest);
+    }
+    response
+}
+
+// Handler for PUT /CID
+// Streams the body into the store, verifying it against the CID. DRISL
+// records must also decode, and are removed again if they don't.
+// Uploads that would exceed a quota are rejected with 413 Payload Too Large,
+// up front if the client declares a Content-Length, and otherwise as soon as
+// the limit is crossed. With a store or client quota, uploads must declare a
+// Content-Length and are rejected with 411 Length Required if they don't.
+async fn put_cid(
+    State(state): State<ServerState>,
+    Path(cid): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Err(rejection) = check_auth(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    let Ok(cid) = Cid::parse(&cid) else {
+        return (StatusCode::BAD_REQUEST, "Invalid CID").into_response();
+    };
+
+    if state.store.contains(&cid) {
+        return (StatusCode::OK, "").into_response();
+    }
+
+    let client = client_id(state.auth_token.as_deref(), &headers, Some(addr));
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if content_length.is_none() && state.quota.limits_usage() {
+        return (StatusCode::LENGTH_REQUIRED, "Content-Length required").into_response();
+    }
+
+    let mut max_size = remaining_quota(&state, &client);
+    if max_size.is_some_and(|max_size| content_length.unwrap_or(1) > max_size) {
+        // Blobs may have been removed behind our back (e.g. by `mag gc`), so
+        // check again before turning the upload away
+        match refresh_usage(&state).await {
+            Ok(()) => max_size = remaining_quota(&state, &client),
+            Err(err) => tracing::error!("unable to refresh usage: {}", err),
+        }
+    }
+    if let (Some(max_size), Some(content_length)) = (max_size, content_length)
+        && content_length > max_size
+    {
+        return too_large(max_size);
+    }
+    let is_record = cid.codec() == Codec::Drisl;
+    if is_record && content_length.is_some_and(|size| size > drisl::MAX_RECORD_SIZE) {
+        return record_too_large();
+    }
+    let reservation = match Reservation::new(&state, &client, content_length) {
+        Ok(reservation) => reservation,
+        Err(max_size) => return too_large(max_size),
+    };
+
+    let quota = reservation.limit.unwrap_or(u64::MAX);
+    let stream = body.into_data_stream();
+    let result = if is_record {
+        state
+            .store
+            .put_stream_checked(
+                &cid,
+                stream,
+                quota.min(drisl::MAX_RECORD_SIZE),
+                check_record,
+            )
+            .await
+    } else {
+        state.store.put_stream_limited(&cid, stream, quota).await
+    };
+
+    match result {
+        Ok(size) => {
+            tracing::info!(%cid, size, client, "stored blob");
+            if let Err(err) = record_owner(&state.store, &cid, &client) {
+                tracing::error!(%cid, "unable to record owner: {}", err);
+            }
+            reservation.commit(cid, size);
+            if let Some(metrics) = &state.metrics {
+                metrics.add_bytes_uploaded(size);
+            }
+            (StatusCode::CREATED, "").into_response()
+        }
+        Err(StoreError::IntegrityError { .. }) => {
+            if let Some(metrics) = &state.metrics {
+                metrics.add_integrity_failures(IntegrityCheck::Upload, 1);
+            }
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Content doesn't match CID",
+            )
+                .into_response()
+        }
+        Err(StoreError::TooLarge { max_size }) if max_size < quota => record_too_large(),
+        Err(StoreError::TooLarge { max_size }) => too_large(max_size),
+        Err(StoreError::Rejected(err)) => (StatusCode::UNPROCESSABLE_ENTITY, err).into_response(),
+        Err(StoreError::Body(err)) => (StatusCode::BAD_REQUEST, err).into_response(),
+        Err(StoreError::Io(err)) => {
+            tracing::error!(%cid, "unable to store blob: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Unable to store content").into_response()
+        }
+    }
+}
+
+/// Check that an uploaded blob is a record that decodes
+fn check_record(path: &std::path::Path) -> Result<(), StoreError> {
+    let bytes = fs::read(path)?;
+    drisl::decode(&bytes)
+        .map(|_| ())
+        .map_err(|err| StoreError::Rejected(err.to_string()))
+}
+
+fn record_too_large() -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!("Records may be at most {} bytes", drisl::MAX_RECORD_SIZE),
+    )
+        .into_response()
+}
+
+fn too_large(max_size: u64) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!(
+            "Upload exceeds quota. At most {} more bytes allowed.",
+            max_size
+        ),
+    )
+        .into_response()
+}
+
+/// Bytes set aside in the usage for an upload in progress, so concurrent
+/// uploads can't together go over the quota. Released when dropped.
+struct Reservation {
+    usage: Arc<Mutex<Usage>>,
+    client: String,
+    bytes: u64,
+    /// The most bytes the upload may have, or None if there is no limit
+    limit: Option<u64>,
+}
+
+impl Reservation {
+    /// Set aside `size` bytes for an upload from `client`. If the size isn't
+    /// known, at most the largest blob size is set aside, since only uploads
+    /// with a Content-Length are taken when the store or client is capped.
+    /// Returns the limit if there isn't room.
+    fn new(state: &ServerState, client: &str, size: Option<u64>) -> Result<Self, u64> {
+        let mut usage = state.usage.lock().expect("Usage lock poisoned");
+        let limit = state.quota.remaining(&usage, client);
+        let bytes = match (size, limit) {
+            (Some(size), Some(limit)) if size > limit => return Err(limit),
+            (Some(size), _) => size,
+            (None, limit) => limit.unwrap_or(0),
+        };
+        usage.reserve(client, bytes);
+        Ok(Self {
+            usage: state.usage.clone(),
+            client: client.to_string(),
+            bytes,
+            limit: limit.map(|_| bytes),
+        })
+    }
+
+    /// Count the uploaded blob in place of the bytes set aside for it
+    fn commit(mut self, cid: Cid, size: u64) {
+        let mut usage = self.usage.lock().expect("Usage lock poisoned");
+        usage.release(&self.client, self.bytes);
+        usage.add(cid, size, &self.client);
+        self.bytes = 0;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            self.usage
+                .lock()
+                .expect("Usage lock poisoned")
+                .release(&self.client, self.bytes);
+        }
+    }
+}
+
+/// The most bytes a client may upload right now, or None if unlimited
+fn remaining_quota(state: &ServerState, client: &str) -> Option<u64> {
+    let usage = state.usage.lock().expect("Usage lock poisoned");
+    state.quota.remaining(&usage, client)
+}
+
+/// Recount what the store holds
+async fn refresh_usage(state: &ServerState) -> io::Result<()> {
+    let store = state.store.clone();
+    let usage = tokio::task::spawn_blocking(move || Usage::scan(&store))
+        .await
+        .map_err(io::Error::other)??;
+    state
+        .usage
+        .lock()
+        .expect("Usage lock poisoned")
+        .recount(usage);
+    Ok(())
+}
+
+// Handler for GET /api/v1/usage
+async fn get_usage(
+    State(state): State<ServerState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let client = client_id(state.auth_token.as_deref(), &headers, Some(addr));
+    let usage = state.usage.lock().expect("Usage lock poisoned");
+    Json(UsageReport {
+        blobs: usage.blobs,
+        size: usage.size,
+        client_size: usage.client_size(&client),
+        client,
+        quota: state.quota.clone(),
+    })
+    .into_response()
+}
+
+// Handler for DELETE /CID
+// Pinned blobs can't be deleted. Remove the pin first.
+async fn delete_cid(
+    State(state): State<ServerState>,
+    Path(cid): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = check_auth(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    let Ok(cid) = Cid::parse(&cid) else {
+        return (StatusCode::BAD_REQUEST, "Invalid CID").into_response();
+    };
+
+    // Hold the pins lock while deleting, so the blob can't be pinned between
+    // the check and the delete
+    let deleted = with_pins(&state, move |store, pins| {
+        if pins.pinned(store)?.contains(&cid) {
+            return Ok(None);
+        }
+        let Some(blob) = store.info(&cid)? else {
+            return Ok(Some(None));
+        };
+        store.delete(&cid)?;
+        Ok(Some(Some(blob.size)))
+    })
+    .await;
+
+    match deleted {
+        Ok(Some(Some(size))) => {
+            tracing::info!(%cid, "deleted blob");
+            state
+                .usage
+                .lock()
+                .expect("Usage lock poisoned")
+                .remove(&cid, size);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(Some(None)) => (StatusCode::NOT_FOUND, "File not found").into_response(),
+        Ok(None) => (StatusCode::CONFLICT, "Content is pinned").into_response(),
+        Err(err) => {
+            tracing::error!(%cid, "unable to delete blob: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to delete content",
+            )
+                .into_response()
+        }
+    }
+}
+
+// Handler for GET /api/v1/pins
+async fn get_pins(State(state): State<ServerState>, headers: HeaderMap) -> Response {
+    if let Err(rejection) = check_auth(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    match with_pins(&state, |_, pins| Ok(pins.clone())).await {
+        Ok(pins) => Json(pins).into_response(),
+        Err(err) => {
+            tracing::error!("unable to read pins: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Unable to read pins").into_response()
+        }
+    }
+}
+
+// Handler for PUT /api/v1/pins/NAME
+// The body is the CID to pin. The content doesn't have to be in the store
+// yet, so clients can pin before they upload.
+async fn put_pin(
+    State(state): State<ServerState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if let Err(rejection) = check_auth(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    let Ok(cid) = Cid::parse(body.trim()) else {
+        return (StatusCode::BAD_REQUEST, "Invalid CID").into_response();
+    };
+
+    let replaced = with_pins(&state, {
+        let name = name.clone();
+        move |_, pins| Ok(pins.add(&name, cid))
+    })
+    .await;
 
-    if file_path.exists() {
-        (StatusCode::OK, "").into_response()
+    match replaced {
+        Ok(None) => {
+            tracing::info!(%cid, name, "added pin");
+            StatusCode::CREATED.into_response()
+        }
+        Ok(Some(_)) => {
+            tracing::info!(%cid, name, "updated pin");
+            StatusCode::OK.into_response()
+        }
+        Err(err) => {
+            tracing::error!("unable to save pins: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Unable to save pins").into_response()
+        }
+    }
+}
+
+// Handler for DELETE /api/v1/pins/NAME
+async fn delete_pin(
+    State(state): State<ServerState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = check_auth(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    let removed = with_pins(&state, {
+        let name = name.clone();
+        move |_, pins| Ok(pins.remove(&name))
+    })
+    .await;
+
+    match removed {
+        Ok(Some(cid)) => {
+            tracing::info!(%cid, name, "removed pin");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "Pin not found").into_response(),
+        Err(err) => {
+            tracing::error!("unable to save pins: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Unable to save pins").into_response()
+        }
+    }
+}
+
+/// Run a blocking operation on the store's pins while holding the pins
+/// lock. Changes the operation makes to the pins are saved.
+async fn with_pins<T, F>(state: &ServerState, operation: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Store, &mut PinSet) -> io::Result<T> + Send + 'static,
+{
+    let store = state.store.clone();
+    tokio::task::spawn_blocking(move || {
+        let _lock = PinSet::lock(&store)?;
+        let mut pins = PinSet::load(&store)?;
+        let before = pins.clone();
+        let result = operation(&store, &mut pins)?;
+        if pins != before {
+            pins.save(&store)?;
+        }
+        Ok(result)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Check that the request carries the server's bearer token.
+/// Write operations are forbidden if the server has no token configured.
+fn check_auth(state: &ServerState, headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+    let Some(expected) = &state.auth_token else {
+        return Err((StatusCode::FORBIDDEN, "Writes are disabled"));
+    };
+    if has_token(headers, expected) {
+        Ok(())
     } else {
-        (StatusCode::NOT_FOUND, "File not found").into_response()
+        Err((StatusCode::UNAUTHORIZED, "Invalid or missing token"))
+    }
+}
+
+/// Whether a request carries the given bearer token
+fn has_token(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+}
+
+/// Who a request counts against, for quotas and rate limits alike.
+/// Clients that present the auth token count together as `token`, and
+/// every other client counts by IP address.
+pub(crate) fn client_id(
+    auth_token: Option<&str>,
+    headers: &HeaderMap,
+    addr: Option<SocketAddr>,
+) -> String {
+    match auth_token {
+        Some(expected) if has_token(headers, expected) => "token".to_string(),
+        _ => addr.map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string()),
+    }
+}
+
+/// Bearer token of the servers started by `test_server`
+#[cfg(test)]
+pub(crate) const TOKEN: &str = "secret";
+
+/// Serve a store in a temp directory on a random local port
+#[cfg(test)]
+pub(crate) async fn test_server(name: &str) -> (Url, crate::store::TestStore) {
+    test_server_with(name, |_| {}).await
+}
+
+#[cfg(test)]
+async fn test_server_with(
+    name: &str,
+    configure: impl FnOnce(&mut ServerState),
+) -> (Url, crate::store::TestStore) {
+    let store = crate::store::test_store("server", name);
+    let mut state = ServerState {
+        store: store.clone(),
+        auth_token: Some(TOKEN.to_string()),
+        enable_listing: true,
+        quota: Quota::default(),
+        usage: Arc::default(),
+        verify_on_read: false,
+        metrics: None,
+        shutting_down: Arc::default(),
+        cors_origins: Vec::new(),
+        rate_limiter: None,
+    };
+    configure(&mut state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (Url::parse(&format!("http://{}/", addr)).unwrap(), store)
+}
+
+/// Count the files in a directory, ignoring subdirectories like the
+/// store's metadata directory
+#[cfg(test)]
+pub(crate) fn count_files(dir: &std::path::Path) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_type().unwrap().is_file())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::RateLimit;
+    use crate::request::header_content_length;
+
+    #[tokio::test]
+    async fn test_put_then_get() {
+        let (base, _store) = test_server("put-get").await;
+        let cid = Cid::of(b"hello world");
+        let url = base.join(&cid.to_string()).unwrap();
+        let client = reqwest::Client::new();
+
+        let response = client
+            .put(url.clone())
+            .bearer_auth(TOKEN)
+            .body("hello world")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = client.head(url.clone()).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_content_length(&response), Some(11));
+
+        let body = client.get(url).send().await.unwrap().bytes().await.unwrap();
+        assert_eq!(&body[..], b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_records() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Release {
+            name: String,
+            files: Vec<Cid>,
+        }
+
+        let (base, store) = test_server("records").await;
+        let client = reqwest::Client::new();
+        let release = Release {
+            name: "v1".to_string(),
+            files: vec![Cid::of(b"hello world")],
+        };
+
+        let cid = crate::request::put_record(&client, &base, &release, Some(TOKEN))
+            .await
+            .unwrap();
+        assert_eq!(cid.codec(), Codec::Drisl);
+        let url = base.join(&cid.to_string()).unwrap();
+        let fetched: Release = crate::request::get_record(&client, &url, &cid)
+            .await
+            .unwrap();
+        assert_eq!(fetched, release);
+
+        let response = client.head(url).send().await.unwrap();
+        assert_eq!(response.headers()["content-type"], drisl::DRISL_MEDIA_TYPE);
+
+        // Hashes to its CID, but isn't a record
+        let not_record = drisl::cid_of(b"hello world");
+        let response = client
+            .put(base.join(&not_record.to_string()).unwrap())
+            .bearer_auth(TOKEN)
+            .body("hello world")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(!store.contains(&not_record));
+
+        // Too large to be a record, sent without a length, is cut off
+        let too_large = vec![0u8; drisl::MAX_RECORD_SIZE as usize + 1];
+        let too_large_cid = drisl::cid_of(&too_large);
+        let stream = futures_util::stream::iter([Ok::<_, std::io::Error>(too_large)]);
+        let response = client
+            .put(base.join(&too_large_cid.to_string()).unwrap())
+            .bearer_auth(TOKEN)
+            .body(reqwest::Body::wrap_stream(stream))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(!store.contains(&too_large_cid));
+    }
+
+    #[tokio::test]
+    async fn test_put_rejects_wrong_content() {
+        let (base, store) = test_server("put-wrong").await;
+        let cid = Cid::of(b"hello world");
+        let url = base.join(&cid.to_string()).unwrap();
+
+        let response = reqwest::Client::new()
+            .put(url)
+            .bearer_auth(TOKEN)
+            .body("goodbye")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(!store.contains(&cid));
+    }
+
+    #[tokio::test]
+    async fn test_put_requires_token() {
+        let (base, store) = test_server("put-auth").await;
+        let cid = Cid::of(b"hello world");
+        let url = base.join(&cid.to_string()).unwrap();
+        let client = reqwest::Client::new();
+
+        let response = client
+            .put(url.clone())
+            .body("hello world")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = client
+            .put(url)
+            .bearer_auth("wrong")
+            .body("hello world")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(!store.contains(&cid));
+    }
+
+    #[tokio::test]
+    async fn test_push_cid() {
+        use crate::request::{Progress, PushOutcome, Throttle, push_cid};
+
+        let (base, store) = test_server("push").await;
+        let path = store.dir().join("upload.txt");
+        fs::write(&path, "hello world").unwrap();
+        let cid = Cid::of(b"hello world");
+        let client = reqwest::Client::new();
+
+        let first = push_cid(
+            &client,
+            &base,
+            &cid,
+            &path,
+            Some(TOKEN),
+            &Progress::default(),
+            &Throttle::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(first, PushOutcome::Uploaded);
+        assert!(store.contains(&cid));
+
+        let second = push_cid(
+            &client,
+            &base,
+            &cid,
+            &path,
+            Some(TOKEN),
+            &Progress::default(),
+            &Throttle::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(second, PushOutcome::AlreadyPresent);
+    }
+
+    #[tokio::test]
+    async fn test_list_blobs() {
+        use crate::request::list_blobs;
+
+        let (base, store) = test_server("list").await;
+        for content in ["a", "bb", "ccc"] {
+            fs::write(store.path(&Cid::of(content)), content).unwrap();
+        }
+        let client = reqwest::Client::new();
+
+        let first = list_blobs(&client, &base, None, None, Some(2))
+            .await
+            .unwrap();
+        assert_eq!(first.blobs.len(), 2);
+        let cursor = first.cursor.expect("A full page should have a cursor");
+
+        let last = list_blobs(&client, &base, Some(&cursor), None, Some(2))
+            .await
+            .unwrap();
+        assert_eq!(last.blobs.len(), 1);
+        assert_eq!(last.cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_list_blobs_since() {
+        use crate::request::list_blobs;
+
+        let (base, store) = test_server("list-since").await;
+        let cid = Cid::of("hello world");
+        fs::write(store.path(&cid), "hello world").unwrap();
+        let client = reqwest::Client::new();
+
+        let all = list_blobs(&client, &base, None, None, None).await.unwrap();
+        assert_eq!(all.blobs.len(), 1);
+        assert!(all.blobs[0].added <= all.listed_at);
+
+        let later = list_blobs(&client, &base, None, Some(all.listed_at + 1), None)
+            .await
+            .unwrap();
+        assert!(later.blobs.is_empty());
+
+        // The same listing is also served at the RASL well-known path
+        let url = base.join(".well-known/rasl/").unwrap();
+        let since = httpdate::fmt_http_date(SystemTime::now() + std::time::Duration::from_secs(1));
+        let response = client
+            .get(url.clone())
+            .header(header::IF_MODIFIED_SINCE, since)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_has() {
+        use crate::request::has_cids;
+
+        let (base, store) = test_server("has").await;
+        let present = Cid::of("hello world");
+        let missing = Cid::of("goodbye");
+        fs::write(store.path(&present), "hello world").unwrap();
+        let client = reqwest::Client::new();
+
+        let has = has_cids(&client, &base, &[present, missing]).await.unwrap();
+        assert_eq!(has.present.len(), 1);
+        assert_eq!(has.present[0].cid, present);
+        assert_eq!(has.present[0].size, 11);
+        assert_eq!(has.missing, vec![missing]);
+
+        // One CID per line works too
+        let url = base.join("api/v1/has").unwrap();
+        let body = format!("{}\n\n{}\n", present, missing);
+        let response = client.post(url.clone()).body(body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let has: HasResponse = response.json().await.unwrap();
+        assert_eq!(has.missing, vec![missing]);
+
+        let response = client.post(url).body("not-a-cid").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_delete_respects_pins() {
+        let (base, store) = test_server("delete").await;
+        let leaf = Cid::of("leaf");
+        let record_content = drisl::to_vec(&[leaf]).unwrap();
+        let record = drisl::cid_of(&record_content);
+        fs::write(store.path(&leaf), "leaf").unwrap();
+        fs::write(store.path(&record), &record_content).unwrap();
+        let leaf_url = base.join(&leaf.to_string()).unwrap();
+        let pin_url = base.join("api/v1/pins/release").unwrap();
+        let client = reqwest::Client::new();
+
+        let response = client.delete(leaf_url.clone()).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = client
+            .put(pin_url.clone())
+            .bearer_auth(TOKEN)
+            .body(record.to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // The leaf is pinned through the record
+        let response = client
+            .delete(leaf_url.clone())
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(store.contains(&leaf));
+
+        let response = client
+            .delete(pin_url)
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = client
+            .delete(leaf_url.clone())
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!store.contains(&leaf));
+
+        let response = client
+            .delete(leaf_url)
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_put_enforces_quota() {
+        let (base, store) = test_server_with("quota", |state| {
+            state.quota = Quota {
+                max_blob_size: Some(8),
+                max_store_size: Some(10),
+                max_client_size: None,
+            }
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let put = |content: &'static str| {
+            let url = base.join(&Cid::of(content).to_string()).unwrap();
+            client.put(url).bearer_auth(TOKEN).body(content).send()
+        };
+
+        // Too large for a single blob, rejected from the Content-Length
+        let response = put("hello world").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Without a Content-Length, rejected up front, since the store is capped
+        let url = base.join(&Cid::of("hello world").to_string()).unwrap();
+        let body = reqwest::Body::wrap_stream(futures_util::stream::iter(vec![
+            Ok::<_, std::io::Error>("hello "),
+            Ok("world"),
+        ]));
+        let response = client
+            .put(url)
+            .bearer_auth(TOKEN)
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::LENGTH_REQUIRED);
+
+        let response = put("hello").await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Only 5 bytes left in the store
+        let response = put("goodbye").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let usage: UsageReport = client
+            .get(base.join("api/v1/usage").unwrap())
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(usage.blobs, 1);
+        assert_eq!(usage.size, 5);
+        assert_eq!(usage.client_size, 5);
+        assert_eq!(usage.quota.max_store_size, Some(10));
+
+        // No temp files are left behind
+        assert_eq!(count_files(store.dir()), 1);
+    }
+
+    #[tokio::test]
+    async fn test_put_without_length_is_limited_while_streaming() {
+        let (base, store) = test_server_with("quota-stream", |state| {
+            state.quota = Quota {
+                max_blob_size: Some(8),
+                ..Quota::default()
+            }
+        })
+        .await;
+        let url = base.join(&Cid::of("hello world").to_string()).unwrap();
+        let body = reqwest::Body::wrap_stream(futures_util::stream::iter(vec![
+            Ok::<_, std::io::Error>("hello "),
+            Ok("world"),
+        ]));
+        let response = reqwest::Client::new()
+            .put(url)
+            .bearer_auth(TOKEN)
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(count_files(store.dir()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_verify_on_read() {
+        let (base, store) = test_server_with("verify", |state| state.verify_on_read = true).await;
+        let client = reqwest::Client::new();
+
+        // Larger than one chunk of the file stream, so some bytes are sent
+        // before the mismatch is found
+        let good = vec![7u8; 100_000];
+        let good_cid = Cid::of(&good);
+        fs::write(store.path(&good_cid), &good).unwrap();
+        let response = client
+            .get(base.join(&good_cid.to_string()).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), good);
+
+        let rotten_cid = Cid::of(&good[1..]);
+        fs::write(store.path(&rotten_cid), &good).unwrap();
+        let url = base.join(&rotten_cid.to_string()).unwrap();
+        let response = client.get(url.clone()).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.bytes().await.is_err());
+
+        assert!(!store.contains(&rotten_cid));
+        assert_eq!(count_files(&store.quarantine_dir()), 1);
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (base, _store) = test_server_with("metrics", |state| {
+            state.metrics = Some(Arc::new(Metrics::new()))
+        })
+        .await;
+        let client = reqwest::Client::new();
+
+        let cid = Cid::of(b"hello world");
+        let url = base.join(&cid.to_string()).unwrap();
+        client
+            .put(url.clone())
+            .bearer_auth(TOKEN)
+            .body("hello world")
+            .send()
+            .await
+            .unwrap();
+        client.get(url.clone()).send().await.unwrap();
+        let wrong = base.join(&Cid::of(b"other").to_string()).unwrap();
+        client
+            .put(wrong)
+            .bearer_auth(TOKEN)
+            .body("hello world")
+            .send()
+            .await
+            .unwrap();
+
+        let response = client
+            .get(base.join("metrics").unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let text = response.text().await.unwrap();
+        for line in [
+            r#"magnetize_http_requests_total{method="GET",route="/{cid}",status="200"} 1"#,
+            r#"magnetize_http_requests_total{method="PUT",route="/{cid}",status="201"} 1"#,
+            r#"magnetize_http_requests_total{method="PUT",route="/{cid}",status="422"} 1"#,
+            "magnetize_bytes_served_total 11",
+            "magnetize_bytes_uploaded_total 11",
+            r#"magnetize_integrity_failures_total{check="upload"} 1"#,
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_disabled() {
+        let (base, _store) = test_server("no-metrics").await;
+        let response = reqwest::get(base.join("metrics").unwrap()).await.unwrap();
+        assert_ne!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_health_and_readiness() {
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let flag = shutting_down.clone();
+        let (base, store) = test_server_with("ready", |state| state.shutting_down = flag).await;
+        let get = |path: &str| reqwest::get(base.join(path).unwrap());
+
+        assert_eq!(get("healthz").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(get("readyz").await.unwrap().status(), StatusCode::OK);
+
+        shutting_down.store(true, Ordering::SeqCst);
+        assert_eq!(get("healthz").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            get("readyz").await.unwrap().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        shutting_down.store(false, Ordering::SeqCst);
+        fs::remove_dir_all(store.dir()).unwrap();
+        assert_eq!(
+            get("readyz").await.unwrap().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let (base, store) = test_server_with("cors", |state| {
+            state.cors_origins = vec!["https://app.example".to_string()]
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let cid = Cid::of(b"hello world");
+        fs::write(store.path(&cid), "hello world").unwrap();
+        let url = base.join(&cid.to_string()).unwrap();
+
+        let preflight = client
+            .request(reqwest::Method::OPTIONS, url.clone())
+            .header("origin", "https://app.example")
+            .header("access-control-request-method", "GET")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(preflight.status(), StatusCode::OK);
+        assert_eq!(
+            preflight.headers()["access-control-allow-origin"],
+            "https://app.example"
+        );
+
+        let response = client
+            .get(url.clone())
+            .header("origin", "https://app.example")
+            .send()
+            .await
+            .unwrap();
+        let headers = response.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://app.example"
+        );
+        let exposed = headers["access-control-expose-headers"].to_str().unwrap();
+        assert!(exposed.contains("content-digest"));
+        assert_eq!(headers["cross-origin-resource-policy"], "cross-origin");
+
+        let other_origin = client
+            .get(url)
+            .header("origin", "https://evil.example")
+            .send()
+            .await
+            .unwrap();
+        assert!(
+            !other_origin
+                .headers()
+                .contains_key("access-control-allow-origin")
+        );
+
+        let api = client
+            .get(base.join("api/v1/usage").unwrap())
+            .header("origin", "https://app.example")
+            .send()
+            .await
+            .unwrap();
+        assert!(!api.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn test_ipfs_gateway() {
+        use crate::magnet::MagnetLink;
+        use crate::request::get_and_check_cid;
+
+        let (base, store) = test_server_with("ipfs", |state| {
+            state.metrics = Some(Arc::new(Metrics::new()))
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let cid = Cid::of(b"hello world");
+        fs::write(store.path(&cid), "hello world").unwrap();
+        let url = base.join(&format!("ipfs/{}", cid)).unwrap();
+
+        let response = client
+            .get(format!("{}?format=raw", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers["content-type"], "application/vnd.ipld.raw");
+        assert_eq!(headers["etag"], format!("\"{}.raw\"", cid));
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(response.text().await.unwrap(), "hello world");
+
+        let accept = client
+            .head(url.clone())
+            .header("accept", "application/json, application/vnd.ipld.raw;q=0.9")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(accept.status(), StatusCode::OK);
+        assert_eq!(accept.headers()["content-length"], "11");
+
+        let unsupported = client.get(url.clone()).send().await.unwrap();
+        assert_eq!(unsupported.status(), StatusCode::NOT_ACCEPTABLE);
+        let unsupported = client
+            .get(format!("{}?format=dag-json", url))
+            .header("accept", "application/vnd.ipld.raw")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(unsupported.status(), StatusCode::NOT_ACCEPTABLE);
+
+        // The format parameter wins over the Accept header
+        let response = client
+            .get(format!("{}?format=car", url))
+            .header("accept", "application/vnd.ipld.raw")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/vnd.ipld.car; version=1"
+        );
+        assert!(!response.headers().contains_key("content-digest"));
+        let length: usize = response.headers()["content-length"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let bytes = response.bytes().await.unwrap();
+        assert_eq!(bytes.len(), length);
+        let mut reader = crate::car::CarReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.roots(), [cid]);
+        let mut block = reader.next_block().unwrap().unwrap();
+        assert_eq!(block.cid(), cid);
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut block, &mut data).unwrap();
+        assert_eq!(data, b"hello world");
+        assert!(reader.next_block().unwrap().is_none());
+
+        let missing = client
+            .get(
+                base.join(&format!("ipfs/{}?format=raw", Cid::of(b"nope")))
+                    .unwrap(),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+        // Magnet links can use the server as a gateway
+        let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:cid:{}&gw={}", cid, base)).unwrap();
+        let body = get_and_check_cid(&client, &magnet.urls()[0], &cid)
+            .await
+            .unwrap();
+        assert_eq!(body, b"hello world");
+
+        let metrics = client
+            .get(base.join("metrics").unwrap())
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains(r#"magnetize_gateway_cache_requests_total{result="hit"} 4"#));
+        assert!(metrics.contains(r#"magnetize_gateway_cache_requests_total{result="miss"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits() {
+        let limits = RateLimits {
+            read: RateLimit {
+                requests_per_sec: None,
+                bytes_per_sec: Some(100_000),
+            },
+            write: RateLimit {
+                requests_per_sec: Some(1.0),
+                bytes_per_sec: None,
+            },
+            max_concurrent_requests: None,
+        };
+        let (base, store) = test_server_with("rate", |state| {
+            state.rate_limiter = Some(Arc::new(RateLimiter::new(limits, None)))
+        })
+        .await;
+        let client = reqwest::Client::new();
+
+        let put = |content: &'static str| {
+            let url = base.join(&Cid::of(content).to_string()).unwrap();
+            client.put(url).bearer_auth(TOKEN).body(content).send()
+        };
+        assert_eq!(put("one").await.unwrap().status(), StatusCode::CREATED);
+        let limited = put("two").await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers()["retry-after"], "1");
+
+        // A second's worth of bytes is sent right away, and the rest once
+        // the client has earned it
+        let content = vec![1u8; 150_000];
+        let cid = Cid::of(&content);
+        fs::write(store.path(&cid), &content).unwrap();
+        let start = std::time::Instant::now();
+        let response = client
+            .get(base.join(&cid.to_string()).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.bytes().await.unwrap(), content);
+        assert!(start.elapsed() >= Duration::from_millis(400));
     }
 }
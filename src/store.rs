@@ -0,0 +1,686 @@
+use crate::api::BlobInfo;
+use crate::cid::{Cid, CidHasher};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+/// Directory inside the store for metadata, like pins. Its name can never be
+/// mistaken for a CID.
+const META_DIR: &str = ".magnetize";
+
+/// Suffix for files that are still being written.
+/// Anything with this suffix is safe to delete when no write is in progress.
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+/// Temp files that haven't been written to for this long belong to writes
+/// that were interrupted. Younger ones may belong to writes in progress.
+pub const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A directory's modified time may not change for changes made within the
+/// same tick of its clock, which is up to 2 seconds on some filesystems. A
+/// listing of a directory is only reused if the directory hadn't changed
+/// for this long before it was read.
+const LISTING_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// A directory of content-addressed blobs, each stored in a file named after
+/// its CID.
+#[derive(Debug, Clone)]
+pub struct Store {
+    dir: PathBuf,
+    /// The CIDs in the store, shared by clones so pages of a listing don't
+    /// each read the whole directory
+    listing: Arc<Mutex<Option<Listing>>>,
+}
+
+/// The sorted CIDs in the store's directory, as of its modified time
+#[derive(Debug)]
+struct Listing {
+    dir_modified: SystemTime,
+    names: Arc<Vec<String>>,
+}
+
+impl Store {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            listing: Arc::default(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Directory for metadata about the store, like pins
+    pub fn meta_dir(&self) -> PathBuf {
+        self.dir.join(META_DIR)
+    }
+
+    /// Directory that corrupted blobs are moved to, so they are no longer
+    /// served but can still be inspected
+    pub fn quarantine_dir(&self) -> PathBuf {
+        self.meta_dir().join("quarantine")
+    }
+
+    /// Move a blob that doesn't match its CID out of the store and into
+    /// quarantine. Returns where the blob was moved to.
+    pub fn quarantine(&self, cid: &Cid) -> io::Result<PathBuf> {
+        let dir = self.quarantine_dir();
+        std::fs::create_dir_all(&dir)?;
+        let nonce: u64 = rand::random();
+        let path = dir.join(format!("{}.{:016x}", cid, nonce));
+        std::fs::rename(self.path(cid), &path)?;
+        Ok(path)
+    }
+
+    /// Path of the file holding the blob for a CID
+    pub fn path(&self, cid: &Cid) -> PathBuf {
+        self.dir.join(cid.to_string())
+    }
+
+    /// Check if the store has the blob for a CID
+    pub fn contains(&self, cid: &Cid) -> bool {
+        self.path(cid).is_file()
+    }
+
+    /// Remove a blob from the store.
+    /// Returns false if the store didn't have it.
+    pub fn delete(&self, cid: &Cid) -> io::Result<bool> {
+        match std::fs::remove_file(self.path(cid)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the size and added time of a blob, or None if the store doesn't
+    /// have it
+    pub fn info(&self, cid: &Cid) -> io::Result<Option<BlobInfo>> {
+        match std::fs::metadata(self.path(cid)) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(blob_info(*cid, &metadata)?)),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// List blobs in the store, ordered by CID.
+    /// Starts after the CID `after` (if given), and returns at most `limit`
+    /// blobs, so large stores can be listed page by page. If `since` is given,
+    /// only blobs added at or after that Unix timestamp are listed.
+    /// Temp files and anything else that isn't named after a CID are skipped.
+    pub fn list(
+        &self,
+        after: Option<&str>,
+        since: Option<u64>,
+        limit: usize,
+    ) -> io::Result<Vec<BlobInfo>> {
+        let names = self.names()?;
+        let start = match after {
+            Some(after) => names.partition_point(|name| name.as_str() <= after),
+            None => 0,
+        };
+        let mut blobs = Vec::new();
+        for name in &names[start..] {
+            if blobs.len() >= limit {
+                break;
+            }
+            let Ok(cid) = Cid::parse(name) else {
+                continue;
+            };
+            let metadata = match std::fs::metadata(self.dir.join(name)) {
+                Ok(metadata) if metadata.is_file() => metadata,
+                Ok(_) => continue,
+                // Deleted since the directory was read
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let blob = blob_info(cid, &metadata)?;
+            if since.is_some_and(|since| blob.added < since) {
+                continue;
+            }
+            blobs.push(blob);
+        }
+        Ok(blobs)
+    }
+
+    /// The sorted names of the CIDs in the store. The directory is only read
+    /// again once it has changed.
+    fn names(&self) -> io::Result<Arc<Vec<String>>> {
+        let dir_modified = std::fs::metadata(&self.dir)?.modified()?;
+        let mut listing = self.listing.lock().expect("Store listing lock poisoned");
+        if let Some(listing) = listing.as_ref()
+            && listing.dir_modified == dir_modified
+        {
+            return Ok(listing.names.clone());
+        }
+
+        let read_at = SystemTime::now();
+        let mut names = Vec::new();
+        for dir_entry in std::fs::read_dir(&self.dir)? {
+            let file_name = dir_entry?.file_name();
+            if let Some(name) = file_name.to_str()
+                && Cid::parse(name).is_ok()
+            {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        let names = Arc::new(names);
+
+        let settled = read_at
+            .duration_since(dir_modified)
+            .is_ok_and(|age| age >= LISTING_SETTLE_TIME);
+        *listing = settled.then(|| Listing {
+            dir_modified,
+            names: names.clone(),
+        });
+        Ok(names)
+    }
+
+    /// Check that the store can be read and written, by writing, reading
+    /// back and removing a small file in the metadata directory
+    pub fn probe(&self) -> io::Result<()> {
+        std::fs::read_dir(&self.dir)?;
+        let dir = self.meta_dir();
+        std::fs::create_dir_all(&dir)?;
+        let nonce: u64 = rand::random();
+        let path = dir.join(format!("probe.{:016x}{}", nonce, TEMP_SUFFIX));
+        let contents = nonce.to_be_bytes();
+        std::fs::write(&path, contents)?;
+        let read = std::fs::read(&path);
+        std::fs::remove_file(&path)?;
+        if read? != contents {
+            return Err(io::Error::other("probe file read back differently"));
+        }
+        Ok(())
+    }
+
+    /// Remove the temp files of writes that were interrupted long ago.
+    /// Recent temp files are left alone, since they may belong to another
+    /// process writing to the store. Returns the removed files.
+    pub fn remove_stale_temp_files(&self) -> io::Result<Vec<PathBuf>> {
+        let now = SystemTime::now();
+        let mut removed = Vec::new();
+        for dir_entry in std::fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            if !dir_entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(TEMP_SUFFIX)
+            {
+                continue;
+            }
+            let metadata = match dir_entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let age = now
+                .duration_since(metadata.modified()?)
+                .unwrap_or(Duration::ZERO);
+            if !metadata.is_file() || age < STALE_TEMP_AGE {
+                continue;
+            }
+            match std::fs::remove_file(dir_entry.path()) {
+                Ok(()) => removed.push(dir_entry.path()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// A unique temp path to write a blob to before it is verified
+    fn temp_path(&self, cid: &Cid) -> PathBuf {
+        self.temp_path_named(&cid.to_string())
+    }
+
+    fn temp_path_named(&self, name: &str) -> PathBuf {
+        let nonce: u64 = rand::random();
+        self.dir
+            .join(format!("{}.{:016x}{}", name, nonce, TEMP_SUFFIX))
+    }
+
+    /// Write a stream of bytes into the store under a CID.
+    ///
+    /// The bytes are hashed while they are written to a temp file. The temp
+    /// file is only moved into place if the bytes match the CID, so the store
+    /// never holds a blob under the wrong CID, and readers never see a
+    /// partial blob. On any error, or if the returned future is dropped
+    /// before it completes, the temp file is removed.
+    ///
+    /// Returns the size of the blob in bytes.
+    pub async fn put_stream<S, E>(&self, cid: &Cid, stream: S) -> Result<u64, StoreError>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        E: std::fmt::Display,
+    {
+        self.put_stream_limited(cid, stream, u64::MAX).await
+    }
+
+    /// Like `put_stream`, but gives up with `StoreError::TooLarge` as soon as
+    /// more than `max_size` bytes have been received.
+    pub async fn put_stream_limited<S, E>(
+        &self,
+        cid: &Cid,
+        stream: S,
+        max_size: u64,
+    ) -> Result<u64, StoreError>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        E: std::fmt::Display,
+    {
+        self.put_stream_checked(cid, stream, max_size, |_| Ok(()))
+            .await
+    }
+
+    /// Like `put_stream_limited`, but also only moves the blob into place
+    /// if `check` accepts the complete, verified temp file. `check` can turn
+    /// it away with `StoreError::Rejected`.
+    pub async fn put_stream_checked<S, E, F>(
+        &self,
+        cid: &Cid,
+        stream: S,
+        max_size: u64,
+        check: F,
+    ) -> Result<u64, StoreError>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        E: std::fmt::Display,
+        F: FnOnce(&Path) -> Result<(), StoreError>,
+    {
+        let temp = TempFile(self.temp_path(cid));
+        let size = self.write_temp(&temp.0, cid, stream, max_size).await?;
+        check(&temp.0)?;
+        tokio::fs::rename(&temp.0, self.path(cid)).await?;
+        Ok(size)
+    }
+
+    /// Like `put_stream`, for blocking code reading the bytes from `reader`
+    pub fn put_reader(&self, cid: &Cid, reader: impl Read) -> Result<u64, StoreError> {
+        let temp = TempFile(self.temp_path(cid));
+        let (file, hasher, size) = write_temp_reader(&temp.0, reader, u64::MAX)?;
+        verify(hasher, cid)?;
+        file.set_modified(SystemTime::now())?;
+        std::fs::rename(&temp.0, self.path(cid))?;
+        Ok(size)
+    }
+
+    /// Add the bytes read from `reader` under their raw CID, which is only
+    /// known once they have all been read. Like `put_reader`, readers never
+    /// see a partial blob. Fails with `StoreError::TooLarge` as soon as
+    /// there are more than `max_size` bytes, leaving nothing behind.
+    ///
+    /// Returns the CID and the size of the blob in bytes.
+    pub fn add_reader(&self, reader: impl Read, max_size: u64) -> Result<(Cid, u64), StoreError> {
+        let temp = TempFile(self.temp_path_named("add"));
+        let (file, hasher, size) = write_temp_reader(&temp.0, reader, max_size)?;
+        let cid = hasher.finalize();
+        file.set_modified(SystemTime::now())?;
+        std::fs::rename(&temp.0, self.path(&cid))?;
+        Ok((cid, size))
+    }
+
+    async fn write_temp<S, E>(
+        &self,
+        temp_path: &Path,
+        cid: &Cid,
+        stream: S,
+        max_size: u64,
+    ) -> Result<u64, StoreError>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        E: std::fmt::Display,
+    {
+        let mut file = tokio::fs::File::create(temp_path).await?;
+        let mut hasher = CidHasher::new();
+        let mut size: u64 = 0;
+
+        let mut stream = std::pin::pin!(stream);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|err| StoreError::Body(err.to_string()))?;
+            size += chunk.len() as u64;
+            if size > max_size {
+                return Err(StoreError::TooLarge { max_size });
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        verify(hasher, cid)?;
+
+        // The modified time is when the blob was added, so it should be when
+        // it is moved into place, not when its last byte arrived
+        file.into_std().await.set_modified(SystemTime::now())?;
+        Ok(size)
+    }
+}
+
+/// Copy at most `max_size` bytes from `reader` to a temp file, hashing
+/// them on the way. Returns the synced file, the hasher and the size.
+fn write_temp_reader(
+    path: &Path,
+    mut reader: impl Read,
+    max_size: u64,
+) -> Result<(std::fs::File, CidHasher, u64), StoreError> {
+    let mut file = std::fs::File::create(path)?;
+    let mut hasher = CidHasher::new();
+    let mut size: u64 = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        size += len as u64;
+        if size > max_size {
+            return Err(StoreError::TooLarge { max_size });
+        }
+        hasher.update(&buf[..len]);
+        file.write_all(&buf[..len])?;
+    }
+    file.sync_all()?;
+    Ok((file, hasher, size))
+}
+
+/// Check that the hashed bytes match a CID
+fn verify(hasher: CidHasher, cid: &Cid) -> Result<(), StoreError> {
+    let actual = hasher.finalize().with_codec(cid.codec());
+    if actual != *cid {
+        return Err(StoreError::IntegrityError {
+            expected: *cid,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// A temp file that is removed when dropped, unless it was moved away
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn blob_info(cid: Cid, metadata: &std::fs::Metadata) -> io::Result<BlobInfo> {
+    // Blobs are never modified after they are moved into place, so the
+    // modified time is when they were added. Blobs copied into the store by
+    // other means should be copied without preserving their times.
+    let added = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok(BlobInfo {
+        cid,
+        size: metadata.len(),
+        added,
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Error reading body: {0}")]
+    Body(String),
+    #[error("Content doesn't match CID. Expected: {expected} Got: {actual}")]
+    IntegrityError { expected: Cid, actual: Cid },
+    #[error("Content is larger than the limit of {max_size} bytes")]
+    TooLarge { max_size: u64 },
+    #[error("Content rejected: {0}")]
+    Rejected(String),
+}
+
+/// A store in its own temp directory, which is removed when this is dropped,
+/// so tests that fail don't leave directories behind
+#[cfg(test)]
+pub(crate) struct TestStore(Store);
+
+#[cfg(test)]
+impl std::ops::Deref for TestStore {
+    type Target = Store;
+
+    fn deref(&self) -> &Store {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(self.0.dir());
+    }
+}
+
+/// Make an empty store for a test. `prefix` names the module and `name` the
+/// test, so tests running at the same time get different directories.
+#[cfg(test)]
+pub(crate) fn test_store(prefix: &str, name: &str) -> TestStore {
+    let dir = std::env::temp_dir().join(format!(
+        "magnetize-{}-test-{}-{}",
+        prefix,
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    TestStore(Store::new(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(chunks: &[&'static str]) -> impl Stream<Item = Result<Bytes, String>> {
+        futures_util::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_put_stream() {
+        let store = test_store("store", "put");
+        let cid = Cid::of(b"hello world");
+
+        let size = store
+            .put_stream(&cid, chunks(&["hello ", "world"]))
+            .await
+            .unwrap();
+
+        assert_eq!(size, 11);
+        assert!(store.contains(&cid));
+        assert_eq!(std::fs::read(store.path(&cid)).unwrap(), b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_put_stream_removes_temp_file_when_dropped() {
+        let store = test_store("store", "dropped");
+        let cid = Cid::of("hello world");
+        let stream = futures_util::stream::iter([Ok::<_, String>(Bytes::from("hello"))])
+            .chain(futures_util::stream::pending());
+
+        let put = store.put_stream(&cid, stream);
+        let timed_out = tokio::time::timeout(std::time::Duration::from_millis(50), put).await;
+        assert!(timed_out.is_err());
+        assert_eq!(std::fs::read_dir(store.dir()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_put_stream_rejects_wrong_content() {
+        let store = test_store("store", "wrong");
+        let cid = Cid::of(b"hello world");
+
+        let result = store.put_stream(&cid, chunks(&["goodbye"])).await;
+
+        assert!(matches!(result, Err(StoreError::IntegrityError { .. })));
+        assert!(!store.contains(&cid));
+        // The temp file was cleaned up
+        assert_eq!(std::fs::read_dir(store.dir()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_put_reader() {
+        let store = test_store("store", "reader");
+        let cid = Cid::of(b"hello world");
+
+        assert_eq!(store.put_reader(&cid, &b"hello world"[..]).unwrap(), 11);
+        assert_eq!(std::fs::read(store.path(&cid)).unwrap(), b"hello world");
+
+        let other = Cid::of(b"goodbye");
+        let result = store.put_reader(&other, &b"hello"[..]);
+        assert!(matches!(result, Err(StoreError::IntegrityError { .. })));
+        assert!(!store.contains(&other));
+        assert_eq!(std::fs::read_dir(store.dir()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_add_reader() {
+        let store = test_store("store", "add");
+
+        let (cid, size) = store.add_reader(&b"hello world"[..], 11).unwrap();
+        assert_eq!((cid, size), (Cid::of(b"hello world"), 11));
+        assert_eq!(std::fs::read(store.path(&cid)).unwrap(), b"hello world");
+
+        let result = store.add_reader(&b"goodbye world"[..], 11);
+        assert!(matches!(result, Err(StoreError::TooLarge { max_size: 11 })));
+        assert_eq!(std::fs::read_dir(store.dir()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_put_stream_checked() {
+        let store = test_store("store", "checked");
+        let cid = Cid::of(b"hello world");
+
+        let result = store
+            .put_stream_checked(&cid, chunks(&["hello world"]), u64::MAX, |path| {
+                assert_eq!(std::fs::read(path).unwrap(), b"hello world");
+                Err(StoreError::Rejected("not today".to_string()))
+            })
+            .await;
+        assert!(matches!(result, Err(StoreError::Rejected(_))));
+        assert_eq!(std::fs::read_dir(store.dir()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_put_stream_limited() {
+        let store = test_store("store", "limited");
+        let cid = Cid::of(b"hello world");
+
+        let result = store
+            .put_stream_limited(&cid, chunks(&["hello ", "world"]), 10)
+            .await;
+
+        assert!(matches!(result, Err(StoreError::TooLarge { max_size: 10 })));
+        assert_eq!(std::fs::read_dir(store.dir()).unwrap().count(), 0);
+
+        let size = store
+            .put_stream_limited(&cid, chunks(&["hello ", "world"]), 11)
+            .await
+            .unwrap();
+        assert_eq!(size, 11);
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() {
+        let store = test_store("store", "list");
+        let mut cids = Vec::new();
+        for content in ["a", "bb", "ccc"] {
+            let cid = Cid::of(content);
+            store.put_stream(&cid, chunks(&[content])).await.unwrap();
+            cids.push(cid.to_string());
+        }
+        cids.sort();
+        std::fs::write(store.dir().join("stray.tmp"), "partial").unwrap();
+
+        let first = store.list(None, None, 2).unwrap();
+        assert_eq!(
+            first.iter().map(|b| b.cid.to_string()).collect::<Vec<_>>(),
+            cids[..2]
+        );
+        assert_eq!(
+            first[0].size,
+            std::fs::metadata(store.path(&first[0].cid)).unwrap().len()
+        );
+
+        let last = store.list(Some(&cids[1]), None, 2).unwrap();
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].cid.to_string(), cids[2]);
+
+        // Changes show up in the next listing
+        store.delete(&first[0].cid).unwrap();
+        let cid = Cid::of("dddd");
+        store.put_stream(&cid, chunks(&["dddd"])).await.unwrap();
+        let all = store.list(None, None, 10).unwrap();
+        assert_eq!(all.len(), 3);
+        assert!(all.iter().any(|blob| blob.cid == cid));
+        assert!(!all.iter().any(|blob| blob.cid == first[0].cid));
+    }
+
+    #[tokio::test]
+    async fn test_list_since() {
+        let store = test_store("store", "since");
+        let old = Cid::of("old");
+        let new = Cid::of("new");
+        store.put_stream(&old, chunks(&["old"])).await.unwrap();
+        store.put_stream(&new, chunks(&["new"])).await.unwrap();
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(store.path(&old))
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+
+        let all = store.list(None, None, 10).unwrap();
+        assert_eq!(all.len(), 2);
+
+        let since = all.iter().map(|blob| blob.added).max().unwrap();
+        let recent = store.list(None, Some(since), 10).unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].cid, new);
+    }
+
+    #[test]
+    fn test_probe_and_remove_stale_temp_files() {
+        let store = test_store("store", "temp");
+        store.probe().unwrap();
+
+        let cid = Cid::of("blob");
+        std::fs::write(store.path(&cid), "blob").unwrap();
+        let stale = store.temp_path(&cid);
+        std::fs::write(&stale, "partial").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&stale)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * STALE_TEMP_AGE)
+            .unwrap();
+        let fresh = store.temp_path(&cid);
+        std::fs::write(&fresh, "in progress").unwrap();
+
+        assert_eq!(
+            store.remove_stale_temp_files().unwrap(),
+            vec![stale.clone()]
+        );
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        assert!(store.contains(&cid));
+
+        std::fs::remove_dir_all(store.dir()).unwrap();
+        assert!(store.probe().is_err());
+    }
+}
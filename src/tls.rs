@@ -0,0 +1,130 @@
+use axum::{
+    Router,
+    http::{HeaderMap, StatusCode, Uri, header, uri::Authority},
+    response::{IntoResponse, Response},
+};
+use axum_server::tls_rustls::RustlsConfig;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// How often to check whether the certificate files have changed
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Certificate and key for serving HTTPS
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain
+    pub cert: PathBuf,
+    /// PEM file with the private key
+    pub key: PathBuf,
+    /// Also listen for plain HTTP on this address, and redirect every
+    /// request to HTTPS
+    #[serde(default)]
+    pub redirect_addr: Option<String>,
+}
+
+/// Load the certificate and key for a TLS listener
+pub async fn load(config: &TlsConfig) -> io::Result<RustlsConfig> {
+    // Only the ring provider is compiled in, but rustls still needs to be
+    // told to use it. Installing fails harmlessly if it already is.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(&config.cert, &config.key).await
+}
+
+/// Reload the certificate whenever its files change, e.g. when it is
+/// renewed. If the new files can't be loaded, the old certificate is kept,
+/// and loading is tried again on the next check.
+pub async fn watch(config: TlsConfig, rustls: RustlsConfig) {
+    let mut loaded = modified(&config);
+    loop {
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        let current = modified(&config);
+        if current == loaded {
+            continue;
+        }
+        match rustls.reload_from_pem_file(&config.cert, &config.key).await {
+            Ok(()) => {
+                tracing::info!(cert = %config.cert.display(), "reloaded TLS certificate");
+                loaded = current;
+            }
+            Err(err) => tracing::error!("unable to reload TLS certificate: {}", err),
+        }
+    }
+}
+
+/// When the certificate and key files were last modified
+fn modified(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&config.cert).ok()?.modified().ok()?;
+    let key = fs::metadata(&config.key).ok()?.modified().ok()?;
+    Some((cert, key))
+}
+
+/// Routes for the plain HTTP listener, which redirect every request to the
+/// same path on the HTTPS listener
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        redirect(&headers, &uri, https_port)
+    })
+}
+
+fn redirect(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    let location = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| https_location(host, uri, https_port));
+    match location {
+        Some(location) => (
+            StatusCode::PERMANENT_REDIRECT,
+            [(header::LOCATION, location)],
+        )
+            .into_response(),
+        None => (StatusCode::BAD_REQUEST, "Missing or invalid Host header").into_response(),
+    }
+}
+
+/// The HTTPS URL for a request made over plain HTTP
+fn https_location(host: &str, uri: &Uri, https_port: u16) -> Option<String> {
+    let authority: Authority = host.parse().ok()?;
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    Some(match https_port {
+        443 => format!("https://{}{}", authority.host(), path),
+        port => format!("https://{}:{}{}", authority.host(), port, path),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_https_location() {
+        let uri: Uri = "/bafkreia?dn=hello.txt".parse().unwrap();
+        assert_eq!(
+            https_location("example.com", &uri, 443).unwrap(),
+            "https://example.com/bafkreia?dn=hello.txt"
+        );
+        assert_eq!(
+            https_location("example.com:80", &uri, 8443).unwrap(),
+            "https://example.com:8443/bafkreia?dn=hello.txt"
+        );
+        assert_eq!(
+            https_location("[::1]:8080", &"/".parse().unwrap(), 443).unwrap(),
+            "https://[::1]/"
+        );
+        assert_eq!(https_location("exa mple.com", &uri, 443), None);
+    }
+
+    #[tokio::test]
+    async fn test_load_rejects_missing_files() {
+        let dir = std::env::temp_dir().join(format!("magnetize-tls-test-{}", std::process::id()));
+        let config = TlsConfig {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            redirect_addr: None,
+        };
+        assert!(load(&config).await.is_err());
+    }
+}
@@ -0,0 +1,638 @@
+use crate::url::{Url, parse_btih_urn_str, parse_btmh_urn_str};
+use crate::util::group;
+use data_encoding::{BASE32, HEXLOWER};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Multihash prefix for a sha256 digest (`0x12` sha2-256, `0x20` length 32).
+/// BitTorrent v2 infohashes are encoded as multihashes in `urn:btmh`.
+const MULTIHASH_SHA256_PREFIX: &str = "1220";
+
+/// Nesting allowed in bencoded values, so decoding can't overflow the stack
+const MAX_DEPTH: usize = 64;
+
+/// Length of a v1 piece hash (SHA-1)
+const PIECE_HASH_LEN: usize = 20;
+
+/// The parts of a torrent or BitTorrent magnet link that we need to
+/// construct a magnetize link.
+/// The CID is not part of a torrent, so it has to be computed by fetching
+/// the payload from one of the web seeds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentInfo {
+    /// Suggested file name
+    pub name: Option<String>,
+    /// BitTorrent v1 infohash (hex encoded SHA-1)
+    pub btih: Option<String>,
+    /// BitTorrent v2 infohash (hex encoded sha256 multihash)
+    pub btmh: Option<String>,
+    /// Web seeds (HTTP URLs for the payload)
+    pub ws: Vec<Url>,
+    /// Size of the payload in bytes, if known. Magnet links only have it if
+    /// they give an exact length (`xl`).
+    pub length: Option<u64>,
+    /// v1 piece hashes of the payload. Not part of v2 torrents or magnets.
+    pub pieces: Option<Pieces>,
+}
+
+/// The v1 pieces of a payload: SHA-1 hashes of consecutive runs of
+/// `piece_length` bytes, the last of which may be shorter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pieces {
+    pub piece_length: u64,
+    /// Concatenated 20 byte SHA-1 hashes, one per piece
+    pub hashes: Vec<u8>,
+}
+
+impl TorrentInfo {
+    /// Read a single-file torrent from bencoded metainfo (the contents of a
+    /// `.torrent` file). Supports v1, v2 and hybrid torrents.
+    /// See <https://www.bittorrent.org/beps/bep_0003.html>
+    /// and <https://www.bittorrent.org/beps/bep_0052.html>.
+    pub fn from_metainfo(bytes: &[u8]) -> Result<Self, Error> {
+        let metainfo = decode(bytes)?;
+        let info = metainfo.get(b"info").ok_or(Error::InvalidTorrent(
+            "info dictionary not found".to_string(),
+        ))?;
+        let Value::Dict(_, info_bytes) = info else {
+            return Err(Error::InvalidTorrent(
+                "info is not a dictionary".to_string(),
+            ));
+        };
+
+        let name = info
+            .get(b"name")
+            .and_then(Value::as_str)
+            .map(|name| name.to_string());
+
+        if info.get(b"files").is_some() || !is_single_file_tree(info.get(b"file tree")) {
+            return Err(Error::Unsupported(
+                "only single-file torrents are supported".to_string(),
+            ));
+        }
+
+        // v1 torrents have a `pieces` field, v2 torrents have `meta version`
+        // of 2. Hybrid torrents have both.
+        let btih = info
+            .get(b"pieces")
+            .map(|_| HEXLOWER.encode(&Sha1::digest(info_bytes)));
+
+        let btmh = match info.get(b"meta version").and_then(Value::as_int) {
+            Some(2) => Some(format!(
+                "{}{}",
+                MULTIHASH_SHA256_PREFIX,
+                HEXLOWER.encode(&Sha256::digest(info_bytes))
+            )),
+            _ => None,
+        };
+
+        if btih.is_none() && btmh.is_none() {
+            return Err(Error::InvalidTorrent(
+                "torrent is neither v1 nor v2".to_string(),
+            ));
+        }
+
+        // v1 gives the length in the info dictionary, v2 in the file tree
+        let length = info
+            .get(b"length")
+            .or_else(|| single_file(info.get(b"file tree"))?.get(b"length"))
+            .and_then(Value::as_int)
+            .map(|length| {
+                u64::try_from(length)
+                    .map_err(|_| Error::InvalidTorrent("negative length".to_string()))
+            })
+            .transpose()?;
+
+        let pieces = match (info.get(b"pieces"), info.get(b"piece length")) {
+            (Some(Value::Bytes(hashes)), Some(Value::Int(piece_length)))
+                if *piece_length > 0 && hashes.len() % PIECE_HASH_LEN == 0 =>
+            {
+                Some(Pieces {
+                    piece_length: *piece_length as u64,
+                    hashes: hashes.to_vec(),
+                })
+            }
+            (Some(_), _) => {
+                return Err(Error::InvalidTorrent(
+                    "invalid pieces or piece length".to_string(),
+                ));
+            }
+            (None, _) => None,
+        };
+
+        // `url-list` may be a single string or a list of strings.
+        // See <https://www.bittorrent.org/beps/bep_0019.html>
+        let url_list: Vec<&str> = match metainfo.get(b"url-list") {
+            Some(Value::List(urls)) => urls.iter().filter_map(Value::as_str).collect(),
+            Some(value) => value.as_str().into_iter().collect(),
+            None => Vec::new(),
+        };
+
+        let ws = url_list
+            .into_iter()
+            .filter_map(|url| Url::parse(url).ok())
+            .filter_map(|url| into_web_seed_url(url, name.as_deref()))
+            .collect();
+
+        Ok(TorrentInfo {
+            name,
+            btih,
+            btmh,
+            ws,
+            length,
+            pieces,
+        })
+    }
+
+    /// Parse a BitTorrent v1 or v2 magnet link.
+    /// At least one of `xt=urn:btih` or `xt=urn:btmh` must be present.
+    pub fn parse_magnet(url_str: &str) -> Result<Self, Error> {
+        let url = Url::parse(url_str)?;
+
+        let query = group(
+            url.query_pairs()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        );
+
+        let xts = query.get("xt").cloned().unwrap_or_default();
+
+        let btih = xts
+            .iter()
+            .filter_map(|xt| parse_btih_urn_str(xt).ok())
+            .find_map(|btih| normalize_btih(&btih));
+        let btmh = xts
+            .iter()
+            .find_map(|xt| parse_btmh_urn_str(xt).ok())
+            .map(|btmh| btmh.to_lowercase());
+
+        if btih.is_none() && btmh.is_none() {
+            return Err(Error::InvalidMagnetLink(
+                "btih or btmh xt parameter not found".to_string(),
+            ));
+        }
+
+        let name = query.get("dn").and_then(|dn| dn.first()).cloned();
+
+        let ws = query
+            .get("ws")
+            .map(|v| v.iter().filter_map(|s| Url::parse(s).ok()).collect())
+            .unwrap_or_default();
+
+        let length = query
+            .get("xl")
+            .and_then(|xl| xl.first())
+            .and_then(|xl| xl.parse().ok());
+
+        Ok(TorrentInfo {
+            name,
+            btih,
+            btmh,
+            ws,
+            length,
+            pieces: None,
+        })
+    }
+
+    /// Check that a payload fetched from a web seed is the torrent's, as far
+    /// as the torrent tells: that it has the torrent's length, and that its
+    /// v1 pieces have the torrent's hashes.
+    pub fn check_payload(&self, payload: &[u8]) -> Result<(), Error> {
+        if let Some(length) = self.length
+            && payload.len() as u64 != length
+        {
+            return Err(Error::PayloadMismatch(format!(
+                "expected {} bytes, got {}",
+                length,
+                payload.len()
+            )));
+        }
+        if let Some(pieces) = &self.pieces {
+            let piece_length = usize::try_from(pieces.piece_length).unwrap_or(usize::MAX);
+            let hashes = pieces.hashes.chunks(PIECE_HASH_LEN);
+            if hashes.len() != payload.len().div_ceil(piece_length) {
+                return Err(Error::PayloadMismatch("wrong number of pieces".to_string()));
+            }
+            for (i, (piece, hash)) in payload.chunks(piece_length).zip(hashes).enumerate() {
+                if Sha1::digest(piece).as_slice() != hash {
+                    return Err(Error::PayloadMismatch(format!("piece {} doesn't match", i)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Magnets give v1 infohashes as 40 hex digits or 32 base32 digits.
+/// Returns the infohash as lowercase hex, or None if it is neither.
+fn normalize_btih(btih: &str) -> Option<String> {
+    match btih.len() {
+        40 if btih.bytes().all(|b| b.is_ascii_hexdigit()) => Some(btih.to_lowercase()),
+        32 => BASE32
+            .decode(btih.to_uppercase().as_bytes())
+            .ok()
+            .map(|hash| HEXLOWER.encode(&hash)),
+        _ => None,
+    }
+}
+
+/// Check that a v2 `file tree` (if any) describes exactly one file.
+fn is_single_file_tree(file_tree: Option<&Value>) -> bool {
+    file_tree.is_none() || single_file(file_tree).is_some()
+}
+
+/// The file of a single-file v2 `file tree`, which looks like
+/// `{name: {"": {length, pieces root}}}`
+fn single_file<'v, 'a>(file_tree: Option<&'v Value<'a>>) -> Option<&'v Value<'a>> {
+    let Some(Value::Dict(entries, _)) = file_tree else {
+        return None;
+    };
+    if entries.len() != 1 {
+        return None;
+    }
+    entries
+        .values()
+        .next()?
+        .get(b"")
+        .filter(|file| file.get(b"length").is_some())
+}
+
+/// Web seed URLs ending in `/` point to a directory, and the file name must be
+/// appended as a path segment to get the URL of the payload. See BEP 19.
+/// The name is percent-encoded, so `#`, `?`, `/` and the like stay part of
+/// it, and names that would walk the path (`.` and `..`) are refused.
+fn into_web_seed_url(mut url: Url, name: Option<&str>) -> Option<Url> {
+    if !url.path().ends_with('/') {
+        return Some(url);
+    }
+    let name = name.filter(|name| !matches!(*name, "" | "." | ".."))?;
+    url.path_segments_mut().ok()?.pop_if_empty().push(name);
+    Some(url)
+}
+
+/// A decoded bencode value, borrowing from the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Value<'a>>),
+    /// Dictionary entries, plus the raw bencoded bytes of the whole dictionary.
+    /// We keep the raw bytes around because infohashes are computed over the
+    /// exact bytes of the info dictionary.
+    Dict(BTreeMap<&'a [u8], Value<'a>>, &'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    /// Look up a key in a dictionary value
+    pub fn get(&self, key: &[u8]) -> Option<&Value<'a>> {
+        match self {
+            Value::Dict(entries, _) => entries.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self {
+            Value::Bytes(bytes) => std::str::from_utf8(bytes).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Decode a complete bencoded value. Trailing bytes are an error.
+pub fn decode(bytes: &[u8]) -> Result<Value<'_>, Error> {
+    let (value, end) = decode_at(bytes, 0, 0)?;
+    if end != bytes.len() {
+        return Err(Error::Bencode("trailing bytes after value".to_string()));
+    }
+    Ok(value)
+}
+
+/// Decode the bencoded value starting at `pos`.
+/// Returns the value and the position just past its end.
+fn decode_at(bytes: &[u8], pos: usize, depth: usize) -> Result<(Value<'_>, usize), Error> {
+    if depth > MAX_DEPTH {
+        return Err(Error::Bencode("nested too deep".to_string()));
+    }
+    match bytes.get(pos) {
+        Some(b'i') => {
+            let end = find(bytes, pos + 1, b'e')?;
+            let int = std::str::from_utf8(&bytes[pos + 1..end])
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or(Error::Bencode("invalid integer".to_string()))?;
+            Ok((Value::Int(int), end + 1))
+        }
+        Some(b'l') => {
+            let mut items = Vec::new();
+            let mut pos = pos + 1;
+            while bytes.get(pos) != Some(&b'e') {
+                let (item, next) = decode_at(bytes, pos, depth + 1)?;
+                items.push(item);
+                pos = next;
+            }
+            Ok((Value::List(items), pos + 1))
+        }
+        Some(b'd') => {
+            let start = pos;
+            let mut entries = BTreeMap::new();
+            let mut pos = pos + 1;
+            while bytes.get(pos) != Some(&b'e') {
+                let (key, next) = decode_at(bytes, pos, depth + 1)?;
+                let Value::Bytes(key) = key else {
+                    return Err(Error::Bencode("dictionary key is not a string".to_string()));
+                };
+                let (value, next) = decode_at(bytes, next, depth + 1)?;
+                entries.insert(key, value);
+                pos = next;
+            }
+            Ok((Value::Dict(entries, &bytes[start..pos + 1]), pos + 1))
+        }
+        Some(b'0'..=b'9') => {
+            let colon = find(bytes, pos, b':')?;
+            let len = std::str::from_utf8(&bytes[pos..colon])
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .ok_or(Error::Bencode("invalid string length".to_string()))?;
+            let start = colon + 1;
+            let end = start
+                .checked_add(len)
+                .filter(|end| *end <= bytes.len())
+                .ok_or(Error::Bencode("string length out of bounds".to_string()))?;
+            Ok((Value::Bytes(&bytes[start..end]), end))
+        }
+        Some(_) => Err(Error::Bencode(format!("unexpected byte at {}", pos))),
+        None => Err(Error::Bencode("unexpected end of input".to_string())),
+    }
+}
+
+fn find(bytes: &[u8], from: usize, needle: u8) -> Result<usize, Error> {
+    bytes[from..]
+        .iter()
+        .position(|b| *b == needle)
+        .map(|i| from + i)
+        .ok_or(Error::Bencode("unexpected end of input".to_string()))
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Bencode error: {0}")]
+    Bencode(String),
+    #[error("Invalid torrent: {0}")]
+    InvalidTorrent(String),
+    #[error("Invalid magnet link: {0}")]
+    InvalidMagnetLink(String),
+    #[error("Unsupported torrent: {0}")]
+    Unsupported(String),
+    #[error("Payload doesn't match torrent: {0}")]
+    PayloadMismatch(String),
+    #[error("URL parse error: {0}")]
+    UrlParseError(#[from] url::ParseError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bstr(s: &str) -> String {
+        format!("{}:{}", s.len(), s)
+    }
+
+    fn v1_info() -> String {
+        format!(
+            "d{}i11e{}{}{}i16384e{}{}e",
+            bstr("length"),
+            bstr("name"),
+            bstr("hello.txt"),
+            bstr("piece length"),
+            bstr("pieces"),
+            bstr("aaaaaaaaaaaaaaaaaaaa"),
+        )
+    }
+
+    #[test]
+    fn test_decode_values() {
+        assert_eq!(decode(b"i42e").unwrap(), Value::Int(42));
+        assert_eq!(decode(b"i-7e").unwrap(), Value::Int(-7));
+        assert_eq!(decode(b"4:spam").unwrap(), Value::Bytes(b"spam"));
+        assert_eq!(
+            decode(b"l4:spami1ee").unwrap(),
+            Value::List(vec![Value::Bytes(b"spam"), Value::Int(1)])
+        );
+
+        let dict = decode(b"d3:cow3:moo4:spam4:eggse").unwrap();
+        assert_eq!(dict.get(b"cow"), Some(&Value::Bytes(b"moo")));
+        assert_eq!(dict.get(b"spam"), Some(&Value::Bytes(b"eggs")));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(decode(b"").is_err());
+        assert!(decode(b"i42").is_err());
+        assert!(decode(b"10:short").is_err());
+        assert!(decode(b"di1e3:fooe").is_err());
+        assert!(decode(b"i1ei2e").is_err());
+
+        let nested = format!("{}{}", "l".repeat(100_000), "e".repeat(100_000));
+        assert!(decode(nested.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_from_metainfo_v1() {
+        let info = v1_info();
+        let url_list = format!(
+            "l{}{}e",
+            bstr("https://example.com/files/"),
+            bstr("https://mirror.example.com/hello.txt")
+        );
+        let metainfo = format!("d{}{}{}{}e", bstr("info"), info, bstr("url-list"), url_list);
+
+        let torrent = TorrentInfo::from_metainfo(metainfo.as_bytes()).unwrap();
+
+        assert_eq!(torrent.name, Some("hello.txt".to_string()));
+        assert_eq!(
+            torrent.btih,
+            Some(HEXLOWER.encode(&Sha1::digest(info.as_bytes())))
+        );
+        assert_eq!(torrent.btmh, None);
+        assert_eq!(torrent.length, Some(11));
+        assert_eq!(
+            torrent.pieces,
+            Some(Pieces {
+                piece_length: 16384,
+                hashes: b"aaaaaaaaaaaaaaaaaaaa".to_vec(),
+            })
+        );
+        assert_eq!(
+            torrent.ws,
+            vec![
+                Url::parse("https://example.com/files/hello.txt").unwrap(),
+                Url::parse("https://mirror.example.com/hello.txt").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_web_seed_url_appends_name_as_a_segment() {
+        let seed = Url::parse("https://example.com/files/").unwrap();
+        let url = |name| into_web_seed_url(seed.clone(), Some(name)).map(String::from);
+        assert_eq!(
+            url("Release #1.iso").as_deref(),
+            Some("https://example.com/files/Release%20%231.iso")
+        );
+        assert_eq!(
+            url("what?.txt").as_deref(),
+            Some("https://example.com/files/what%3F.txt")
+        );
+        assert_eq!(
+            url("c:file").as_deref(),
+            Some("https://example.com/files/c:file")
+        );
+        assert_eq!(
+            url("../x").as_deref(),
+            Some("https://example.com/files/..%2Fx")
+        );
+        assert_eq!(url(".."), None);
+        assert_eq!(into_web_seed_url(seed, None), None);
+    }
+
+    #[test]
+    fn test_from_metainfo_v2() {
+        let info = format!(
+            "d{}d{}d{}d{}i11e{}{}eee{}i2e{}{}{}i16384ee",
+            bstr("file tree"),
+            bstr("hello.txt"),
+            bstr(""),
+            bstr("length"),
+            bstr("pieces root"),
+            bstr("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"),
+            bstr("meta version"),
+            bstr("name"),
+            bstr("hello.txt"),
+            bstr("piece length"),
+        );
+        let metainfo = format!(
+            "d{}{}{}{}e",
+            bstr("info"),
+            info,
+            bstr("url-list"),
+            bstr("https://example.com/hello.txt"),
+        );
+
+        let torrent = TorrentInfo::from_metainfo(metainfo.as_bytes()).unwrap();
+
+        assert_eq!(torrent.btih, None);
+        assert_eq!(
+            torrent.btmh,
+            Some(format!(
+                "1220{}",
+                HEXLOWER.encode(&Sha256::digest(info.as_bytes()))
+            ))
+        );
+        assert_eq!(torrent.length, Some(11));
+        assert_eq!(torrent.pieces, None);
+        assert_eq!(
+            torrent.ws,
+            vec![Url::parse("https://example.com/hello.txt").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_from_metainfo_multi_file_unsupported() {
+        let info = format!(
+            "d{}ld{}i1e{}l{}eee{}{}{}i16384e{}{}e",
+            bstr("files"),
+            bstr("length"),
+            bstr("path"),
+            bstr("a.txt"),
+            bstr("name"),
+            bstr("dir"),
+            bstr("piece length"),
+            bstr("pieces"),
+            bstr("aaaaaaaaaaaaaaaaaaaa"),
+        );
+        let metainfo = format!("d{}{}e", bstr("info"), info);
+
+        let result = TorrentInfo::from_metainfo(metainfo.as_bytes());
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_check_payload() {
+        let mut hashes = Sha1::digest(b"hello ").to_vec();
+        hashes.extend_from_slice(&Sha1::digest(b"world"));
+        let torrent = TorrentInfo {
+            name: None,
+            btih: Some("c12fe1c06bba254a9dc9f519b335aa7c1367a88a".to_string()),
+            btmh: None,
+            ws: Vec::new(),
+            length: Some(11),
+            pieces: Some(Pieces {
+                piece_length: 6,
+                hashes,
+            }),
+        };
+
+        assert!(torrent.check_payload(b"hello world").is_ok());
+        assert!(matches!(
+            torrent.check_payload(b"hello"),
+            Err(Error::PayloadMismatch(_))
+        ));
+        assert!(matches!(
+            torrent.check_payload(b"hello WORLD"),
+            Err(Error::PayloadMismatch(_))
+        ));
+
+        // Without pieces, only the length is checked
+        let torrent = TorrentInfo {
+            pieces: None,
+            ..torrent
+        };
+        assert!(torrent.check_payload(b"hello WORLD").is_ok());
+        assert!(torrent.check_payload(b"hello").is_err());
+    }
+
+    #[test]
+    fn test_parse_magnet() {
+        let magnet = "magnet:?xt=urn:btih:C12FE1C06BBA254A9DC9F519B335AA7C1367A88A&dn=hello.txt&xl=11&ws=https://example.com/hello.txt";
+        let torrent = TorrentInfo::parse_magnet(magnet).unwrap();
+
+        assert_eq!(
+            torrent.btih,
+            Some("c12fe1c06bba254a9dc9f519b335aa7c1367a88a".to_string())
+        );
+        assert_eq!(torrent.btmh, None);
+        assert_eq!(torrent.name, Some("hello.txt".to_string()));
+        assert_eq!(torrent.length, Some(11));
+        assert_eq!(
+            torrent.ws,
+            vec![Url::parse("https://example.com/hello.txt").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_parse_magnet_base32() {
+        let magnet = "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+        let torrent = TorrentInfo::parse_magnet(magnet).unwrap();
+
+        assert_eq!(
+            torrent.btih,
+            Some("c12fe1c06bba254a9dc9f519b335aa7c1367a88a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_magnet_missing_infohash() {
+        let result = TorrentInfo::parse_magnet("magnet:?dn=hello.txt");
+        assert!(matches!(result, Err(Error::InvalidMagnetLink(_))));
+    }
+}
@@ -24,7 +24,7 @@ impl TryFrom<&Cid> for Url {
 
     fn try_from(cid: &Cid) -> Result<Url, Self::Error> {
         let cid_str = cid.to_string();
-        Url::parse(&format!("urn:cid:{}", cid_str)).map_err(|e| Error::Url(e))
+        Url::parse(&format!("urn:cid:{}", cid_str)).map_err(Error::Url)
     }
 }
 
@@ -40,6 +40,28 @@ pub fn into_btmh_urn_str(btmh: &str) -> String {
     format!("urn:btmh:{}", btmh)
 }
 
+/// Parse `urn:btih` into btih string
+pub fn parse_btih_urn_str(urn_str: &str) -> Result<String, Error> {
+    let btih = urn_str
+        .strip_prefix("urn:btih:")
+        .ok_or(Error::Value("Not a urn:btih".to_string()))?;
+    Ok(btih.to_string())
+}
+
+pub fn into_btih_urn_str(btih: &str) -> String {
+    format!("urn:btih:{}", btih)
+}
+
+/// Make sure a server URL ends with a `/`, so that joining a CID to it
+/// appends to the path instead of replacing the last segment.
+pub fn into_base_url(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Invalid URL")]
@@ -76,4 +98,17 @@ mod tests {
         let result = parse_cid_urn_str(invalid_cid);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_into_base_url() {
+        let base = into_base_url(Url::parse("https://example.com/mag").unwrap());
+        assert_eq!(base.as_str(), "https://example.com/mag/");
+        assert_eq!(
+            base.join("bafkrei").unwrap().as_str(),
+            "https://example.com/mag/bafkrei"
+        );
+
+        let base = into_base_url(Url::parse("https://example.com").unwrap());
+        assert_eq!(base.as_str(), "https://example.com/");
+    }
 }
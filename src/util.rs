@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 /// Group a list of key-value pairs into a HashMap of key-to-vector.
 pub fn group<K, V>(pairs: Vec<(K, V)>) -> HashMap<K, Vec<V>>
@@ -8,12 +9,86 @@ where
     let mut groups: HashMap<K, Vec<V>> = HashMap::new();
 
     pairs.into_iter().for_each(|(key, value)| {
-        groups.entry(key).or_insert(Vec::new()).push(value);
+        groups.entry(key).or_default().push(value);
     });
 
     groups
 }
 
+/// Parse a human-readable size like `512`, `10K`, `10M` or `1G` into bytes.
+/// Suffixes are binary (1K = 1024 bytes), and case-insensitive.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier: u64 = match c.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(format!("Unknown size suffix: {}", c)),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("Invalid size: {}", s))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or(format!("Size too large: {}", s))
+}
+
+/// Parse a rate limit in bytes per second, a size like `10M` above zero
+pub fn parse_byte_rate(s: &str) -> Result<u64, String> {
+    match parse_size(s)? {
+        0 => Err(format!("Rate must be above zero: {}", s.trim())),
+        rate => Ok(rate),
+    }
+}
+
+/// Parse a rate limit in events per second, a number above zero
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let rate: f64 = s.parse().map_err(|_| format!("Invalid rate: {}", s))?;
+    if rate.is_finite() && rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err(format!("Rate must be above zero: {}", s))
+    }
+}
+
+/// Parse a human-readable duration like `90s`, `15m`, `12h`, `30d` or `2w`.
+/// A number without a suffix is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier: u64 = match c.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                'w' => 7 * 24 * 60 * 60,
+                _ => return Err(format!("Unknown duration suffix: {}", c)),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", s))?;
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or(format!("Duration too long: {}", s))
+}
+
+/// Compare secrets without leaking how much of them matched through timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -70,4 +145,44 @@ mod tests {
         assert_eq!(result.get(&2), Some(&vec!["b".to_string()]));
         assert_eq!(result.get(&3), Some(&vec!["c".to_string()]));
     }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("10m"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0.5"), Ok(0.5));
+        assert_eq!(parse_rate("10"), Ok(10.0));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("inf").is_err());
+        assert!(parse_rate("NaN").is_err());
+        assert_eq!(parse_byte_rate("1M"), Ok(1024 * 1024));
+        assert!(parse_byte_rate("0").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(
+            parse_duration("30d"),
+            Ok(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("2W"),
+            Ok(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("1y").is_err());
+        assert!(parse_duration("d").is_err());
+    }
 }